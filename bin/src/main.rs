@@ -1,4 +1,4 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use colored::*;
 
 use tracing::{event, Level};
@@ -10,7 +10,14 @@ use tnc_analysis_lib::prelude::*;
 
 const FILENAME: &str = "/Users/edmund/Downloads/matrix.csv";
 const OUT_FILE_CSV: &str = "/Users/edmund/Downloads/matrix.logit.csv";
+const LEDGER_JSON: &str = "/Users/edmund/Downloads/matrix.attrition.json";
 const FIELD_NAMES_CFG: &str = "./res/app-cfg.json";
+/// eligibility rules; the include tag (a non-null `subject_idx`) when absent
+const ELIGIBILITY_CFG: &str = "./res/eligibility.json";
+/// treatment, outcome and eligibility roles; the binary target doubles as the treatment when absent
+const STUDY_DESIGN_CFG: &str = "./res/study-design.json";
+/// max propensity distance of a matched pair (matching runs with a study design)
+const CALIPER: f64 = 0.05;
 // const PROPENSITY_CFG: &str = "./res/propensity-cfg.json";
 
 fn main() -> Result<()> {
@@ -23,6 +30,25 @@ fn main() -> Result<()> {
 
     let matrix = Matrix::from_file(FILENAME, None)?;
 
+    // subcommand: power <outcome> [--share 0.5] [--alpha 0.05] [--power 0.8] [--mde <effect>]
+    //   [--cuped] [--pre-window <window>] [--sets <field>] [--clusters <field>]
+    //   [--weight <field>] [--design-effect <d>]
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("power") {
+        let mut matrix = matrix;
+        match eligibility_cfg()? {
+            Some(cfg) => {
+                matrix.with_eligibility(&cfg)?;
+            }
+            None => {
+                matrix.with_include_tag();
+            }
+        }
+        let cfg = power_cfg(&args[2..])?;
+        matrix.power(&cfg)?;
+        return Ok(());
+    }
+
     // print the first few lines
     let summary = matrix.describe(None)?;
     event!(Level::DEBUG, "{}", &summary);
@@ -30,28 +56,97 @@ fn main() -> Result<()> {
 
     // initialize
     let mut matrix = matrix;
-    let (included, excluded) = matrix.with_include_tag();
-
-    event!(
-        Level::INFO,
-        "Included: {} Excluded: {}",
-        included.to_string().green(),
-        excluded.to_string().red()
-    );
+    let design_cfg: Option<Config<StudyDesignCfg>> =
+        match std::path::Path::new(STUDY_DESIGN_CFG).exists() {
+            true => Some(read_config(STUDY_DESIGN_CFG)?),
+            false => None,
+        };
+    // optional formula, e.g. "reach ~ . - q_state + C(q_innetwork) + q_specialty * q_innetwork"
+    let formula = std::env::args().nth(1);
+    let treatment = match (&design_cfg, &formula) {
+        (Some(cfg), _) => cfg.treatment.clone(),
+        (None, Some(formula)) => matrix
+            .model_def(formula, &field_names_cfg)?
+            .target()
+            .to_string(),
+        (None, None) => matrix.binary_target(field_names_cfg.clone()).to_string(),
+    };
+    let mut ledger = AttritionLedger::load(&matrix, &treatment)?;
+
+    match eligibility_cfg()? {
+        Some(cfg) => {
+            matrix.with_eligibility(&cfg)?;
+            ledger.flag("eligibility", &matrix, INCLUDE)?;
+        }
+        None => {
+            let (included, excluded) = matrix.with_include_tag();
+            ledger.flag("include tag", &matrix, INCLUDE)?;
+            event!(
+                Level::INFO,
+                "Included: {} Excluded: {}",
+                included.to_string().green(),
+                excluded.to_string().red()
+            );
+        }
+    }
 
     event!(Level::DEBUG, "{}", matrix.show_fields()?);
 
+    let design = match &design_cfg {
+        Some(cfg) => Some(matrix.study_design(cfg)?),
+        None => None,
+    };
+    if let Some(eligibility) = design.as_ref().and_then(|d| d.eligibility.as_deref()) {
+        ledger.flag("study design", &matrix, eligibility)?;
+    }
+    let matrix = matrix.cohort(&ledger)?;
+
     // Configure the propensity score computation
     // 🔑 this will be how we interact with the module from the external application.
-    let cfg = PropensityCfg::builder(
-        matrix.binary_target(field_names_cfg.clone()),
-        matrix.predictors(field_names_cfg.clone()),
-    )
-    .with_name("prop_score")
-    .bin_count(5)
-    .build();
+    let builder = match &formula {
+        Some(formula) => {
+            let model = matrix.model_def(formula, &field_names_cfg)?;
+            event!(Level::INFO, "📋 model: {}", &model);
+            if let Some(design) = design.as_ref().filter(|d| d.treatment != model.target()) {
+                return Err(eyre!(
+                    "The formula target {} is not the treatment {} of the study design",
+                    model.target(),
+                    design.treatment
+                ));
+            }
+            PropensityCfg::from_model(&model)
+        }
+        None => match &design {
+            Some(design) => design.propensity(matrix.predictors(field_names_cfg.clone())),
+            None => PropensityCfg::builder(
+                matrix.binary_target(field_names_cfg.clone()),
+                matrix.predictors(field_names_cfg.clone()),
+            ),
+        },
+    };
+    let cfg = builder.with_name("prop_score").bin_count(5).build();
     event!(Level::DEBUG, "{:#?}", &cfg);
-    let mut matrix = matrix.with_propensity(cfg.clone())?;
+
+    let mut fields: Vec<&str> = (&cfg.predictors).into();
+    fields.push(cfg.target.as_str());
+    ledger.complete_cases(&matrix, &fields)?;
+    let matrix = matrix.cohort(&ledger)?;
+
+    let matrix = matrix.with_propensity(cfg.clone())?;
+    ledger.common_support(&matrix, &cfg.name)?;
+    let mut matrix = matrix.cohort(&ledger)?;
+
+    if let Some(design) = &design {
+        ledger.caliper(&matrix, &cfg.name, CALIPER)?;
+        let match_cfg = design.matching(&cfg.name).caliper(&cfg.name, CALIPER);
+        matrix = matrix.cohort(&ledger)?.with_matching(&match_cfg)?;
+        ledger.matched(&matrix, &match_cfg.weight_name())?;
+        matrix = matrix.cohort(&ledger)?;
+    }
+
+    event!(Level::INFO, "\n📋 {}", ledger);
+    std::fs::write(LEDGER_JSON, ledger.to_json()?)?;
+    event!(Level::INFO, "✅ Wrote to file: {}", LEDGER_JSON);
 
     let view = matrix.select(["subject_idx", &cfg.name, &cfg.bin_name()])?;
     event!(Level::INFO, "{}", &view.head(Some(5)));
@@ -67,3 +162,41 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn eligibility_cfg() -> Result<Option<EligibilityCfg>> {
+    match std::path::Path::new(ELIGIBILITY_CFG).exists() {
+        true => Ok(Some(
+            read_config::<EligibilityCfg>(ELIGIBILITY_CFG)?.clone(),
+        )),
+        false => Ok(None),
+    }
+}
+
+fn power_cfg(args: &[String]) -> Result<PowerCfg> {
+    let outcome = args
+        .first()
+        .ok_or_else(|| eyre!("usage: power <outcome> [--share 0.5] [--mde <effect>] ..."))?;
+    let mut cfg = PowerCfg::new(outcome, 0.5);
+    let mut args = args[1..].iter();
+    while let Some(flag) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| eyre!("{} needs a value", flag))
+                .cloned()
+        };
+        cfg = match flag.as_str() {
+            "--share" => cfg.treatment_share(value()?.parse()?),
+            "--alpha" => cfg.alpha(value()?.parse()?),
+            "--power" => cfg.power(value()?.parse()?),
+            "--mde" => cfg.mde(value()?.parse()?),
+            "--cuped" => cfg.cuped(),
+            "--pre-window" => cfg.pre_window(&value()?),
+            "--sets" => cfg.sets(&value()?),
+            "--clusters" => cfg.clusters(&value()?),
+            "--weight" => cfg.weight(&value()?),
+            "--design-effect" => cfg.design_effect(value()?.parse()?),
+            other => return Err(eyre!("Unknown power option: {}", other)),
+        };
+    }
+    Ok(cfg)
+}
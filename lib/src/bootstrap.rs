@@ -0,0 +1,340 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::matrix::Matrix;
+use crate::stats::{mean, normal_cdf, normal_quantile, quantile, variance};
+
+///
+/// The resampling unit.
+///
+/// * `Subjects`          rows, with replacement
+/// * `Clusters(field)`   rows sharing a value of the field are drawn together
+///
+/// Clusters are the matched sets (`match_id`) or strata (`cem_id`, `prop_score_bin`) of the
+/// design; rows with a null value are their own cluster.
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Resample {
+    #[default]
+    Subjects,
+    Clusters(String),
+}
+
+///
+/// Configuration for the bootstrap.  Replicate `b` draws from a generator seeded with
+/// `seed + b`, so the intervals do not depend on how rayon schedules the replicates.
+///
+#[derive(Debug, Clone)]
+pub struct BootstrapCfg {
+    pub replicates: usize,
+    pub seed: u64,
+    pub resample: Resample,
+    /// coverage of the intervals, e.g. 0.95
+    pub level: f64,
+    /// groups of units deleted in turn for the BCa acceleration (delete-a-group jackknife)
+    pub jackknife_groups: usize,
+}
+
+impl BootstrapCfg {
+    pub fn new(replicates: usize, seed: u64) -> Self {
+        BootstrapCfg {
+            replicates,
+            seed,
+            resample: Resample::default(),
+            level: 0.95,
+            jackknife_groups: 20,
+        }
+    }
+    pub fn resample(mut self, resample: Resample) -> Self {
+        self.resample = resample;
+        self
+    }
+    /// Resample the matched sets or strata found in the field
+    pub fn clusters(self, field: &str) -> Self {
+        self.resample(Resample::Clusters(field.to_string()))
+    }
+    pub fn level(mut self, level: f64) -> Self {
+        self.level = level;
+        self
+    }
+    pub fn jackknife_groups(mut self, groups: usize) -> Self {
+        self.jackknife_groups = groups.max(2);
+        self
+    }
+}
+
+///
+/// Point estimate, bootstrap standard error and the percentile and BCa intervals of one effect.
+///
+#[derive(Debug, Clone)]
+pub struct Interval {
+    pub name: String,
+    pub estimate: f64,
+    pub se: f64,
+    pub percentile: (f64, f64),
+    pub bca: (f64, f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct BootstrapFindings {
+    pub intervals: Vec<Interval>,
+    pub replicates: usize,
+    /// replicates where the pipeline failed (e.g. a resample without treated subjects)
+    pub failed: usize,
+    pub level: f64,
+}
+
+impl BootstrapFindings {
+    pub fn get(&self, name: &str) -> Option<&Interval> {
+        self.intervals.iter().find(|i| i.name == name)
+    }
+    ///
+    /// `effect`, `estimate`, `se`, `pct_lower`, `pct_upper`, `bca_lower`, `bca_upper`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let column =
+            |f: fn(&Interval) -> f64| -> Vec<f64> { self.intervals.iter().map(f).collect() };
+        let names: Vec<&str> = self.intervals.iter().map(|i| i.name.as_str()).collect();
+        Ok(DataFrame::new(vec![
+            Series::new("effect", names),
+            Series::new("estimate", column(|i| i.estimate)),
+            Series::new("se", column(|i| i.se)),
+            Series::new("pct_lower", column(|i| i.percentile.0)),
+            Series::new("pct_upper", column(|i| i.percentile.1)),
+            Series::new("bca_lower", column(|i| i.bca.0)),
+            Series::new("bca_upper", column(|i| i.bca.1)),
+        ])?)
+    }
+}
+impl fmt::Display for BootstrapFindings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Bootstrap ({} replicates, {} failed), {:.0}% intervals",
+            self.replicates,
+            self.failed,
+            self.level * 100.0
+        )?;
+        for i in &self.intervals {
+            writeln!(
+                f,
+                "  {:<24} {:>10.4}  se {:>8.4}  percentile [{:.4}, {:.4}]  BCa [{:.4}, {:.4}]",
+                i.name, i.estimate, i.se, i.percentile.0, i.percentile.1, i.bca.0, i.bca.1
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Engine
+///
+/// Rows grouped by resampling unit.
+///
+fn units(df: &DataFrame, resample: &Resample) -> Result<Vec<Vec<usize>>> {
+    match resample {
+        Resample::Subjects => Ok((0..df.height()).map(|r| vec![r]).collect()),
+        Resample::Clusters(field) => {
+            let values = df.column(field)?.cast(&DataType::Utf8)?;
+            let mut clusters: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+            let mut singletons = Vec::new();
+            for (row, value) in values.utf8()?.into_iter().enumerate() {
+                match value {
+                    Some(value) => clusters.entry(value).or_default().push(row),
+                    None => singletons.push(vec![row]),
+                }
+            }
+            Ok(clusters.into_values().chain(singletons).collect())
+        }
+    }
+}
+
+fn take(df: &DataFrame, rows: &[usize]) -> Result<DataFrame> {
+    let idx = IdxCa::from_vec("", rows.iter().map(|r| *r as IdxSize).collect());
+    Ok(df.take(&idx)?)
+}
+
+///
+/// Rerun `pipeline` (propensity fit, matching, effects, ...) on `replicates` resamples of the
+/// matrix.  The pipeline returns one value per name.
+///
+pub fn bootstrap<F>(
+    df: &DataFrame,
+    cfg: &BootstrapCfg,
+    names: &[&str],
+    pipeline: F,
+) -> Result<BootstrapFindings>
+where
+    F: Fn(Matrix<DataFrame>) -> Result<Vec<f64>> + Sync,
+{
+    let estimate = pipeline(df.clone().into())?;
+    if estimate.len() != names.len() {
+        return Err(eyre!(
+            "The pipeline returned {} values for {} names",
+            estimate.len(),
+            names.len()
+        ));
+    }
+    let units = units(df, &cfg.resample)?;
+    let n = units.len();
+    event!(
+        Level::INFO,
+        "🔁 bootstrap: {} replicates over {} units",
+        cfg.replicates,
+        n
+    );
+
+    let replicates: Vec<Option<Vec<f64>>> = (0..cfg.replicates)
+        .into_par_iter()
+        .map(|b| {
+            let mut rng = StdRng::seed_from_u64(cfg.seed.wrapping_add(b as u64));
+            let rows: Vec<usize> = (0..n)
+                .flat_map(|_| units[rng.gen_range(0..n)].iter().copied())
+                .collect();
+            match take(df, &rows).and_then(|sample| pipeline(sample.into())) {
+                Ok(values) if values.iter().all(|v| v.is_finite()) => Some(values),
+                Ok(_) => None,
+                Err(e) => {
+                    event!(Level::DEBUG, "replicate {} failed: {}", b, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    let failed = replicates.iter().filter(|r| r.is_none()).count();
+    let replicates: Vec<Vec<f64>> = replicates.into_iter().flatten().collect();
+    if replicates.len() < 2 {
+        return Err(eyre!("Fewer than two bootstrap replicates succeeded"));
+    }
+
+    // delete-a-group jackknife for the acceleration
+    let groups = cfg.jackknife_groups.min(n);
+    let jackknife: Vec<Vec<f64>> = (0..groups)
+        .into_par_iter()
+        .filter_map(|g| {
+            let rows: Vec<usize> = (0..n)
+                .filter(|u| u % groups != g)
+                .flat_map(|u| units[u].iter().copied())
+                .collect();
+            take(df, &rows)
+                .and_then(|sample| pipeline(sample.into()))
+                .ok()
+        })
+        .collect();
+
+    let intervals = names
+        .iter()
+        .enumerate()
+        .map(|(k, name)| {
+            let draws: Vec<f64> = replicates.iter().map(|r| r[k]).collect();
+            let leave_out: Vec<f64> = jackknife.iter().map(|r| r[k]).collect();
+            Interval {
+                name: name.to_string(),
+                estimate: estimate[k],
+                se: variance(&draws).sqrt(),
+                percentile: percentile(&draws, cfg.level),
+                bca: bca(estimate[k], &draws, &leave_out, cfg.level),
+            }
+        })
+        .collect();
+
+    Ok(BootstrapFindings {
+        intervals,
+        replicates: cfg.replicates,
+        failed,
+        level: cfg.level,
+    })
+}
+
+fn percentile(draws: &[f64], level: f64) -> (f64, f64) {
+    let alpha = (1.0 - level) / 2.0;
+    (quantile(draws, alpha), quantile(draws, 1.0 - alpha))
+}
+
+///
+/// Bias-corrected and accelerated interval (Efron 1987).  Falls back to the percentile
+/// interval when the jackknife is degenerate.
+///
+fn bca(estimate: f64, draws: &[f64], leave_out: &[f64], level: f64) -> (f64, f64) {
+    let below = draws.iter().filter(|d| **d < estimate).count() as f64;
+    let ties = draws.iter().filter(|d| **d == estimate).count() as f64;
+    let z0 = normal_quantile((below + 0.5 * ties) / draws.len() as f64);
+
+    let centre = mean(leave_out);
+    let (num, den) = leave_out.iter().fold((0.0, 0.0), |(num, den), v| {
+        let d = centre - v;
+        (num + d.powi(3), den + d.powi(2))
+    });
+    let a = match den > 0.0 {
+        true => num / (6.0 * den.powf(1.5)),
+        false => 0.0,
+    };
+    if !z0.is_finite() || !a.is_finite() {
+        return percentile(draws, level);
+    }
+    let adjusted = |alpha: f64| {
+        let z = normal_quantile(alpha);
+        normal_cdf(z0 + (z0 + z) / (1.0 - a * (z0 + z)))
+    };
+    let alpha = (1.0 - level) / 2.0;
+    (
+        quantile(draws, adjusted(alpha)),
+        quantile(draws, adjusted(1.0 - alpha)),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::columns::series_to_f64;
+
+    fn difference_in_means(matrix: Matrix<DataFrame>) -> Result<Vec<f64>> {
+        let t = series_to_f64(matrix.column("treated")?)?;
+        let y = series_to_f64(matrix.column("y")?)?;
+        let arm = |k: f64| -> Vec<f64> {
+            y.iter()
+                .zip(&t)
+                .filter(|(_, t)| **t == k)
+                .map(|(y, _)| *y)
+                .collect()
+        };
+        Ok(vec![mean(&arm(1.0)) - mean(&arm(0.0))])
+    }
+    fn sample() -> DataFrame {
+        let rows = 200;
+        df!(
+            "treated" => (0..rows).map(|i| (i % 2) as f64).collect::<Vec<_>>(),
+            "y" => (0..rows).map(|i| (i % 2) as f64 + ((i * 37) % 11) as f64 / 5.0).collect::<Vec<_>>(),
+            "pair" => (0..rows).map(|i| (i / 2) as u32).collect::<Vec<_>>()
+        )
+        .unwrap()
+    }
+    #[test]
+    fn test_intervals_cover_and_reproduce() {
+        let df = sample();
+        let cfg = BootstrapCfg::new(200, 11);
+        let first = bootstrap(&df, &cfg, &["ate"], difference_in_means).unwrap();
+        let again = bootstrap(&df, &cfg, &["ate"], difference_in_means).unwrap();
+        let (a, b) = (first.get("ate").unwrap(), again.get("ate").unwrap());
+        assert_eq!(a.percentile, b.percentile);
+        assert_eq!(a.bca, b.bca);
+        assert!(a.percentile.0 < a.estimate && a.estimate < a.percentile.1);
+        assert!(a.bca.0 < a.estimate && a.estimate < a.bca.1);
+    }
+    #[test]
+    fn test_cluster_resampling_keeps_pairs() {
+        let df = sample();
+        let units = units(&df, &Resample::Clusters("pair".into())).unwrap();
+        assert_eq!(100, units.len());
+        assert!(units.iter().all(|u| u.len() == 2));
+        let cfg = BootstrapCfg::new(50, 3).clusters("pair");
+        let found = bootstrap(&df, &cfg, &["ate"], difference_in_means).unwrap();
+        assert_eq!(0, found.failed);
+    }
+}
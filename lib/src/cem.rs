@@ -0,0 +1,312 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::columns::series_to_f64;
+use crate::config::FieldNamesCfg;
+use crate::matching::strata_keys;
+use crate::stats::quantile;
+
+///
+/// How a continuous measure is coarsened before forming the strata.
+///
+/// * `Sturges`       ⌈log2(n) + 1⌉ equal-width bins over the observed range (the CEM default)
+/// * `EqualWidth(k)` k equal-width bins over the observed range
+/// * `Quantiles(k)`  k bins with (about) the same number of subjects
+/// * `Cutpoints(..)` user-defined interior cutpoints; bins are `(.., c1], (c1, c2], .., (ck, ..)`
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Coarsening {
+    #[default]
+    Sturges,
+    EqualWidth(usize),
+    Quantiles(usize),
+    Cutpoints(Vec<f64>),
+}
+
+impl Coarsening {
+    /// Interior cutpoints for the (non-null) values
+    pub fn cutpoints(&self, values: &[f64]) -> Vec<f64> {
+        let (min, max) = values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(*v), hi.max(*v))
+            });
+        let equal_width = |k: usize| -> Vec<f64> {
+            let width = (max - min) / k as f64;
+            (1..k).map(|i| min + width * i as f64).collect()
+        };
+        let mut cuts = match self {
+            _ if values.is_empty() => Vec::new(),
+            Coarsening::Sturges => {
+                equal_width(((values.len() as f64).log2() + 1.0).ceil().max(1.0) as usize)
+            }
+            Coarsening::EqualWidth(k) => equal_width((*k).max(1)),
+            Coarsening::Quantiles(k) => (1..*k)
+                .map(|i| quantile(values, i as f64 / *k as f64))
+                .collect(),
+            Coarsening::Cutpoints(cuts) => cuts.clone(),
+        };
+        cuts.sort_by(|a, b| a.total_cmp(b));
+        cuts.dedup();
+        cuts
+    }
+}
+
+/// Index of the bin that holds the value
+fn bin(cuts: &[f64], value: f64) -> usize {
+    cuts.partition_point(|c| *c < value)
+}
+
+///
+/// Configuration for coarsened exact matching (CEM).  Strata are the cross-product of the
+/// categorical fields (e.g. `q_state`, `q_specialty`, `q_innetwork`) and the bins of the
+/// coarsened measures; only the strata with both treated and control subjects are kept.
+///
+/// The result is appended to the matrix with the same layout as [`crate::matching::MatchCfg`]:
+///
+/// * `{name}_id`      stratum id (null when the subject is pruned)
+/// * `{name}_weight`  CEM weight: 1 for treated, (m_C / m_T)·(m_T^s / m_C^s) for controls in stratum s, 0 when pruned
+///
+#[derive(Debug, Clone)]
+pub struct CemCfg {
+    pub treatment: String,
+    /// matched exactly on their values (nulls are a value)
+    pub categorical: Vec<String>,
+    /// continuous measures and how to coarsen each
+    pub coarsened: Vec<(String, Coarsening)>,
+    pub name: String,
+}
+
+impl CemCfg {
+    pub fn new(treatment: &str) -> Self {
+        CemCfg {
+            treatment: treatment.to_string(),
+            categorical: Vec::new(),
+            coarsened: Vec::new(),
+            name: "cem".to_string(),
+        }
+    }
+    pub fn categorical(mut self, fields: Vec<&str>) -> Self {
+        self.categorical = fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+    /// Every field tagged as a quality field, e.g. `q_`
+    pub fn quality_fields(mut self, fields: &[&str], cfg: &FieldNamesCfg) -> Self {
+        self.categorical = fields
+            .iter()
+            .filter(|f| f.starts_with(cfg.quality_field_tag.as_str()))
+            .map(|f| f.to_string())
+            .collect();
+        self
+    }
+    pub fn coarsen(mut self, field: &str, coarsening: Coarsening) -> Self {
+        self.coarsened.push((field.to_string(), coarsening));
+        self
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+    pub fn id_name(&self) -> String {
+        self.name.to_owned() + "_id"
+    }
+    pub fn weight_name(&self) -> String {
+        self.name.to_owned() + "_weight"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stratum {
+    pub key: String,
+    pub treated: Vec<usize>,
+    pub controls: Vec<usize>,
+}
+impl Stratum {
+    pub fn is_matched(&self) -> bool {
+        !self.treated.is_empty() && !self.controls.is_empty()
+    }
+}
+
+///
+/// Strata found by CEM along with the cutpoints used for each coarsened measure.
+///
+#[derive(Debug, Clone)]
+pub struct Cem {
+    pub strata: Vec<Stratum>,
+    pub cutpoints: Vec<(String, Vec<f64>)>,
+    pub rows: usize,
+    pub treated: usize,
+    pub controls: usize,
+}
+
+impl Cem {
+    pub fn matched_strata(&self) -> impl Iterator<Item = &Stratum> {
+        self.strata.iter().filter(|s| s.is_matched())
+    }
+    pub fn matched_treated(&self) -> usize {
+        self.matched_strata().map(|s| s.treated.len()).sum()
+    }
+    pub fn matched_controls(&self) -> usize {
+        self.matched_strata().map(|s| s.controls.len()).sum()
+    }
+    /// CEM weights in row order (ATT)
+    pub fn weights(&self) -> Vec<f64> {
+        let mut weights = vec![0.0; self.rows];
+        let ratio = self.matched_controls() as f64 / self.matched_treated() as f64;
+        for stratum in self.matched_strata() {
+            let w = ratio * stratum.treated.len() as f64 / stratum.controls.len() as f64;
+            stratum.treated.iter().for_each(|t| weights[*t] = 1.0);
+            stratum.controls.iter().for_each(|c| weights[*c] = w);
+        }
+        weights
+    }
+    ///
+    /// The `{name}_id` and `{name}_weight` columns in row order.
+    ///
+    pub fn to_columns(&self, cfg: &CemCfg) -> Vec<Series> {
+        let mut ids: Vec<Option<u32>> = vec![None; self.rows];
+        for (id, stratum) in self.matched_strata().enumerate() {
+            for row in stratum.treated.iter().chain(&stratum.controls) {
+                ids[*row] = Some(id as u32);
+            }
+        }
+        vec![
+            Series::new(&cfg.id_name(), ids),
+            Series::new(&cfg.weight_name(), self.weights()),
+        ]
+    }
+}
+impl fmt::Display for Cem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Coarsened exact matching")?;
+        for (field, cuts) in &self.cutpoints {
+            writeln!(f, "  {}: {} bins", field, cuts.len() + 1)?;
+        }
+        writeln!(
+            f,
+            "  strata kept:     {} of {}",
+            self.matched_strata().count(),
+            self.strata.len()
+        )?;
+        writeln!(
+            f,
+            "  treated kept:    {} of {}",
+            self.matched_treated(),
+            self.treated
+        )?;
+        write!(
+            f,
+            "  controls kept:   {} of {}",
+            self.matched_controls(),
+            self.controls
+        )
+    }
+}
+
+///
+/// Coarsen, stratify and prune.
+///
+pub fn cem(df: &DataFrame, cfg: &CemCfg) -> Result<Cem> {
+    if cfg.categorical.is_empty() && cfg.coarsened.is_empty() {
+        return Err(eyre!(
+            "CEM requires at least one categorical or coarsened field"
+        ));
+    }
+    let treatment = series_to_f64(df.column(&cfg.treatment)?)?;
+    let mut keys = strata_keys(df, &cfg.categorical)?;
+
+    let mut cutpoints = Vec::new();
+    for (field, coarsening) in &cfg.coarsened {
+        let values: Vec<Option<f64>> = df
+            .column(field)?
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .collect();
+        let observed: Vec<f64> = values.iter().flatten().copied().collect();
+        let cuts = coarsening.cutpoints(&observed);
+        for (key, value) in keys.iter_mut().zip(&values) {
+            match value {
+                Some(value) => key.push_str(&bin(&cuts, *value).to_string()),
+                None => key.push('∅'),
+            }
+            key.push('|');
+        }
+        event!(Level::DEBUG, "{}: cutpoints {:?}", field, cuts);
+        cutpoints.push((field.clone(), cuts));
+    }
+
+    let mut strata: BTreeMap<String, Stratum> = BTreeMap::new();
+    for (row, key) in keys.into_iter().enumerate() {
+        let stratum = strata.entry(key.clone()).or_insert(Stratum {
+            key,
+            treated: Vec::new(),
+            controls: Vec::new(),
+        });
+        match treatment[row] > 0.5 {
+            true => stratum.treated.push(row),
+            false => stratum.controls.push(row),
+        }
+    }
+    let treated = treatment.iter().filter(|t| **t > 0.5).count();
+
+    Ok(Cem {
+        strata: strata.into_values().collect(),
+        cutpoints,
+        rows: df.height(),
+        treated,
+        controls: df.height() - treated,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coarsening_cutpoints() {
+        let values = vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0];
+        assert_eq!(
+            vec![2.0, 4.0, 6.0],
+            Coarsening::EqualWidth(4).cutpoints(&values)
+        );
+        assert_eq!(4, Coarsening::Sturges.cutpoints(&values).len() + 1);
+        assert_eq!(
+            vec![1.0, 5.0],
+            Coarsening::Cutpoints(vec![5.0, 1.0]).cutpoints(&values)
+        );
+        assert_eq!(0, bin(&[1.0, 5.0], 1.0));
+        assert_eq!(2, bin(&[1.0, 5.0], 5.5));
+    }
+    #[test]
+    fn test_cem_prunes_and_weights() {
+        let df = df!(
+            "treated" => [1, 1, 0, 0, 0, 1, 0],
+            "q_state" => ["CA", "CA", "CA", "CA", "CA", "NY", "TX"],
+            "m_unitcount" => [1.0, 9.0, 2.0, 1.5, 8.0, 1.0, 1.0]
+        )
+        .unwrap();
+        let cfg = CemCfg::new("treated")
+            .categorical(vec!["q_state"])
+            .coarsen("m_unitcount", Coarsening::Cutpoints(vec![5.0]));
+        let found = cem(&df, &cfg).unwrap();
+        // CA low: 1 treated, 2 controls; CA high: 1 and 1; NY and TX are pruned
+        assert_eq!(2, found.matched_strata().count());
+        assert_eq!(4, found.strata.len());
+        assert_eq!(2, found.matched_treated());
+        assert_eq!(3, found.matched_controls());
+
+        let weights = found.weights();
+        let ratio = 3.0 / 2.0;
+        assert_eq!(vec![1.0, 1.0], vec![weights[0], weights[1]]);
+        assert!((weights[2] - ratio * 0.5).abs() < 1e-12);
+        assert!((weights[4] - ratio).abs() < 1e-12);
+        assert_eq!(0.0, weights[5] + weights[6]);
+        // control weights sum to the number of matched controls
+        let controls: f64 = [2, 3, 4].iter().map(|i| weights[*i]).sum();
+        assert!((controls - 3.0).abs() < 1e-12);
+    }
+}
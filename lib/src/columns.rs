@@ -0,0 +1,30 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+
+///
+/// Cast to f64; nulls are an error (the design matrix cannot hold them).
+///
+pub(crate) fn series_to_f64(s: &Series) -> Result<Vec<f64>> {
+    s.cast(&DataType::Float64)?
+        .f64()?
+        .into_iter()
+        .map(|v| v.ok_or_else(|| eyre!("Null value in {}", s.name())))
+        .collect()
+}
+/// Cast to f64, keeping the nulls
+pub(crate) fn series_to_options(s: &Series) -> Result<Vec<Option<f64>>> {
+    Ok(s.cast(&DataType::Float64)?.f64()?.into_iter().collect())
+}
+/// The field as set (cluster, segment, unit) labels; nulls are in no set
+pub(crate) fn set_ids(df: &DataFrame, field: &str) -> Result<Vec<Option<String>>> {
+    Ok(df
+        .column(field)?
+        .cast(&DataType::Utf8)?
+        .utf8()?
+        .into_iter()
+        .map(|v| v.map(|v| v.to_string()))
+        .collect())
+}
+pub(crate) fn optional_weights(df: &DataFrame, weight: Option<&str>) -> Result<Option<Vec<f64>>> {
+    weight.map(|w| series_to_f64(df.column(w)?)).transpose()
+}
@@ -0,0 +1,154 @@
+use color_eyre::eyre::{eyre, Result};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::propensity_model::{Metrics, PropensityModel};
+use crate::stats::take_rows;
+
+///
+/// K-fold configuration for out-of-fold propensity scores.  Each subject is scored by a model
+/// fitted on the other K-1 folds.  Folds are stratified on the treatment and reproducible from
+/// the seed.
+///
+/// # Example
+///
+/// ```
+/// use tnc_analysis_lib::prelude::CrossFitCfg;
+///
+/// let json = r#"{ "folds": 5, "seed": 42 }"#;
+/// let cfg: CrossFitCfg = serde_json::from_str(&json).unwrap();
+/// assert!(cfg.folds == 5);
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrossFitCfg {
+    pub folds: usize,
+    pub seed: u64,
+}
+
+///
+/// Out-of-fold scores (in row order) with the per-fold and pooled metrics.
+///
+#[derive(Debug, Clone)]
+pub struct CrossFitFindings {
+    pub model: &'static str,
+    pub scores: Vec<f64>,
+    pub fold_ids: Vec<u32>,
+    pub fold_metrics: Vec<Metrics>,
+    pub pooled: Metrics,
+}
+impl CrossFitFindings {
+    pub fn report(&self) -> String {
+        let mut report = format!("Cross-fitted {} propensity (out-of-fold)\n", self.model);
+        for (k, m) in self.fold_metrics.iter().enumerate() {
+            report.push_str(&format!("  fold {:>2}  {}\n", k, m));
+        }
+        report.push_str(&format!("  pooled   {}\n", self.pooled));
+        report
+    }
+}
+
+///
+/// Assign a fold to each row.  Treated and control rows are shuffled separately and dealt
+/// round-robin so that each fold has the same treatment share.
+///
+pub fn stratified_folds(y: &[f64], folds: usize, seed: u64) -> Vec<u32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut treated: Vec<usize> = (0..y.len()).filter(|i| y[*i] > 0.5).collect();
+    let mut control: Vec<usize> = (0..y.len()).filter(|i| y[*i] <= 0.5).collect();
+    treated.shuffle(&mut rng);
+    control.shuffle(&mut rng);
+
+    let mut fold_ids = vec![0; y.len()];
+    // continue the deal where the treated left off to balance the fold sizes
+    for (k, row) in treated.iter().chain(control.iter()).enumerate() {
+        fold_ids[*row] = (k % folds) as u32;
+    }
+    fold_ids
+}
+
+///
+/// Out-of-fold scores for the row-dominant X (with bias slot) and binary y.
+///
+pub fn cross_fit(
+    model: &dyn PropensityModel,
+    x: &[f64],
+    y: &[f64],
+    rows: usize,
+    cfg: &CrossFitCfg,
+) -> Result<CrossFitFindings> {
+    if cfg.folds < 2 {
+        return Err(eyre!(
+            "Cross-fitting requires at least 2 folds: {}",
+            cfg.folds
+        ));
+    }
+    if rows < cfg.folds || x.is_empty() {
+        return Err(eyre!(
+            "Cross-fitting {} folds requires at least as many subjects, found {}",
+            cfg.folds,
+            rows
+        ));
+    }
+    let cols = x.len() / rows;
+    let fold_ids = stratified_folds(y, cfg.folds, cfg.seed);
+    let mut scores = vec![f64::NAN; rows];
+    let mut fold_metrics = Vec::with_capacity(cfg.folds);
+
+    for k in 0..cfg.folds as u32 {
+        let (held_out, train): (Vec<usize>, Vec<usize>) =
+            (0..rows).partition(|i| fold_ids[*i] == k);
+        let train_y: Vec<f64> = train.iter().map(|i| y[*i]).collect();
+        let fit = model.fit(&take_rows(x, cols, &train), &train_y, train.len())?;
+        let predicted = fit.predict(&take_rows(x, cols, &held_out));
+        for (row, p) in held_out.iter().zip(&predicted) {
+            scores[*row] = *p;
+        }
+        let held_out_y: Vec<f64> = held_out.iter().map(|i| y[*i]).collect();
+        let metrics = Metrics::new(&held_out_y, &predicted);
+        event!(Level::DEBUG, "✅ {} fold {k}: {metrics}", model.name());
+        fold_metrics.push(metrics);
+    }
+
+    Ok(CrossFitFindings {
+        model: model.name(),
+        pooled: Metrics::new(y, &scores),
+        scores,
+        fold_ids,
+        fold_metrics,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stratified_folds_balance_treatment() {
+        let y: Vec<f64> = (0..100).map(|i| if i < 20 { 1.0 } else { 0.0 }).collect();
+        let fold_ids = stratified_folds(&y, 5, 7);
+        for k in 0..5 {
+            let treated = (0..100)
+                .filter(|i| fold_ids[*i] == k && y[*i] > 0.5)
+                .count();
+            let total = fold_ids.iter().filter(|f| **f == k).count();
+            assert_eq!(4, treated);
+            assert_eq!(20, total);
+        }
+    }
+    #[test]
+    fn test_stratified_folds_reproducible() {
+        let y: Vec<f64> = (0..50).map(|i| (i % 3 == 0) as u8 as f64).collect();
+        assert_eq!(stratified_folds(&y, 4, 11), stratified_folds(&y, 4, 11));
+    }
+    #[test]
+    fn test_cross_fit_rejects_too_few_subjects() {
+        let model = crate::propensity_model::Glm(crate::glm::Link::Logit);
+        let cfg = CrossFitCfg { folds: 5, seed: 1 };
+        assert!(cross_fit(&model, &[], &[], 0, &cfg).is_err());
+        let (x, y) = (vec![1.0; 3], vec![0.0, 1.0, 0.0]);
+        assert!(cross_fit(&model, &x, &y, 3, &cfg).is_err());
+    }
+}
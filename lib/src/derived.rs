@@ -0,0 +1,216 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::fmt;
+
+use crate::columns::series_to_options;
+use crate::field_name::{pre_period, ParsedField, DERIVED_KEY};
+use crate::stats::{mean, quantile_bins, ranks, variance};
+
+///
+/// The standard derived fields of a measure, appended as `<base>.derivedField::<name>` so that
+/// the fuzzy predictor search treats them like the ones computed upstream.
+///
+/// * `Decile`          1..=10, equal-count bins
+/// * `QuantileRank`    (rank - 1) / (n - 1) in [0, 1], ties share the average rank
+/// * `Log`             ln(1 + x), safe for zero counts
+/// * `ZScore`          (x - mean) / sd
+/// * `Delta`           x minus its closest pre-period window (see [`crate::field_name::pre_periods`])
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Derivation {
+    Decile,
+    QuantileRank,
+    Log,
+    ZScore,
+    Delta,
+}
+
+impl Derivation {
+    pub fn all() -> [Derivation; 5] {
+        [
+            Derivation::Decile,
+            Derivation::QuantileRank,
+            Derivation::Log,
+            Derivation::ZScore,
+            Derivation::Delta,
+        ]
+    }
+    /// The derived field name of `base`: the name as given with `.derivedField::<name>` appended
+    pub fn field(&self, base: &str) -> String {
+        format!("{}.{}::{}", base, DERIVED_KEY, self)
+    }
+    ///
+    /// The derived series of `base`.  Nulls stay null; deciles, ranks and z-scores are of the
+    /// present values only.
+    ///
+    pub fn derive(&self, df: &DataFrame, base: &str) -> Result<Series> {
+        let x = series_to_options(df.column(base)?)?;
+        let present: Vec<f64> = x.iter().flatten().copied().collect();
+        let values: Vec<Option<f64>> = match self {
+            Derivation::Decile => fill(
+                &x,
+                quantile_bins(&present, 10)
+                    .iter()
+                    .map(|b| *b as f64)
+                    .collect(),
+            ),
+            Derivation::QuantileRank => {
+                let n = present.len() as f64;
+                fill(
+                    &x,
+                    ranks(&present)
+                        .iter()
+                        .map(|r| match n > 1.0 {
+                            true => (r - 1.0) / (n - 1.0),
+                            false => 0.5,
+                        })
+                        .collect(),
+                )
+            }
+            Derivation::Log => x
+                .iter()
+                .map(|x| {
+                    x.map(|x| match x > -1.0 {
+                        true => x.ln_1p(),
+                        false => f64::NAN,
+                    })
+                })
+                .collect(),
+            Derivation::ZScore => {
+                let (m, sd) = (mean(&present), variance(&present).sqrt());
+                fill(
+                    &x,
+                    present
+                        .iter()
+                        .map(|x| match sd > 0.0 {
+                            true => (x - m) / sd,
+                            false => 0.0,
+                        })
+                        .collect(),
+                )
+            }
+            Derivation::Delta => {
+                let pre = pre_period(base, None, &df.get_column_names())?;
+                let pre = series_to_options(df.column(&pre)?)?;
+                x.iter()
+                    .zip(&pre)
+                    .map(|(x, p)| match (x, p) {
+                        (Some(x), Some(p)) => Some(x - p),
+                        _ => None,
+                    })
+                    .collect()
+            }
+        };
+        Ok(Series::new(&self.field(base), values))
+    }
+}
+/// Spread the values derived from the present values back over the rows
+fn fill(x: &[Option<f64>], derived: Vec<f64>) -> Vec<Option<f64>> {
+    let mut derived = derived.into_iter();
+    x.iter().map(|v| v.and_then(|_| derived.next())).collect()
+}
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Derivation::Decile => write!(f, "decile"),
+            Derivation::QuantileRank => write!(f, "quantileRank"),
+            Derivation::Log => write!(f, "log"),
+            Derivation::ZScore => write!(f, "zScore"),
+            Derivation::Delta => write!(f, "delta"),
+        }
+    }
+}
+
+///
+/// The fields derived from `base`: the column itself, or else every field of the measure
+/// (`m_unitcount` covers each product and time window).  Derived fields are never a base.
+///
+pub fn base_fields<'a>(base: &str, names: &[&'a str]) -> Result<Vec<&'a str>> {
+    if let Some(name) = names.iter().find(|n| **n == base) {
+        return Ok(vec![*name]);
+    }
+    let fields: Vec<&str> = names
+        .iter()
+        .filter(|name| {
+            let field = ParsedField::parse(name);
+            field.is_structured() && field.measure == base && field.derived().is_none()
+        })
+        .copied()
+        .collect();
+    match fields.is_empty() {
+        true => Err(eyre!("No field or measure named {} in the matrix", base)),
+        false => Ok(fields),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FieldNamesCfg;
+    use crate::get_fuzzy_predictors;
+
+    fn sample() -> DataFrame {
+        df!(
+            "subject_idx" => (0..20u32).collect::<Vec<_>>(),
+            "MeaType::m_unitcount.product::A.time::0_13" => (0..20).map(|i| i as f64).collect::<Vec<_>>(),
+            "MeaType::m_unitcount.product::A.time::14_27" => (0..20).map(|i| (2 * i) as f64).collect::<Vec<_>>()
+        )
+        .unwrap()
+    }
+    #[test]
+    fn test_derived_fields() {
+        let df = sample();
+        let base = "MeaType::m_unitcount.product::A.time::14_27";
+        assert_eq!(
+            "MeaType::m_unitcount.product::A.time::14_27.derivedField::decile",
+            Derivation::Decile.field(base)
+        );
+        let decile = Derivation::Decile.derive(&df, base).unwrap();
+        assert_eq!(Some(1.0), decile.f64().unwrap().get(0));
+        assert_eq!(Some(10.0), decile.f64().unwrap().get(19));
+        let delta = Derivation::Delta.derive(&df, base).unwrap();
+        assert_eq!(Some(5.0), delta.f64().unwrap().get(5));
+        let rank = Derivation::QuantileRank.derive(&df, base).unwrap();
+        assert_eq!(Some(1.0), rank.f64().unwrap().get(19));
+
+        let names = df.get_column_names();
+        assert_eq!(2, base_fields("m_unitcount", &names).unwrap().len());
+        assert!(base_fields("m_missing", &names).is_err());
+    }
+    #[test]
+    fn test_derived_fields_are_predictors() {
+        let field = Derivation::ZScore.field("MeaType::m_unitcount.product::A.time::0_13");
+        let cfg = FieldNamesCfg {
+            quality_field_tag: "q_".to_string(),
+            derived_field_tag: "derived".to_string(),
+            binary_target_field_tag: "reach".to_string(),
+        };
+        let found = get_fuzzy_predictors(vec![field.as_str(), "subject_idx"], cfg);
+        assert_eq!(vec![field.as_str()], found);
+    }
+    #[test]
+    fn test_derived_plain_field() {
+        assert_eq!(
+            "q_age.derivedField::zScore",
+            Derivation::ZScore.field("q_age")
+        );
+        let df = df!("q_age" => [30.0, 40.0, 50.0]).unwrap();
+        let log = Derivation::Log.derive(&df, "q_age").unwrap();
+        assert_eq!("q_age.derivedField::log", log.name());
+    }
+    #[test]
+    fn test_derived_fields_keep_nulls() {
+        let df = df!("q_age" => [Some(30.0), None, Some(50.0), Some(40.0)]).unwrap();
+        let rank = Derivation::QuantileRank.derive(&df, "q_age").unwrap();
+        assert_eq!(
+            vec![Some(0.0), None, Some(1.0), Some(0.5)],
+            rank.f64().unwrap().into_iter().collect::<Vec<_>>()
+        );
+        let z = Derivation::ZScore.derive(&df, "q_age").unwrap();
+        assert_eq!(Some(0.0), z.f64().unwrap().get(3));
+        assert_eq!(1, z.null_count());
+        let decile = Derivation::Decile.derive(&df, "q_age").unwrap();
+        assert_eq!(None, decile.f64().unwrap().get(1));
+        assert_eq!(Some(10.0), decile.f64().unwrap().get(2));
+    }
+}
@@ -0,0 +1,335 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::effect::EffectCfg;
+use crate::header::Header;
+use crate::matching::MatchCfg;
+use crate::propensity::{Predictors, PropensityCfg, PropensityCfgBuilder};
+
+///
+/// The roles of the study, declared separately instead of reusing the binary target (found by
+/// the `reach` tag) as both the treatment and the outcome.  Each role is a column name or a
+/// fuzzy search term that must resolve to exactly one column.
+///
+/// # Example
+///
+/// ```
+/// use tnc_analysis_lib::prelude::StudyDesignCfg;
+///
+/// let json = r#"{
+///      "treatment": "q_treated",
+///      "outcomes": ["m_unitcount.product::A.time::28_35", "m_reach.time::28_35"],
+///      "eligibility": "include"
+///   }"#;
+/// let cfg: StudyDesignCfg = serde_json::from_str(&json).unwrap();
+/// assert!(cfg.treatment == "q_treated");
+/// assert!(cfg.outcomes.len() == 2);
+///
+/// let json = r#"{ "treatment": "q_treated", "outcomes": ["m_reach"] }"#;
+/// let cfg: StudyDesignCfg = serde_json::from_str(&json).unwrap();
+/// assert!(cfg.eligibility.is_none());
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StudyDesignCfg {
+    /// binary assignment: 0/1 or boolean
+    pub treatment: SearchTerm,
+    /// numeric or boolean outcomes, one effect each
+    pub outcomes: Vec<SearchTerm>,
+    /// boolean column; subjects are in the study when true (all subjects when absent)
+    #[serde(default)]
+    pub eligibility: Option<SearchTerm>,
+}
+type SearchTerm = String;
+
+impl StudyDesignCfg {
+    pub fn new(treatment: &str, outcomes: Vec<&str>) -> Self {
+        StudyDesignCfg {
+            treatment: treatment.to_string(),
+            outcomes: outcomes.iter().map(|o| o.to_string()).collect(),
+            eligibility: None,
+        }
+    }
+    pub fn eligibility(mut self, eligibility: &str) -> Self {
+        self.eligibility = Some(eligibility.to_string());
+        self
+    }
+}
+
+///
+/// A [`StudyDesignCfg`] resolved against the header of the matrix, with the dtypes and values
+/// of each role validated.  Pass it to scoring ([`StudyDesign::propensity`]), matching
+/// ([`StudyDesign::matching`]) and effect estimation ([`StudyDesign::effects`]).
+///
+#[derive(Debug, Clone)]
+pub struct StudyDesign {
+    pub treatment: String,
+    pub outcomes: Vec<String>,
+    pub eligibility: Option<String>,
+    /// eligible subjects in each arm
+    pub treated: usize,
+    pub controls: usize,
+    /// subjects left out by the eligibility column
+    pub ineligible: usize,
+}
+
+impl StudyDesign {
+    pub fn resolve(df: &DataFrame, cfg: &StudyDesignCfg) -> Result<Self> {
+        let header = Header::from(df);
+        let treatment = resolve_role(&header, "treatment", &cfg.treatment)?;
+        let outcomes = cfg
+            .outcomes
+            .iter()
+            .map(|o| resolve_role(&header, "outcome", o))
+            .collect::<Result<Vec<_>>>()?;
+        let eligibility = match &cfg.eligibility {
+            Some(e) => Some(resolve_role(&header, "eligibility", e)?),
+            None => None,
+        };
+        if outcomes.is_empty() {
+            return Err(eyre!("The study design needs at least one outcome"));
+        }
+        let mut roles: Vec<&str> = vec![treatment.as_str()];
+        roles.extend(outcomes.iter().map(|o| o.as_str()));
+        roles.extend(eligibility.as_deref());
+        for (i, role) in roles.iter().enumerate() {
+            if roles[..i].contains(role) {
+                return Err(eyre!(
+                    "{} is given more than one role in the study design",
+                    role
+                ));
+            }
+        }
+
+        let eligible = eligible_mask(df, eligibility.as_deref())?;
+        let ineligible = eligible.iter().filter(|e| !**e).count();
+
+        // treatment: 0/1 without nulls among the eligible subjects
+        let t = df.column(&treatment)?;
+        if !(t.dtype().is_numeric() || t.dtype() == &DataType::Boolean) {
+            return Err(eyre!(
+                "The treatment {} must be 0/1 or boolean, found {}",
+                treatment,
+                t.dtype()
+            ));
+        }
+        let (mut treated, mut controls) = (0, 0);
+        for (value, eligible) in t
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .zip(&eligible)
+        {
+            match (eligible, value) {
+                (false, _) => {}
+                (true, Some(v)) if v == 0.0 || v == 1.0 => match v == 1.0 {
+                    true => treated += 1,
+                    false => controls += 1,
+                },
+                (true, Some(v)) => {
+                    return Err(eyre!(
+                        "The treatment {} has the value {}, not 0/1",
+                        treatment,
+                        v
+                    ))
+                }
+                (true, None) => return Err(eyre!("Null treatment value in {}", treatment)),
+            }
+        }
+        if treated == 0 || controls == 0 {
+            return Err(eyre!(
+                "The treatment {} needs treated and control subjects, found {} and {}",
+                treatment,
+                treated,
+                controls
+            ));
+        }
+
+        // outcomes: numeric or boolean without nulls among the eligible subjects
+        for outcome in &outcomes {
+            let y = df.column(outcome)?;
+            if !(y.dtype().is_numeric() || y.dtype() == &DataType::Boolean) {
+                return Err(eyre!(
+                    "The outcome {} must be numeric or boolean, found {}",
+                    outcome,
+                    y.dtype()
+                ));
+            }
+            let nulls = y
+                .is_null()
+                .into_iter()
+                .zip(&eligible)
+                .filter(|(null, eligible)| null.unwrap_or(false) && **eligible)
+                .count();
+            if nulls > 0 {
+                return Err(eyre!("{} eligible subjects have a null {}", nulls, outcome));
+            }
+        }
+
+        Ok(StudyDesign {
+            treatment,
+            outcomes,
+            eligibility,
+            treated,
+            controls,
+            ineligible,
+        })
+    }
+    ///
+    /// The predictors without the columns that have a role in the design; the fuzzy search picks
+    /// up e.g. a derived outcome or a `q_` treatment.
+    ///
+    pub fn predictors<'a>(&self, predictors: Predictors<'a>) -> Predictors<'a> {
+        predictors
+            .iter()
+            .filter(|p| !self.has_role(p))
+            .copied()
+            .collect::<Vec<_>>()
+            .into()
+    }
+    fn has_role(&self, field: &str) -> bool {
+        self.treatment == field
+            || self.outcomes.iter().any(|o| o == field)
+            || self.eligibility.as_deref() == Some(field)
+    }
+    ///
+    /// Propensity of the treatment (not the binary target) on the predictors.
+    ///
+    pub fn propensity<'a>(&'a self, predictors: Predictors<'a>) -> PropensityCfgBuilder<'a> {
+        PropensityCfg::builder(self.treatment.as_str().into(), self.predictors(predictors))
+    }
+    pub fn matching(&self, score: &str) -> MatchCfg {
+        MatchCfg::propensity(&self.treatment, score)
+    }
+    /// One effect per outcome
+    pub fn effects(&self) -> Vec<EffectCfg> {
+        self.outcomes
+            .iter()
+            .map(|o| EffectCfg::new(&self.treatment, o))
+            .collect()
+    }
+    ///
+    /// The eligible subjects.
+    ///
+    pub fn eligible(&self, df: &DataFrame) -> Result<DataFrame> {
+        match &self.eligibility {
+            Some(_) => {
+                let mask = eligible_mask(df, self.eligibility.as_deref())?;
+                Ok(df.filter(&BooleanChunked::from_slice("eligible", &mask))?)
+            }
+            None => Ok(df.clone()),
+        }
+    }
+}
+impl fmt::Display for StudyDesign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Study design")?;
+        writeln!(
+            f,
+            "  treatment:   {} ({} treated, {} controls)",
+            self.treatment, self.treated, self.controls
+        )?;
+        for outcome in &self.outcomes {
+            writeln!(f, "  outcome:     {}", outcome)?;
+        }
+        match &self.eligibility {
+            Some(e) => write!(f, "  eligibility: {} ({} ineligible)", e, self.ineligible),
+            None => write!(f, "  eligibility: all subjects"),
+        }
+    }
+}
+
+///
+/// The column for a role: the exact name, else the one field that contains the search term.
+///
+pub(crate) fn resolve_role(header: &Header, role: &str, search: &str) -> Result<String> {
+    if header.contains(&search) {
+        return Ok(search.to_string());
+    }
+    let found = header.get_fuzzy_fields(search);
+    match found.as_slice() {
+        [(field, _)] => Ok(field.to_string()),
+        [] => Err(eyre!("No field for the {} {}", role, search)),
+        _ => Err(eyre!(
+            "The {} {} is ambiguous: {:?}",
+            role,
+            search,
+            found.iter().map(|(f, _)| *f).collect::<Vec<_>>()
+        )),
+    }
+}
+
+/// Nulls in the eligibility column are not eligible
+fn eligible_mask(df: &DataFrame, eligibility: Option<&str>) -> Result<Vec<bool>> {
+    match eligibility {
+        Some(e) => {
+            let column = df.column(e)?;
+            match column.dtype() {
+                DataType::Boolean => Ok(column
+                    .bool()?
+                    .into_iter()
+                    .map(|v| v == Some(true))
+                    .collect()),
+                other => Err(eyre!(
+                    "The eligibility {} must be boolean, found {}",
+                    e,
+                    other
+                )),
+            }
+        }
+        None => Ok(vec![true; df.height()]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> DataFrame {
+        df!(
+            "subject_idx" => [0u32, 1, 2, 3, 4, 5],
+            "q_treated" => [1i32, 0, 1, 0, 1, 7],
+            "q_state" => ["NY", "CA", "TX", "NY", "CA", "TX"],
+            "MeaType::m_reach.time::28_35" => [1.0, 0.0, 1.0, 1.0, 0.0, 0.0],
+            "MeaType::m_unitcount.product::A.time::28_35" => [3.0, 1.0, 4.0, 1.0, 5.0, 9.0],
+            "include" => [true, true, true, true, false, false]
+        )
+        .unwrap()
+    }
+    #[test]
+    fn test_study_design_resolves_roles() {
+        let df = sample();
+        let cfg =
+            StudyDesignCfg::new("q_treated", vec!["unitcount.product::A"]).eligibility("include");
+        let design = StudyDesign::resolve(&df, &cfg).unwrap();
+        assert_eq!(
+            "MeaType::m_unitcount.product::A.time::28_35",
+            design.outcomes[0]
+        );
+        assert_eq!(
+            (2, 2, 2),
+            (design.treated, design.controls, design.ineligible)
+        );
+        assert_eq!(4, design.eligible(&df).unwrap().height());
+
+        let predictors = design.predictors(vec!["q_treated", "q_state"].into());
+        assert_eq!(vec!["q_state"], *predictors);
+        let cfg = design.propensity(vec!["q_state"].into()).build();
+        assert_eq!("q_treated", cfg.target.as_str());
+        assert_eq!("q_treated", design.effects()[0].treatment);
+    }
+    #[test]
+    fn test_study_design_validates_roles() {
+        let df = sample();
+        // 7 is not a treatment value once the ineligible subjects are kept
+        let cfg = StudyDesignCfg::new("q_treated", vec!["m_reach"]);
+        assert!(StudyDesign::resolve(&df, &cfg).is_err());
+        let cfg = StudyDesignCfg::new("q_treated", vec!["q_state"]).eligibility("include");
+        assert!(StudyDesign::resolve(&df, &cfg).is_err());
+        let cfg = StudyDesignCfg::new("q_treated", vec!["time::28_35"]).eligibility("include");
+        assert!(StudyDesign::resolve(&df, &cfg).is_err());
+        let cfg = StudyDesignCfg::new("q_treated", vec!["q_treated"]).eligibility("include");
+        assert!(StudyDesign::resolve(&df, &cfg).is_err());
+    }
+}
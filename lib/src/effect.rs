@@ -0,0 +1,328 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::fmt;
+
+use crate::columns::series_to_f64;
+use crate::field_name::pre_period;
+use crate::glm::ols;
+use crate::stats::{normal_cdf, normal_quantile};
+
+///
+/// An estimated lift (treated minus control) with its standard error.  The estimators return
+/// this so that the reports, placebo tests and sensitivity analyses share one shape.
+///
+#[derive(Debug, Clone)]
+pub struct Effect {
+    pub outcome: String,
+    pub estimator: &'static str,
+    pub estimate: f64,
+    pub se: f64,
+    pub treated: usize,
+    pub controls: usize,
+}
+
+impl Effect {
+    pub fn z(&self) -> f64 {
+        self.estimate / self.se
+    }
+    /// Two-sided p-value of no effect (normal approximation)
+    pub fn p_value(&self) -> f64 {
+        2.0 * (1.0 - normal_cdf(self.z().abs()))
+    }
+    pub fn ci(&self, level: f64) -> (f64, f64) {
+        let z = normal_quantile(1.0 - (1.0 - level) / 2.0);
+        (self.estimate - z * self.se, self.estimate + z * self.se)
+    }
+}
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (lo, hi) = self.ci(0.95);
+        write!(
+            f,
+            "{} ({}): {:.4} (se {:.4}, 95% CI [{:.4}, {:.4}], p {:.4}, n {}/{})",
+            self.outcome,
+            self.estimator,
+            self.estimate,
+            self.se,
+            lo,
+            hi,
+            self.p_value(),
+            self.treated,
+            self.controls
+        )
+    }
+}
+
+///
+/// (Weighted) difference in means.  The standard error treats the weights as fixed and uses
+/// the Kish effective sample size of each arm; see [`crate::bootstrap`] for intervals that
+/// account for estimating the weights.
+///
+pub fn difference_in_means(
+    outcome: &str,
+    treatment: &[f64],
+    y: &[f64],
+    weights: Option<&[f64]>,
+) -> Result<Effect> {
+    let ones = vec![1.0; y.len()];
+    let weights = weights.unwrap_or(&ones);
+    let arm = |treated: bool| -> (f64, f64, usize) {
+        let (mut total, mut squares, mut sum, mut n) = (0.0, 0.0, 0.0, 0);
+        for ((t, y), w) in treatment.iter().zip(y).zip(weights) {
+            if (*t > 0.5) == treated && *w > 0.0 {
+                total += w;
+                squares += w * w;
+                sum += w * y;
+                n += 1;
+            }
+        }
+        let m = sum / total;
+        let var: f64 = treatment
+            .iter()
+            .zip(y)
+            .zip(weights)
+            .filter(|((t, _), w)| (**t > 0.5) == treated && **w > 0.0)
+            .map(|((_, y), w)| w * (y - m).powi(2))
+            .sum::<f64>()
+            / total;
+        // var / n_eff with n_eff = total² / squares
+        (m, var * squares / (total * total), n)
+    };
+    let (m1, v1, n1) = arm(true);
+    let (m0, v0, n0) = arm(false);
+    if n1 < 2 || n0 < 2 {
+        return Err(eyre!(
+            "{}: need two treated and two control subjects, found {} and {}",
+            outcome,
+            n1,
+            n0
+        ));
+    }
+    Ok(Effect {
+        outcome: outcome.to_string(),
+        estimator: "difference in means",
+        estimate: m1 - m0,
+        se: (v1 + v0).sqrt(),
+        treated: n1,
+        controls: n0,
+    })
+}
+
+///
+/// Configuration for the lift of `outcome`.  `cuped` adjusts for the pre-period version of the
+/// outcome (the closest `time::` window before the outcome window unless `pre_window` names
+/// one, e.g. `0_13`); `covariates` adds further adjusters.  Without either the adjusted and
+/// unadjusted estimates coincide.
+///
+#[derive(Debug, Clone)]
+pub struct EffectCfg {
+    pub treatment: String,
+    pub outcome: String,
+    /// weights of the design, e.g. `match_weight`, `cem_weight` or `gps_weight`
+    pub weight: Option<String>,
+    pub cuped: bool,
+    pub pre_window: Option<String>,
+    pub covariates: Vec<String>,
+}
+
+impl EffectCfg {
+    pub fn new(treatment: &str, outcome: &str) -> Self {
+        EffectCfg {
+            treatment: treatment.to_string(),
+            outcome: outcome.to_string(),
+            weight: None,
+            cuped: false,
+            pre_window: None,
+            covariates: Vec::new(),
+        }
+    }
+    pub fn weight(mut self, weight: &str) -> Self {
+        self.weight = Some(weight.to_string());
+        self
+    }
+    pub fn cuped(mut self) -> Self {
+        self.cuped = true;
+        self
+    }
+    /// CUPED on the named pre-period window
+    pub fn pre_window(mut self, window: &str) -> Self {
+        self.cuped = true;
+        self.pre_window = Some(window.to_string());
+        self
+    }
+    pub fn covariates(mut self, covariates: Vec<&str>) -> Self {
+        self.covariates = covariates.iter().map(|c| c.to_string()).collect();
+        self
+    }
+    ///
+    /// The pre-period field (when `cuped`) followed by the covariates.
+    ///
+    pub fn adjusters(&self, names: &[&str]) -> Result<Vec<String>> {
+        let mut adjusters = Vec::new();
+        if self.cuped {
+            adjusters.push(pre_period(
+                &self.outcome,
+                self.pre_window.as_deref(),
+                names,
+            )?);
+        }
+        adjusters.extend(self.covariates.iter().cloned());
+        Ok(adjusters)
+    }
+}
+
+///
+/// The regression-adjusted lift next to the plain difference in means.
+///
+#[derive(Debug, Clone)]
+pub struct AdjustedEffect {
+    pub adjusted: Effect,
+    pub unadjusted: Effect,
+    pub covariates: Vec<String>,
+    /// θ of each covariate
+    pub coefficients: Vec<f64>,
+}
+
+impl AdjustedEffect {
+    /// Percent reduction of the variance of the estimate, 1 - se²(adjusted) / se²(unadjusted)
+    pub fn variance_reduction(&self) -> f64 {
+        100.0 * (1.0 - (self.adjusted.se / self.unadjusted.se).powi(2))
+    }
+}
+impl fmt::Display for AdjustedEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Lift of {}", self.adjusted.outcome)?;
+        writeln!(f, "  unadjusted {}", self.unadjusted)?;
+        writeln!(f, "  adjusted   {}", self.adjusted)?;
+        for (c, theta) in self.covariates.iter().zip(&self.coefficients) {
+            writeln!(f, "    θ {:<48} {:.4}", c, theta)?;
+        }
+        write!(f, "  variance reduction {:.1}%", self.variance_reduction())
+    }
+}
+
+///
+/// CUPED / regression adjustment.  θ comes from the weighted least squares fit of the outcome on
+/// the treatment and the centred covariates; the adjusted outcome Y - (X - X̄)θ then goes
+/// through [`difference_in_means`], so the estimate equals the treatment coefficient and both
+/// standard errors are computed the same way.
+///
+pub fn regression_adjusted(
+    outcome: &str,
+    treatment: &[f64],
+    y: &[f64],
+    covariates: &[Vec<f64>],
+    weights: Option<&[f64]>,
+) -> Result<(Effect, Vec<f64>)> {
+    let rows = y.len();
+    let ones = vec![1.0; rows];
+    let w = weights.unwrap_or(&ones);
+    let total: f64 = w.iter().filter(|w| **w > 0.0).sum();
+    let centres: Vec<f64> = covariates
+        .iter()
+        .map(|c| {
+            c.iter()
+                .zip(w)
+                .filter(|(_, w)| **w > 0.0)
+                .map(|(c, w)| c * w)
+                .sum::<f64>()
+                / total
+        })
+        .collect();
+
+    let mut x = Vec::with_capacity(rows * (covariates.len() + 2));
+    for r in 0..rows {
+        x.push(treatment[r]);
+        x.extend(covariates.iter().zip(&centres).map(|(c, m)| c[r] - m));
+        x.push(1.0);
+    }
+    let w: Vec<f64> = w.iter().map(|w| w.max(0.0)).collect();
+    let beta = ols(&x, y, Some(&w), rows, 1e-9)?;
+    let theta = beta[1..=covariates.len()].to_vec();
+
+    let adjusted: Vec<f64> = (0..rows)
+        .map(|r| {
+            y[r] - covariates
+                .iter()
+                .zip(&centres)
+                .zip(&theta)
+                .map(|((c, m), t)| (c[r] - m) * t)
+                .sum::<f64>()
+        })
+        .collect();
+    let mut effect = difference_in_means(outcome, treatment, &adjusted, weights)?;
+    effect.estimator = "regression adjusted";
+    Ok((effect, theta))
+}
+
+///
+/// The adjusted and unadjusted lift of the configured outcome.
+///
+pub fn adjusted_effect(df: &DataFrame, cfg: &EffectCfg) -> Result<AdjustedEffect> {
+    let covariates = cfg.adjusters(&df.get_column_names())?;
+    let treatment = series_to_f64(df.column(&cfg.treatment)?)?;
+    let y = series_to_f64(df.column(&cfg.outcome)?)?;
+    let weights = match &cfg.weight {
+        Some(w) => Some(series_to_f64(df.column(w)?)?),
+        None => None,
+    };
+    let values = covariates
+        .iter()
+        .map(|c| series_to_f64(df.column(c)?))
+        .collect::<Result<Vec<_>>>()?;
+
+    let unadjusted = difference_in_means(&cfg.outcome, &treatment, &y, weights.as_deref())?;
+    let (adjusted, coefficients) =
+        regression_adjusted(&cfg.outcome, &treatment, &y, &values, weights.as_deref())?;
+    Ok(AdjustedEffect {
+        adjusted,
+        unadjusted,
+        covariates,
+        coefficients,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_difference_in_means() {
+        let t = [1.0, 1.0, 1.0, 0.0, 0.0, 0.0];
+        let y = [3.0, 4.0, 5.0, 1.0, 2.0, 3.0];
+        let effect = difference_in_means("y", &t, &y, None).unwrap();
+        assert!((effect.estimate - 2.0).abs() < 1e-12);
+        // population variance 2/3 per arm over 3 subjects
+        assert!((effect.se - (4.0f64 / 9.0).sqrt()).abs() < 1e-12);
+
+        let w = [1.0, 1.0, 0.0, 1.0, 1.0, 1.0];
+        let effect = difference_in_means("y", &t, &y, Some(&w)).unwrap();
+        assert!((effect.estimate - 1.5).abs() < 1e-12);
+        assert_eq!(2, effect.treated);
+    }
+    #[test]
+    fn test_cuped_reduces_variance() {
+        let rows = 400;
+        let treated: Vec<f64> = (0..rows).map(|i| (i % 2) as f64).collect();
+        let pre: Vec<f64> = (0..rows).map(|i| ((i * 37) % 50) as f64).collect();
+        let post: Vec<f64> = (0..rows)
+            .map(|i| pre[i] + treated[i] + ((i * 13) % 5) as f64 / 5.0)
+            .collect();
+        let df = df!(
+            "treated" => treated,
+            "MeaType::m_unitcount.product::A.time::0_13" => pre,
+            "MeaType::m_unitcount.product::A.time::14_27" => post
+        )
+        .unwrap();
+        let cfg = EffectCfg::new("treated", "MeaType::m_unitcount.product::A.time::14_27").cuped();
+        let found = adjusted_effect(&df, &cfg).unwrap();
+
+        assert_eq!(
+            vec!["MeaType::m_unitcount.product::A.time::0_13"],
+            found.covariates
+        );
+        assert!((found.coefficients[0] - 1.0).abs() < 0.05);
+        assert!((found.adjusted.estimate - 1.0).abs() < 0.1);
+        assert!(found.variance_reduction() > 90.0);
+    }
+}
@@ -0,0 +1,319 @@
+use color_eyre::eyre::Result;
+use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::design::resolve_role;
+use crate::header::Header;
+
+pub const INCLUDE: &str = "include";
+pub const EXCLUSION_REASON: &str = "exclusion_reason";
+
+///
+/// Eligibility rules applied in order, each to the subjects the previous rules kept.  A subject
+/// is excluded by the first rule it fails; the rule is recorded in `exclusion_reason` (null for
+/// the included subjects) and `include` is true when no rule failed.
+///
+/// Fields are a column name or a fuzzy search term that resolves to one column (see
+/// [`crate::design::StudyDesignCfg`]).  `name` overrides the reason recorded for the rule.
+///
+/// # Example
+///
+/// ```
+/// use tnc_analysis_lib::prelude::EligibilityCfg;
+///
+/// let json = r#"{
+///      "rules": [
+///        { "type": "NotNull", "field": "subject_idx" },
+///        { "type": "Min", "field": "m_unitcount.product::A.time::0_23", "value": 1.0,
+///          "name": "no pre-period activity" },
+///        { "type": "In", "field": "q_specialty", "values": ["ONC", "HEM"] },
+///        { "type": "Flag", "field": "q_other_campaign", "value": false }
+///      ]
+///   }"#;
+/// let cfg: EligibilityCfg = serde_json::from_str(&json).unwrap();
+/// assert!(cfg.rules.len() == 4);
+/// assert!(cfg.rules[1].reason() == "no pre-period activity");
+/// assert!(cfg.rules[2].reason() == "q_specialty not in [ONC, HEM]");
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EligibilityCfg {
+    pub rules: Vec<EligibilityRule>,
+}
+impl Default for EligibilityCfg {
+    /// The historical include tag: a non-null `subject_idx`
+    fn default() -> Self {
+        EligibilityCfg {
+            rules: vec![EligibilityRule {
+                name: None,
+                rule: Rule::NotNull {
+                    field: "subject_idx".to_string(),
+                },
+            }],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EligibilityRule {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub rule: Rule,
+}
+
+///
+/// What a subject must satisfy to stay in the study.  A null value fails every rule but
+/// `NotIn`, where a subject without a value is in none of the excluded groups.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Rule {
+    NotNull { field: String },
+    Min { field: String, value: f64 },
+    Max { field: String, value: f64 },
+    In { field: String, values: Vec<String> },
+    NotIn { field: String, values: Vec<String> },
+    Flag { field: String, value: bool },
+}
+
+impl Rule {
+    pub fn field(&self) -> &str {
+        match self {
+            Rule::NotNull { field }
+            | Rule::Min { field, .. }
+            | Rule::Max { field, .. }
+            | Rule::In { field, .. }
+            | Rule::NotIn { field, .. }
+            | Rule::Flag { field, .. } => field,
+        }
+    }
+    ///
+    /// True for the subjects that pass the rule, on the resolved `field`.
+    ///
+    pub fn expr(&self, field: &str) -> Expr {
+        let any_of = |values: &[String]| {
+            values
+                .iter()
+                .fold(lit(false), |acc, v| {
+                    acc.or(col(field).cast(DataType::Utf8).eq(lit(v.as_str())))
+                })
+                .fill_null(lit(false))
+        };
+        let passes = match self {
+            Rule::NotNull { .. } => col(field).is_not_null(),
+            Rule::Min { value, .. } => col(field).cast(DataType::Float64).gt_eq(lit(*value)),
+            Rule::Max { value, .. } => col(field).cast(DataType::Float64).lt_eq(lit(*value)),
+            Rule::In { values, .. } => any_of(values),
+            Rule::NotIn { values, .. } => any_of(values).not(),
+            Rule::Flag { value, .. } => col(field).cast(DataType::Boolean).eq(lit(*value)),
+        };
+        passes.fill_null(lit(false))
+    }
+}
+/// Why a subject failed the rule
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::NotNull { field } => write!(f, "null {}", field),
+            Rule::Min { field, value } => write!(f, "{} < {}", field, value),
+            Rule::Max { field, value } => write!(f, "{} > {}", field, value),
+            Rule::In { field, values } => write!(f, "{} not in [{}]", field, values.join(", ")),
+            Rule::NotIn { field, values } => write!(f, "{} in [{}]", field, values.join(", ")),
+            Rule::Flag { field, value } => write!(f, "{} is not {}", field, value),
+        }
+    }
+}
+
+impl EligibilityRule {
+    pub fn reason(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.rule.to_string(),
+        }
+    }
+}
+
+///
+/// One row of the attrition table: the subjects a rule excluded and those left after it.
+///
+#[derive(Debug, Clone)]
+pub struct AttritionStep {
+    pub reason: String,
+    pub excluded: usize,
+    pub remaining: usize,
+}
+
+///
+/// CONSORT-style flow from the subjects assessed for eligibility to those included.
+///
+#[derive(Debug, Clone)]
+pub struct Attrition {
+    pub assessed: usize,
+    pub steps: Vec<AttritionStep>,
+}
+
+impl Attrition {
+    pub fn included(&self) -> usize {
+        self.steps
+            .last()
+            .map(|s| s.remaining)
+            .unwrap_or(self.assessed)
+    }
+    pub fn excluded(&self) -> usize {
+        self.assessed - self.included()
+    }
+    ///
+    /// `step`, `reason`, `excluded`, `remaining`; step 0 is the assessed population.
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let mut reasons = vec!["assessed for eligibility"];
+        reasons.extend(self.steps.iter().map(|s| s.reason.as_str()));
+        let mut excluded = vec![0u32];
+        excluded.extend(self.steps.iter().map(|s| s.excluded as u32));
+        let mut remaining = vec![self.assessed as u32];
+        remaining.extend(self.steps.iter().map(|s| s.remaining as u32));
+        Ok(DataFrame::new(vec![
+            Series::new("step", (0..reasons.len() as u32).collect::<Vec<_>>()),
+            Series::new("reason", reasons),
+            Series::new("excluded", excluded),
+            Series::new("remaining", remaining),
+        ])?)
+    }
+}
+impl fmt::Display for Attrition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Assessed for eligibility (n = {})", self.assessed)?;
+        writeln!(f, "  Excluded (n = {})", self.excluded())?;
+        for step in &self.steps {
+            writeln!(
+                f,
+                "    {:<48} {:>8}  → {:>8} remaining",
+                step.reason, step.excluded, step.remaining
+            )?;
+        }
+        write!(f, "Included (n = {})", self.included())
+    }
+}
+
+///
+/// Appends the `include` and `exclusion_reason` columns and returns the attrition table.
+///
+pub fn apply_eligibility(df: DataFrame, cfg: &EligibilityCfg) -> Result<(DataFrame, Attrition)> {
+    let header = Header::from(&df);
+    let rules = cfg
+        .rules
+        .iter()
+        .map(|r| {
+            Ok((
+                r.reason(),
+                r.rule
+                    .expr(&resolve_role(&header, "eligibility rule", r.rule.field())?),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // the first failed rule wins: fold from the last rule out
+    let reason = rules.iter().rev().fold(
+        lit(Null {}).cast(DataType::Utf8),
+        |acc, (reason, passes)| {
+            when(passes.clone().not())
+                .then(lit(reason.as_str()))
+                .otherwise(acc)
+        },
+    );
+    let df = df
+        .lazy()
+        .with_column(reason.alias(EXCLUSION_REASON))
+        .with_column(col(EXCLUSION_REASON).is_null().alias(INCLUDE))
+        .collect()?;
+
+    let recorded = df.column(EXCLUSION_REASON)?.utf8()?;
+    let mut remaining = df.height();
+    let steps = rules
+        .iter()
+        .map(|(reason, _)| {
+            let excluded = recorded
+                .into_iter()
+                .filter(|r| *r == Some(reason.as_str()))
+                .count();
+            remaining -= excluded;
+            AttritionStep {
+                reason: reason.clone(),
+                excluded,
+                remaining,
+            }
+        })
+        .collect();
+    let attrition = Attrition {
+        assessed: df.height(),
+        steps,
+    };
+    Ok((df, attrition))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_eligibility_rules() {
+        let df = df!(
+            "subject_idx" => [Some(0u32), Some(1), None, Some(3), Some(4), Some(5)],
+            "q_specialty" => ["ONC", "ONC", "ONC", "CARD", "HEM", "ONC"],
+            "q_campaign" => [None, Some("B"), None, None, None, Some("C")],
+            "MeaType::m_unitcount.product::A.time::0_23" => [3.0, 2.0, 1.0, 5.0, 0.0, 4.0]
+        )
+        .unwrap();
+        let json = r#"{
+            "rules": [
+              { "type": "NotNull", "field": "subject_idx" },
+              { "type": "In", "field": "q_specialty", "values": ["ONC", "HEM"] },
+              { "type": "Min", "field": "unitcount.product::A", "value": 1.0, "name": "inactive" },
+              { "type": "NotIn", "field": "q_campaign", "values": ["B"] }
+            ]
+        }"#;
+        let cfg: EligibilityCfg = serde_json::from_str(json).unwrap();
+        let (df, attrition) = apply_eligibility(df, &cfg).unwrap();
+
+        let reasons: Vec<Option<&str>> = df
+            .column(EXCLUSION_REASON)
+            .unwrap()
+            .utf8()
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(
+            vec![
+                None,
+                Some("q_campaign in [B]"),
+                Some("null subject_idx"),
+                Some("q_specialty not in [ONC, HEM]"),
+                Some("inactive"),
+                None
+            ],
+            reasons
+        );
+        assert_eq!(
+            2,
+            df.column(INCLUDE).unwrap().bool().unwrap().sum().unwrap()
+        );
+        assert_eq!(
+            (6, 2, 4),
+            (
+                attrition.assessed,
+                attrition.included(),
+                attrition.excluded()
+            )
+        );
+        assert_eq!(
+            vec![5, 4, 3, 2],
+            attrition
+                .steps
+                .iter()
+                .map(|s| s.remaining)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(5, attrition.to_dataframe().unwrap().height());
+    }
+}
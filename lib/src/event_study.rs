@@ -0,0 +1,312 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::columns::{optional_weights, series_to_f64};
+use crate::field_name::single_periods;
+use crate::stats::{chi_square_sf, normal_quantile, solve};
+
+///
+/// Event study on the single-period fields of a measure (`time::14`, `time::15`, ...).  The
+/// coefficient of period k is the (weighted) treated-minus-control difference of Yₖ - Y_ref,
+/// with the reference period defaulting to the one before `treatment_start`.
+///
+/// Weights come from the design (`match_weight`, `cem_weight`, `ipw`, ...); rows with a zero
+/// weight are left out.
+///
+#[derive(Debug, Clone)]
+pub struct EventStudyCfg {
+    pub treatment: String,
+    pub measure: String,
+    /// `key::value` qualifiers of the fields, e.g. `product::A`
+    pub filters: Vec<(String, String)>,
+    pub treatment_start: i64,
+    pub reference: Option<i64>,
+    pub weight: Option<String>,
+    pub level: f64,
+}
+
+impl EventStudyCfg {
+    pub fn new(treatment: &str, measure: &str, treatment_start: i64) -> Self {
+        EventStudyCfg {
+            treatment: treatment.to_string(),
+            measure: measure.to_string(),
+            filters: Vec::new(),
+            treatment_start,
+            reference: None,
+            weight: None,
+            level: 0.95,
+        }
+    }
+    pub fn filter(mut self, key: &str, value: &str) -> Self {
+        self.filters.push((key.to_string(), value.to_string()));
+        self
+    }
+    pub fn reference(mut self, period: i64) -> Self {
+        self.reference = Some(period);
+        self
+    }
+    pub fn weight(mut self, weight: &str) -> Self {
+        self.weight = Some(weight.to_string());
+        self
+    }
+    pub fn level(mut self, level: f64) -> Self {
+        self.level = level;
+        self
+    }
+    pub fn reference_period(&self) -> i64 {
+        self.reference.unwrap_or(self.treatment_start - 1)
+    }
+    ///
+    /// The single-period fields of the measure by period.
+    ///
+    pub fn fields<'a>(&self, names: &[&'a str]) -> Result<BTreeMap<i64, &'a str>> {
+        single_periods(&self.measure, &self.filters, names)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventCoefficient {
+    pub period: i64,
+    /// period - treatment start
+    pub relative: i64,
+    pub estimate: f64,
+    pub se: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+///
+/// Per-period coefficients and the Wald test that every pre-period coefficient is zero,
+/// χ² = θ' Σ⁻¹ θ with the covariance Σ of the influence functions.
+///
+#[derive(Debug, Clone)]
+pub struct EventStudy {
+    pub measure: String,
+    pub reference: i64,
+    pub treatment_start: i64,
+    pub coefficients: Vec<EventCoefficient>,
+    pub pre_trend_chi2: f64,
+    pub pre_trend_df: usize,
+    pub pre_trend_p: f64,
+    pub level: f64,
+}
+
+impl EventStudy {
+    /// No evidence against parallel pre-trends at 1 - level
+    pub fn parallel_trends(&self) -> bool {
+        self.pre_trend_p >= 1.0 - self.level
+    }
+    ///
+    /// `period`, `relative`, `estimate`, `se`, `lower`, `upper`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let column = |f: fn(&EventCoefficient) -> f64| -> Vec<f64> {
+            self.coefficients.iter().map(f).collect()
+        };
+        let period = |f: fn(&EventCoefficient) -> i64| -> Vec<i64> {
+            self.coefficients.iter().map(f).collect()
+        };
+        Ok(DataFrame::new(vec![
+            Series::new("period", period(|c| c.period)),
+            Series::new("relative", period(|c| c.relative)),
+            Series::new("estimate", column(|c| c.estimate)),
+            Series::new("se", column(|c| c.se)),
+            Series::new("lower", column(|c| c.lower)),
+            Series::new("upper", column(|c| c.upper)),
+        ])?)
+    }
+}
+impl fmt::Display for EventStudy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Event study of {} (treatment starts {}, reference {}, {:.0}% CI)",
+            self.measure,
+            self.treatment_start,
+            self.reference,
+            self.level * 100.0
+        )?;
+        for c in &self.coefficients {
+            writeln!(
+                f,
+                "  {:>6} {:>+4} {:>10.4} [{:.4}, {:.4}]",
+                c.period, c.relative, c.estimate, c.lower, c.upper
+            )?;
+        }
+        write!(
+            f,
+            "  pre-trend χ² {:.4} (df {}, p {:.4}) {}",
+            self.pre_trend_chi2,
+            self.pre_trend_df,
+            self.pre_trend_p,
+            match self.parallel_trends() {
+                true => "✅",
+                false => "⚠️ parallel trends rejected",
+            }
+        )
+    }
+}
+
+///
+/// Influence function of the weighted difference in means of `d`, one value per row:
+/// w (d - m₁) / W₁ for the treated and -w (d - m₀) / W₀ for the controls.
+///
+fn influence(d: &[f64], t: &[f64], w: &[f64]) -> (f64, Vec<f64>) {
+    let arm = |treated: bool| -> (f64, f64) {
+        let (mut total, mut sum) = (0.0, 0.0);
+        for ((d, t), w) in d.iter().zip(t).zip(w) {
+            if (*t > 0.5) == treated {
+                total += w;
+                sum += w * d;
+            }
+        }
+        (sum / total, total)
+    };
+    let (m1, w1) = arm(true);
+    let (m0, w0) = arm(false);
+    let psi = d
+        .iter()
+        .zip(t)
+        .zip(w)
+        .map(|((d, t), w)| match *t > 0.5 {
+            true => w * (d - m1) / w1,
+            false => -w * (d - m0) / w0,
+        })
+        .collect();
+    (m1 - m0, psi)
+}
+
+pub fn event_study(df: &DataFrame, cfg: &EventStudyCfg) -> Result<EventStudy> {
+    let fields = cfg.fields(&df.get_column_names())?;
+    let reference = cfg.reference_period();
+    let reference_field = fields.get(&reference).ok_or_else(|| {
+        eyre!(
+            "No single-period field of {} for the reference period {}",
+            cfg.measure,
+            reference
+        )
+    })?;
+    let t = series_to_f64(df.column(&cfg.treatment)?)?;
+    let w: Vec<f64> = match optional_weights(df, cfg.weight.as_deref())? {
+        Some(w) => w.iter().map(|w| w.max(0.0)).collect(),
+        None => vec![1.0; t.len()],
+    };
+    let treated = t
+        .iter()
+        .zip(&w)
+        .filter(|(t, w)| **t > 0.5 && **w > 0.0)
+        .count();
+    let controls = t
+        .iter()
+        .zip(&w)
+        .filter(|(t, w)| **t <= 0.5 && **w > 0.0)
+        .count();
+    if treated < 2 || controls < 2 {
+        return Err(eyre!(
+            "The event study needs two treated and two control subjects, found {} and {}",
+            treated,
+            controls
+        ));
+    }
+    let y_ref = series_to_f64(df.column(reference_field)?)?;
+
+    let z = normal_quantile(1.0 - (1.0 - cfg.level) / 2.0);
+    let mut coefficients = Vec::new();
+    let mut pre: Vec<(f64, Vec<f64>)> = Vec::new();
+    for (period, field) in &fields {
+        let y = series_to_f64(df.column(field)?)?;
+        let d: Vec<f64> = y.iter().zip(&y_ref).map(|(y, r)| y - r).collect();
+        let (estimate, psi) = influence(&d, &t, &w);
+        let se = psi.iter().map(|p| p * p).sum::<f64>().sqrt();
+        let relative = period - cfg.treatment_start;
+        if relative < 0 && *period != reference {
+            pre.push((estimate, psi));
+        }
+        coefficients.push(EventCoefficient {
+            period: *period,
+            relative,
+            estimate,
+            se,
+            lower: estimate - z * se,
+            upper: estimate + z * se,
+        });
+    }
+
+    // Wald test of the pre-period coefficients
+    let k = pre.len();
+    let (pre_trend_chi2, pre_trend_p) = match k {
+        0 => (0.0, 1.0),
+        _ => {
+            let mut sigma = vec![0.0; k * k];
+            for i in 0..k {
+                for j in 0..k {
+                    sigma[i * k + j] = pre[i].1.iter().zip(&pre[j].1).map(|(a, b)| a * b).sum();
+                }
+            }
+            let theta: Vec<f64> = pre.iter().map(|(e, _)| *e).collect();
+            let x = solve(&sigma, &theta, k)?;
+            let chi2: f64 = theta.iter().zip(&x).map(|(a, b)| a * b).sum();
+            (chi2, chi_square_sf(chi2, k as f64))
+        }
+    };
+
+    Ok(EventStudy {
+        measure: cfg.measure.clone(),
+        reference,
+        treatment_start: cfg.treatment_start,
+        coefficients,
+        pre_trend_chi2,
+        pre_trend_df: k,
+        pre_trend_p,
+        level: cfg.level,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// periods 10..=15, treatment from 13 adds 2; `drift` opens a gap before the start
+    fn sample(drift: f64) -> DataFrame {
+        let rows = 300;
+        let treated: Vec<f64> = (0..rows).map(|i| (i % 2) as f64).collect();
+        let mut columns = vec![Series::new("treated", treated.clone())];
+        for period in 10..=15i64 {
+            let values: Vec<f64> = (0..rows)
+                .map(|i| {
+                    let t = treated[i];
+                    let effect = if period >= 13 { 2.0 } else { 0.0 };
+                    let gap = drift * (period - 12).min(0) as f64;
+                    5.0 + t + t * (effect + gap) + ((i * 17 + period as usize * 7) % 9) as f64 / 9.0
+                })
+                .collect();
+            columns.push(Series::new(
+                &format!("MeaType::m_unitcount.product::A.time::{}", period),
+                values,
+            ));
+        }
+        DataFrame::new(columns).unwrap()
+    }
+    #[test]
+    fn test_event_study_parallel_trends() {
+        let df = sample(0.0);
+        let cfg = EventStudyCfg::new("treated", "m_unitcount", 13).filter("product", "A");
+        let found = event_study(&df, &cfg).unwrap();
+        assert_eq!(6, found.coefficients.len());
+        assert_eq!(12, found.reference);
+        assert_eq!(0.0, found.coefficients[2].estimate);
+        assert!((found.coefficients[4].estimate - 2.0).abs() < 0.2);
+        assert_eq!(2, found.pre_trend_df);
+        assert!(found.parallel_trends());
+    }
+    #[test]
+    fn test_event_study_rejects_diverging_trends() {
+        let df = sample(1.0);
+        let cfg = EventStudyCfg::new("treated", "m_unitcount", 13);
+        let found = event_study(&df, &cfg).unwrap();
+        assert!(!found.parallel_trends());
+        assert_eq!(6, found.to_dataframe().unwrap().height());
+    }
+}
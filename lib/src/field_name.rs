@@ -0,0 +1,273 @@
+use color_eyre::eyre::{eyre, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+
+///
+/// Structured view of a field name as generated by the tnc app, e.g.
+///
+/// `MeaType::m_unitcount.product::A.time::0_23.derivedField::decile`
+///
+/// The first `key::value` segment names the measure; the remaining segments qualify it.  Plain
+/// fields (`q_state`, `subject_idx`) have no components and the measure is the whole name.
+///
+/// # Example
+///
+/// ```
+/// use tnc_analysis_lib::prelude::ParsedField;
+///
+/// let field = ParsedField::parse("MeaType::m_unitcount.product::A.time::0_23");
+/// assert_eq!("m_unitcount", field.measure);
+/// assert_eq!(Some("A"), field.component("product"));
+/// assert_eq!(Some((0, 23)), field.time().map(|t| (t.start, t.end)));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedField {
+    pub measure: String,
+    pub components: Vec<(String, String)>,
+}
+
+///
+/// Inclusive window of periods; a single period `time::14` has `start == end`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimeWindow {
+    pub start: i64,
+    pub end: i64,
+}
+impl TimeWindow {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.split_once('_') {
+            Some((start, end)) => Some(TimeWindow {
+                start: start.parse().ok()?,
+                end: end.parse().ok()?,
+            }),
+            None => {
+                let period = value.parse().ok()?;
+                Some(TimeWindow {
+                    start: period,
+                    end: period,
+                })
+            }
+        }
+    }
+    pub fn is_single_period(&self) -> bool {
+        self.start == self.end
+    }
+}
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_single_period() {
+            true => write!(f, "{}", self.start),
+            false => write!(f, "{}_{}", self.start, self.end),
+        }
+    }
+}
+
+const SEPARATOR: &str = "::";
+pub const MEASURE_TYPE_KEY: &str = "MeaType";
+pub const PRODUCT_KEY: &str = "product";
+pub const TIME_KEY: &str = "time";
+pub const DERIVED_KEY: &str = "derivedField";
+
+impl ParsedField {
+    pub fn parse(field: &str) -> Self {
+        let mut segments = field.split('.').map(|s| s.split_once(SEPARATOR));
+        match segments.next() {
+            Some(Some((_, measure))) if segments.clone().all(|s| s.is_some()) => ParsedField {
+                measure: measure.to_string(),
+                components: segments
+                    .flatten()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            },
+            _ => ParsedField {
+                measure: field.to_string(),
+                components: Vec::new(),
+            },
+        }
+    }
+    pub fn is_structured(&self) -> bool {
+        !self.components.is_empty()
+    }
+    pub fn component(&self, key: &str) -> Option<&str> {
+        self.components
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+    pub fn product(&self) -> Option<&str> {
+        self.component(PRODUCT_KEY)
+    }
+    pub fn time(&self) -> Option<TimeWindow> {
+        self.component(TIME_KEY).and_then(TimeWindow::parse)
+    }
+    pub fn derived(&self) -> Option<&str> {
+        self.component(DERIVED_KEY)
+    }
+    ///
+    /// True when the measure matches and every `key::value` filter is present.
+    ///
+    pub fn matches(&self, measure: &str, filters: &[(String, String)]) -> bool {
+        self.measure == measure
+            && filters
+                .iter()
+                .all(|(k, v)| self.component(k) == Some(v.as_str()))
+    }
+    /// Same measure and qualifiers with another value for `key` (added when missing)
+    pub fn with_component(&self, key: &str, value: &str) -> Self {
+        let mut components = self.components.clone();
+        match components.iter_mut().find(|(k, _)| k == key) {
+            Some(component) => component.1 = value.to_string(),
+            None => components.push((key.to_string(), value.to_string())),
+        }
+        ParsedField {
+            measure: self.measure.clone(),
+            components,
+        }
+    }
+}
+///
+/// Fields of the same measure and qualifiers as `outcome` that end before its `time::` window
+/// starts, closest window first.  Derived fields are skipped.
+///
+pub fn pre_periods<'a>(outcome: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let outcome = ParsedField::parse(outcome);
+    let window = match outcome.time() {
+        Some(window) => window,
+        None => return Vec::new(),
+    };
+    let qualifiers = |field: &ParsedField| -> Vec<(String, String)> {
+        field
+            .components
+            .iter()
+            .filter(|(k, _)| k != TIME_KEY)
+            .cloned()
+            .collect()
+    };
+    let mut found: Vec<(TimeWindow, &'a str)> = names
+        .iter()
+        .filter_map(|name| {
+            let field = ParsedField::parse(name);
+            match field.time() {
+                Some(time)
+                    if field.measure == outcome.measure
+                        && field.derived().is_none()
+                        && time.end < window.start
+                        && qualifiers(&field) == qualifiers(&outcome) =>
+                {
+                    Some((time, *name))
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    found.sort_by(|a, b| b.0.end.cmp(&a.0.end).then(b.0.start.cmp(&a.0.start)));
+    found.into_iter().map(|(_, name)| name).collect()
+}
+///
+/// The single-period fields (`time::14`) of a measure by period.  Derived fields are skipped;
+/// two fields for one period (e.g. two products) are an error asking for another filter.
+///
+pub fn single_periods<'a>(
+    measure: &str,
+    filters: &[(String, String)],
+    names: &[&'a str],
+) -> Result<BTreeMap<i64, &'a str>> {
+    let mut fields = BTreeMap::new();
+    for name in names {
+        let field = ParsedField::parse(name);
+        let window = match field.time() {
+            Some(window) if window.is_single_period() => window,
+            _ => continue,
+        };
+        if field.derived().is_some() || !field.matches(measure, filters) {
+            continue;
+        }
+        if let Some(other) = fields.insert(window.start, *name) {
+            return Err(eyre!(
+                "{} and {} share period {}; add a filter, e.g. product",
+                other,
+                name,
+                window.start
+            ));
+        }
+    }
+    Ok(fields)
+}
+///
+/// The pre-period field of `outcome` in the matrix: the named `window` (e.g. `0_13`) or the
+/// closest window before the outcome window.
+///
+pub fn pre_period(outcome: &str, window: Option<&str>, names: &[&str]) -> Result<String> {
+    let field = match window {
+        Some(window) => ParsedField::parse(outcome)
+            .with_component(TIME_KEY, window)
+            .to_string(),
+        None => pre_periods(outcome, names)
+            .first()
+            .map(|f| f.to_string())
+            .ok_or_else(|| eyre!("No pre-period window found for {}", outcome))?,
+    };
+    match names.contains(&field.as_str()) {
+        true => Ok(field),
+        false => Err(eyre!("The pre-period field {} is not in the matrix", field)),
+    }
+}
+
+/// Renders the name back in the tnc app convention
+impl fmt::Display for ParsedField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_structured() {
+            return write!(f, "{}", self.measure);
+        }
+        write!(f, "{}{}{}", MEASURE_TYPE_KEY, SEPARATOR, self.measure)?;
+        for (k, v) in &self.components {
+            write!(f, ".{}{}{}", k, SEPARATOR, v)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_round_trip() {
+        let name = "MeaType::m_unitcount.product::C.time::0_23.derivedField::decile";
+        let field = ParsedField::parse(name);
+        assert_eq!(Some("C"), field.product());
+        assert_eq!(Some("decile"), field.derived());
+        assert_eq!(name, field.to_string());
+    }
+    #[test]
+    fn test_parse_plain() {
+        let field = ParsedField::parse("q_specialty");
+        assert!(!field.is_structured());
+        assert_eq!("q_specialty", field.to_string());
+    }
+    #[test]
+    fn test_pre_periods() {
+        let names = [
+            "MeaType::m_unitcount.product::A.time::0_13",
+            "MeaType::m_unitcount.product::A.time::14_27",
+            "MeaType::m_unitcount.product::B.time::14_27",
+            "MeaType::m_unitcount.product::A.time::28_35",
+            "MeaType::m_unitcount.product::A.time::0_23.derivedField::decile",
+        ];
+        assert_eq!(
+            vec![names[1], names[0]],
+            pre_periods("MeaType::m_unitcount.product::A.time::28_35", &names)
+        );
+        assert!(pre_periods("q_state", &names).is_empty());
+    }
+    #[test]
+    fn test_time_window() {
+        let field = ParsedField::parse("MeaType::m_unitcount.product::A.time::14");
+        assert!(field.time().unwrap().is_single_period());
+        assert_eq!(
+            Some(TimeWindow { start: 28, end: 35 }),
+            ParsedField::parse("MeaType::m_reach.time::28_35").time()
+        );
+    }
+}
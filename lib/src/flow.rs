@@ -0,0 +1,189 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+///
+/// Min-cost flow by successive shortest paths (Dijkstra with Johnson potentials).  Used by the
+/// optimal and full matching modes.
+///
+/// Costs may be negative on the initial graph; the potentials are seeded with Bellman-Ford.
+///
+#[derive(Debug, Clone)]
+pub struct MinCostFlow {
+    graph: Vec<Vec<usize>>,
+    edges: Vec<Edge>,
+}
+
+#[derive(Debug, Clone)]
+struct Edge {
+    to: usize,
+    cap: i64,
+    cost: f64,
+}
+
+const EPS: f64 = 1e-12;
+
+impl MinCostFlow {
+    pub fn new(nodes: usize) -> Self {
+        MinCostFlow {
+            graph: vec![Vec::new(); nodes],
+            edges: Vec::new(),
+        }
+    }
+    /// Returns the id of the forward edge (see [`MinCostFlow::flow`])
+    pub fn add_edge(&mut self, from: usize, to: usize, cap: i64, cost: f64) -> usize {
+        let id = self.edges.len();
+        self.edges.push(Edge { to, cap, cost });
+        self.edges.push(Edge {
+            to: from,
+            cap: 0,
+            cost: -cost,
+        });
+        self.graph[from].push(id);
+        self.graph[to].push(id + 1);
+        id
+    }
+    /// Flow pushed through the forward edge
+    pub fn flow(&self, edge: usize) -> i64 {
+        self.edges[edge ^ 1].cap
+    }
+    ///
+    /// Push flow from `s` to `t` along cheapest paths.  With `while_negative` the augmentation
+    /// stops at the first path that would not lower the total cost (min-cost, any flow);
+    /// otherwise it continues to the max flow (min-cost max-flow).
+    ///
+    /// Returns (flow, cost).
+    ///
+    pub fn run(&mut self, s: usize, t: usize, while_negative: bool) -> (i64, f64) {
+        let n = self.graph.len();
+        let mut potential = self.bellman_ford(s);
+        let (mut flow, mut cost) = (0, 0.0);
+
+        loop {
+            // Dijkstra on the reduced costs
+            let mut dist = vec![f64::INFINITY; n];
+            let mut prev: Vec<Option<usize>> = vec![None; n];
+            let mut heap = BinaryHeap::new();
+            dist[s] = 0.0;
+            heap.push(State { dist: 0.0, node: s });
+            while let Some(State { dist: d, node }) = heap.pop() {
+                if d > dist[node] + EPS {
+                    continue;
+                }
+                for &id in &self.graph[node] {
+                    let edge = &self.edges[id];
+                    if edge.cap <= 0 || !potential[edge.to].is_finite() {
+                        continue;
+                    }
+                    let reduced = (edge.cost + potential[node] - potential[edge.to]).max(0.0);
+                    if dist[node] + reduced + EPS < dist[edge.to] {
+                        dist[edge.to] = dist[node] + reduced;
+                        prev[edge.to] = Some(id);
+                        heap.push(State {
+                            dist: dist[edge.to],
+                            node: edge.to,
+                        });
+                    }
+                }
+            }
+            if !dist[t].is_finite() {
+                break;
+            }
+            for v in 0..n {
+                if dist[v].is_finite() {
+                    potential[v] += dist[v];
+                }
+            }
+
+            // bottleneck and true cost of the path
+            let (mut push, mut path_cost, mut v) = (i64::MAX, 0.0, t);
+            while let Some(id) = prev[v] {
+                push = push.min(self.edges[id].cap);
+                path_cost += self.edges[id].cost;
+                v = self.edges[id ^ 1].to;
+            }
+            if while_negative && path_cost >= -EPS {
+                break;
+            }
+            let mut v = t;
+            while let Some(id) = prev[v] {
+                self.edges[id].cap -= push;
+                self.edges[id ^ 1].cap += push;
+                v = self.edges[id ^ 1].to;
+            }
+            flow += push;
+            cost += path_cost * push as f64;
+        }
+        (flow, cost)
+    }
+    fn bellman_ford(&self, s: usize) -> Vec<f64> {
+        let n = self.graph.len();
+        let mut dist = vec![f64::INFINITY; n];
+        dist[s] = 0.0;
+        for _ in 0..n {
+            let mut changed = false;
+            for u in 0..n {
+                if !dist[u].is_finite() {
+                    continue;
+                }
+                for &id in &self.graph[u] {
+                    let edge = &self.edges[id];
+                    if edge.cap > 0 && dist[u] + edge.cost + EPS < dist[edge.to] {
+                        dist[edge.to] = dist[u] + edge.cost;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        dist
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct State {
+    dist: f64,
+    node: usize,
+}
+impl Eq for State {}
+/// min-heap on the distance
+impl Ord for State {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.dist.total_cmp(&self.dist)
+    }
+}
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_assignment_beats_greedy() {
+        // greedy would pair t0-c0 (1) then t1-c1 (10); optimal is t0-c1 (2) + t1-c0 (2)
+        let cost = [[1.0, 2.0], [2.0, 10.0]];
+        let mut mcf = MinCostFlow::new(6);
+        let mut ids = Vec::new();
+        for (t, row) in cost.iter().enumerate() {
+            mcf.add_edge(0, 2 + t, 1, 0.0);
+            mcf.add_edge(4 + t, 1, 1, 0.0);
+            for (c, d) in row.iter().enumerate() {
+                ids.push(((t, c), mcf.add_edge(2 + t, 4 + c, 1, *d)));
+            }
+        }
+        let (flow, total) = mcf.run(0, 1, false);
+        assert_eq!(2, flow);
+        assert!((total - 4.0).abs() < 1e-12);
+        let chosen: Vec<(usize, usize)> = ids
+            .iter()
+            .filter(|(_, id)| mcf.flow(*id) == 1)
+            .map(|(tc, _)| *tc)
+            .collect();
+        assert_eq!(vec![(0, 1), (1, 0)], chosen);
+    }
+}
@@ -0,0 +1,207 @@
+use color_eyre::eyre::{eyre, Result};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+///
+/// Random forest of CART classification trees (gini impurity).  The score is the average of
+/// the leaf treatment shares across the trees.
+///
+/// X is row-dominant; the constant bias slot is harmless (it never splits).
+///
+/// Pure leaves give scores of exactly 0 or 1; predictions are clipped to [EPS, 1 - EPS] so the
+/// inverse-probability weights and the log-loss stay finite.
+///
+#[derive(Debug, Clone)]
+pub struct Forest {
+    trees: Vec<Node>,
+}
+
+const EPS: f64 = 1e-6;
+
+#[derive(Debug, Clone)]
+pub struct ForestCfg {
+    pub trees: usize,
+    pub max_depth: usize,
+    pub min_leaf: usize,
+    /// features tried at each split; defaults to √cols
+    pub mtry: Option<usize>,
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf(f64),
+    Split {
+        feature: usize,
+        threshold: f64,
+        left: Box<Node>,
+        right: Box<Node>,
+    },
+}
+
+impl Forest {
+    pub fn fit(x: &[f64], y: &[f64], rows: usize, cfg: &ForestCfg) -> Result<Self> {
+        if rows == 0 || x.is_empty() {
+            return Err(eyre!(
+                "The random forest needs a non-empty X, found {} values for {} rows",
+                x.len(),
+                rows
+            ));
+        }
+        let cols = x.len() / rows;
+        let mtry = cfg
+            .mtry
+            .unwrap_or_else(|| (cols as f64).sqrt().ceil() as usize)
+            .clamp(1, cols);
+        let mut rng = StdRng::seed_from_u64(cfg.seed);
+
+        let trees = (0..cfg.trees)
+            .map(|_| {
+                // bootstrap sample of the rows
+                let mut sample: Vec<usize> = (0..rows).map(|_| rng.gen_range(0..rows)).collect();
+                let grower = Grower {
+                    x,
+                    y,
+                    cols,
+                    mtry,
+                    cfg,
+                };
+                grower.grow(&mut sample, 0, &mut rng)
+            })
+            .collect();
+
+        Ok(Forest { trees })
+    }
+    pub fn predict(&self, x: &[f64], cols: usize) -> Vec<f64> {
+        x.chunks(cols)
+            .map(|row| {
+                let p = self.trees.iter().map(|tree| tree.predict(row)).sum::<f64>()
+                    / self.trees.len() as f64;
+                p.clamp(EPS, 1.0 - EPS)
+            })
+            .collect()
+    }
+}
+
+impl Node {
+    fn predict(&self, row: &[f64]) -> f64 {
+        match self {
+            Node::Leaf(p) => *p,
+            Node::Split {
+                feature,
+                threshold,
+                left,
+                right,
+            } => match row[*feature] <= *threshold {
+                true => left.predict(row),
+                false => right.predict(row),
+            },
+        }
+    }
+}
+
+struct Grower<'a> {
+    x: &'a [f64],
+    y: &'a [f64],
+    cols: usize,
+    mtry: usize,
+    cfg: &'a ForestCfg,
+}
+
+impl<'a> Grower<'a> {
+    fn value(&self, row: usize, feature: usize) -> f64 {
+        self.x[row * self.cols + feature]
+    }
+    fn grow(&self, rows: &mut [usize], depth: usize, rng: &mut StdRng) -> Node {
+        let positives: f64 = rows.iter().map(|r| self.y[*r]).sum();
+        let share = positives / rows.len() as f64;
+        if depth >= self.cfg.max_depth
+            || rows.len() < 2 * self.cfg.min_leaf
+            || share == 0.0
+            || share == 1.0
+        {
+            return Node::Leaf(share);
+        }
+
+        let mut features: Vec<usize> = (0..self.cols).collect();
+        features.shuffle(rng);
+
+        // (gini, feature, threshold)
+        let mut best: Option<(f64, usize, f64)> = None;
+        for feature in features.into_iter().take(self.mtry) {
+            rows.sort_by(|a, b| self.value(*a, feature).total_cmp(&self.value(*b, feature)));
+            let n = rows.len() as f64;
+            let mut left_pos = 0.0;
+            for split in 1..rows.len() {
+                left_pos += self.y[rows[split - 1]];
+                let (lo, hi) = (
+                    self.value(rows[split - 1], feature),
+                    self.value(rows[split], feature),
+                );
+                if lo == hi || split < self.cfg.min_leaf || rows.len() - split < self.cfg.min_leaf {
+                    continue;
+                }
+                let n_left = split as f64;
+                let n_right = n - n_left;
+                let right_pos = positives - left_pos;
+                let gini = n_left * gini(left_pos / n_left) + n_right * gini(right_pos / n_right);
+                let better = match best {
+                    None => true,
+                    Some((g, _, _)) => gini < g,
+                };
+                if better {
+                    best = Some((gini, feature, (lo + hi) / 2.0));
+                }
+            }
+        }
+
+        match best {
+            None => Node::Leaf(share),
+            Some((_, feature, threshold)) => {
+                let mut left: Vec<usize> = Vec::with_capacity(rows.len());
+                let mut right: Vec<usize> = Vec::with_capacity(rows.len());
+                for r in rows.iter() {
+                    match self.value(*r, feature) <= threshold {
+                        true => left.push(*r),
+                        false => right.push(*r),
+                    }
+                }
+                Node::Split {
+                    feature,
+                    threshold,
+                    left: Box::new(self.grow(&mut left, depth + 1, rng)),
+                    right: Box::new(self.grow(&mut right, depth + 1, rng)),
+                }
+            }
+        }
+    }
+}
+
+fn gini(p: f64) -> f64 {
+    2.0 * p * (1.0 - p)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_forest_learns_threshold() {
+        let rows = 200;
+        let x: Vec<f64> = (0..rows).flat_map(|i| [i as f64, 1.0]).collect();
+        let y: Vec<f64> = (0..rows).map(|i| (i >= 120) as u8 as f64).collect();
+        let cfg = ForestCfg {
+            trees: 20,
+            max_depth: 4,
+            min_leaf: 5,
+            mtry: Some(2),
+            seed: 3,
+        };
+        let scores = Forest::fit(&x, &y, rows, &cfg).unwrap().predict(&x, 2);
+        assert!(scores[10] < 0.2);
+        assert!(scores[190] > 0.8);
+        assert!(scores.iter().all(|p| *p > 0.0 && *p < 1.0));
+        assert!(Forest::fit(&[], &[], 0, &cfg).is_err());
+    }
+}
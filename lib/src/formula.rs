@@ -0,0 +1,371 @@
+use color_eyre::eyre::{eyre, Result};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::complete::{char, multispace0, one_of, satisfy};
+use nom::combinator::{all_consuming, map, not, opt, peek};
+use nom::multi::{many0, separated_list1};
+use nom::sequence::{delimited, pair, separated_pair, terminated, tuple};
+use nom::IResult;
+use std::fmt;
+
+use crate::config::FieldNamesCfg;
+use crate::field_name::ParsedField;
+use crate::get_fuzzy_predictors;
+use crate::header::Header;
+use crate::terms::{ident, resolve_terms, term, ws, Term};
+
+///
+/// A propensity model, `target ~ predictors`.  Renders as an R-style formula and parses back
+/// from one, resolving the names against the matrix [`Header`].
+///
+/// Right-hand side items, joined with `+` (include) or `-` (exclude):
+///
+/// * `.`                                 all detected predictors (quality and derived fields)
+/// * `q_state`                           a field; a unique fuzzy match is accepted
+/// * `C(q_innetwork)`                    force the field to be categorical, in design terms too
+/// * `m_unitcount[product::A, time::14]` every field of the measure with those qualifiers
+/// * `a * b`, `a:b`, `poly(x, 2)`, `bs(x, 5)`  design terms, see [`Term`]
+///
+/// Field names with characters other than `[A-Za-z0-9_.]` are quoted with backticks.
+///
+#[derive(Debug, Clone)]
+pub struct ModelDef<'a> {
+    dependent: &'a str,
+    predictors: Vec<&'a str>,
+    categorical: Vec<&'a str>,
+    terms: Vec<Term>,
+}
+impl<'a> From<(&'a str, Vec<&'a str>)> for ModelDef<'a> {
+    fn from((dependent, predictors): (&'a str, Vec<&'a str>)) -> Self {
+        ModelDef {
+            dependent,
+            predictors,
+            categorical: Vec::new(),
+            terms: Vec::new(),
+        }
+    }
+}
+impl<'a> fmt::Display for ModelDef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rhs: Vec<String> = self
+            .predictors
+            .iter()
+            .map(|p| match self.categorical.contains(p) {
+                true => format!("C({})", p),
+                false => p.to_string(),
+            })
+            .chain(self.terms.iter().map(|t| t.to_string()))
+            .collect();
+        write!(f, "{} ~ {}", self.dependent, rhs.join(" + "))
+    }
+}
+impl<'a> ModelDef<'a> {
+    pub fn target(&self) -> &'a str {
+        self.dependent
+    }
+    pub fn predictors(&self) -> &[&'a str] {
+        &self.predictors
+    }
+    pub fn categorical(&self) -> &[&'a str] {
+        &self.categorical
+    }
+    /// Interaction, polynomial and spline terms (main effects are in the predictors)
+    pub fn terms(&self) -> &[Term] {
+        &self.terms
+    }
+    ///
+    /// Parse and resolve `target ~ rhs` against the header.  `cfg` sets what `.` expands to.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use tnc_analysis_lib::prelude::{FieldNamesCfg, ModelDef};
+    ///
+    /// let header = vec![
+    ///     "subject_idx",
+    ///     "q_innetwork",
+    ///     "q_specialty",
+    ///     "q_state",
+    ///     "MeaType::m_reach.time::28_35",
+    ///     "MeaType::m_unitcount.product::A.time::14",
+    ///     "MeaType::m_unitcount.product::A.time::15",
+    ///     "MeaType::m_unitcount.product::C.time::0_23.derivedField::decile",
+    /// ].into();
+    /// let cfg: FieldNamesCfg = serde_json::from_str(r#"{
+    ///     "quality-field-tag": "q_",
+    ///     "derived-field-tag": "derived",
+    ///     "binary-target-field-tag": "reach"
+    /// }"#).unwrap();
+    ///
+    /// let model = ModelDef::parse("reach ~ . - q_state + C(q_innetwork):q_specialty", &header, &cfg);
+    /// assert!(model.is_err(), "C() is not a design term");
+    ///
+    /// let model = ModelDef::parse(
+    ///     "reach ~ . - q_state + C(q_innetwork) + m_unitcount[product::A]",
+    ///     &header,
+    ///     &cfg,
+    /// ).unwrap();
+    /// assert_eq!("MeaType::m_reach.time::28_35", model.target());
+    /// assert_eq!(5, model.predictors().len());
+    /// assert_eq!(vec!["q_innetwork"], model.categorical());
+    /// ```
+    pub fn parse(formula: &str, header: &Header<'a>, cfg: &FieldNamesCfg) -> Result<Self> {
+        let (_, (lhs, rhs)) = all_consuming(delimited(
+            multispace0,
+            separated_pair(item, ws(char('~')), rhs),
+            multispace0,
+        ))(formula)
+        .map_err(|e| eyre!("Failed to parse formula '{}': {}", formula, e))?;
+
+        let names: &[&'a str] = header;
+        let dependent = match lhs.resolve(names, cfg)?.as_slice() {
+            [target] => *target,
+            [] => return Err(eyre!("The formula has no target: {}", formula)),
+            many => return Err(eyre!("The target must be a single field: {:?}", many)),
+        };
+
+        let mut model = ModelDef::from((dependent, Vec::new()));
+        for (sign, item) in rhs {
+            match (sign, item) {
+                ('+', Item::Terms(terms)) => {
+                    // the target is never its own predictor, alone or in a design term
+                    for term in resolve_terms(&terms, header)? {
+                        match term {
+                            Term::Main(field) => {
+                                let field = lookup(&field, names)?;
+                                if field != dependent {
+                                    model.include(field);
+                                }
+                            }
+                            term if term.fields().contains(&dependent) => {}
+                            term if !model.terms.contains(&term) => model.terms.push(term),
+                            _ => {}
+                        }
+                    }
+                }
+                ('+', item) => {
+                    let fields = item.resolve(names, cfg)?;
+                    for field in fields.iter().filter(|f| **f != dependent) {
+                        model.include(field);
+                    }
+                    if let Item::Categorical(_) = item {
+                        model.categorical.extend(fields);
+                    }
+                }
+                (_, Item::Terms(terms)) => {
+                    for term in resolve_terms(&terms, header)? {
+                        match term {
+                            Term::Main(field) => model.exclude(&[lookup(&field, names)?]),
+                            term => model.terms.retain(|t| *t != term),
+                        }
+                    }
+                }
+                (_, item) => model.exclude(&item.resolve(names, cfg)?),
+            }
+        }
+        Ok(model)
+    }
+    /// Drop the fields along with any design term that uses them
+    fn exclude(&mut self, excluded: &[&str]) {
+        self.predictors.retain(|p| !excluded.contains(p));
+        self.categorical.retain(|p| !excluded.contains(p));
+        self.terms
+            .retain(|t| t.fields().iter().all(|f| !excluded.contains(f)));
+    }
+    fn include(&mut self, field: &'a str) {
+        if !self.predictors.contains(&field) {
+            self.predictors.push(field);
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Parsing
+#[derive(Debug, Clone)]
+enum Item {
+    All,
+    Categorical(String),
+    Selector {
+        measure: String,
+        filters: Vec<(String, String)>,
+    },
+    Terms(Vec<Term>),
+}
+
+impl Item {
+    fn resolve<'a>(&self, names: &[&'a str], cfg: &FieldNamesCfg) -> Result<Vec<&'a str>> {
+        match self {
+            Item::All => Ok(get_fuzzy_predictors(names.to_vec(), cfg.clone())),
+            Item::Categorical(field) => Ok(vec![lookup(field, names)?]),
+            Item::Selector { measure, filters } => {
+                let found: Vec<&'a str> = names
+                    .iter()
+                    .filter(|name| ParsedField::parse(name).matches(measure, filters))
+                    .copied()
+                    .collect();
+                match found.is_empty() {
+                    true => Err(eyre!("No field matches {}{:?}", measure, filters)),
+                    false => Ok(found),
+                }
+            }
+            Item::Terms(terms) => terms
+                .iter()
+                .map(|term| match term {
+                    Term::Main(field) => lookup(field, names),
+                    _ => Err(eyre!("Expected a field, found the term: {}", term)),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The signed items of the right-hand side; a leading item without a sign is included.
+fn rhs(input: &str) -> IResult<&str, Vec<(char, Item)>> {
+    let (input, first) = pair(opt(ws(char('-'))), item)(input)?;
+    let (input, mut others) = many0(pair(ws(one_of("+-")), item))(input)?;
+    others.insert(0, (first.0.unwrap_or('+'), first.1));
+    Ok((input, others))
+}
+
+fn item(input: &str) -> IResult<&str, Item> {
+    alt((
+        map(
+            terminated(
+                char('.'),
+                not(peek(satisfy(|c| c.is_alphanumeric() || c == '_'))),
+            ),
+            |_| Item::All,
+        ),
+        map(
+            delimited(tag("C("), ws(ident), char(')')),
+            Item::Categorical,
+        ),
+        map(
+            pair(
+                ident,
+                delimited(
+                    ws(char('[')),
+                    separated_list1(ws(char(',')), key_value),
+                    ws(char(']')),
+                ),
+            ),
+            |(measure, filters)| Item::Selector { measure, filters },
+        ),
+        // a single design term; the `+` and `-` between items are handled by rhs
+        map(term, Item::Terms),
+    ))(input)
+}
+fn key_value(input: &str) -> IResult<&str, (String, String)> {
+    let part = |i| take_while1(|c: char| c.is_alphanumeric() || c == '_')(i);
+    map(
+        tuple((part, tag("::"), part)),
+        |(k, _, v): (&str, &str, &str)| (k.to_string(), v.to_string()),
+    )(input)
+}
+
+/// Exact name first, otherwise a unique fuzzy match
+fn lookup<'a>(field: &str, names: &[&'a str]) -> Result<&'a str> {
+    if let Some(found) = names.iter().find(|n| **n == field) {
+        return Ok(found);
+    }
+    let header = Header::new(names.to_vec());
+    match header.get_fuzzy_fields(field).as_slice() {
+        [(_, idx)] => Ok(names[*idx]),
+        [] => Err(eyre!("Field not found in the matrix: {}", field)),
+        many => Err(eyre!(
+            "Field {} is ambiguous: {:?}",
+            field,
+            many.iter().map(|(f, _)| *f).collect::<Vec<_>>()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header() -> Header<'static> {
+        vec![
+            "subject_idx",
+            "q_innetwork",
+            "q_specialty",
+            "q_state",
+            "MeaType::m_reach.time::28_35",
+            "MeaType::m_unitcount.product::A.time::14",
+            "MeaType::m_unitcount.product::A.time::15",
+            "MeaType::m_unitcount.product::B.time::14",
+        ]
+        .into()
+    }
+    fn cfg() -> FieldNamesCfg {
+        serde_json::from_str(
+            r#"{
+                "quality-field-tag": "q_",
+                "derived-field-tag": "derived",
+                "binary-target-field-tag": "reach"
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_all_and_exclude() {
+        let header = header();
+        let model = ModelDef::parse("reach ~ . - q_state", &header, &cfg()).unwrap();
+        assert_eq!("MeaType::m_reach.time::28_35", model.target());
+        assert_eq!(vec!["q_innetwork", "q_specialty"], model.predictors());
+
+        // excluding a field drops the design terms that use it
+        let model =
+            ModelDef::parse("reach ~ . + q_state:q_innetwork - q_state", &header, &cfg()).unwrap();
+        assert!(model.terms().is_empty());
+    }
+    #[test]
+    fn test_categorical() {
+        let header = header();
+        let model = ModelDef::parse("reach ~ q_state + C(q_innetwork)", &header, &cfg()).unwrap();
+        assert_eq!(vec!["q_state", "q_innetwork"], model.predictors());
+        assert_eq!(vec!["q_innetwork"], model.categorical());
+        assert_eq!(
+            "MeaType::m_reach.time::28_35 ~ q_state + C(q_innetwork)",
+            model.to_string()
+        );
+    }
+    #[test]
+    fn test_selector() {
+        let header = header();
+        let model = ModelDef::parse("reach ~ m_unitcount[product::A]", &header, &cfg()).unwrap();
+        assert_eq!(
+            vec![
+                "MeaType::m_unitcount.product::A.time::14",
+                "MeaType::m_unitcount.product::A.time::15"
+            ],
+            model.predictors()
+        );
+        let model =
+            ModelDef::parse("reach ~ m_unitcount[product::A, time::14]", &header, &cfg()).unwrap();
+        assert_eq!(1, model.predictors().len());
+    }
+    #[test]
+    fn test_target_is_not_a_predictor() {
+        let header = header();
+        let model = ModelDef::parse(
+            "reach ~ q_state + m_reach + q_state:m_reach + poly(m_reach, 2)",
+            &header,
+            &cfg(),
+        )
+        .unwrap();
+        assert_eq!(vec!["q_state"], model.predictors());
+        assert!(model.terms().is_empty());
+    }
+    #[test]
+    fn test_parse_errors() {
+        let header = header();
+        // no such field, ambiguous target, C() inside a design term, trailing operator
+        assert!(ModelDef::parse("reach ~ q_missing", &header, &cfg()).is_err());
+        assert!(ModelDef::parse("m_unitcount ~ q_state", &header, &cfg()).is_err());
+        assert!(ModelDef::parse("reach ~ C(q_state):q_innetwork", &header, &cfg()).is_err());
+        assert!(ModelDef::parse("reach ~ q_state +", &header, &cfg()).is_err());
+        assert!(ModelDef::parse("reach ~ m_unitcount[product::Z]", &header, &cfg()).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use color_eyre::eyre::Result;
+use tracing::{event, Level};
+
+use crate::stats::{normal_cdf, normal_pdf, sigmoid, solve};
+
+///
+/// Binary response GLM (logit or probit link) fitted with Fisher scoring / iteratively
+/// reweighted least squares.
+///
+/// The logit family scores in-sample with `propensity_score::logit::run`, which predicts only on
+/// the rows it was fitted with.  Scoring rows held out of the fit (cross-fitting) requires the
+/// coefficients, hence this light-weight version; probit and the outcome models use it too.
+///
+/// X is row-dominant and is expected to include the bias slot (see [`crate::to_row_dominant`]).
+///
+#[derive(Debug, Clone)]
+pub struct GlmFit {
+    pub link: Link,
+    pub coefficients: Vec<f64>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Link {
+    Logit,
+    Probit,
+}
+impl Link {
+    /// inverse link, μ = F(η)
+    pub fn mean(&self, eta: f64) -> f64 {
+        match self {
+            Link::Logit => sigmoid(eta),
+            Link::Probit => normal_cdf(eta),
+        }
+    }
+    /// dμ/dη
+    fn derivative(&self, eta: f64) -> f64 {
+        match self {
+            Link::Logit => {
+                let p = sigmoid(eta);
+                p * (1.0 - p)
+            }
+            Link::Probit => normal_pdf(eta),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IrlsCfg {
+    pub max_iters: usize,
+    pub tolerance: f64,
+    /// ridge penalty that keeps the Hessian invertible when dummies are sparse or separate y
+    pub ridge: f64,
+}
+impl Default for IrlsCfg {
+    fn default() -> Self {
+        IrlsCfg {
+            max_iters: 100,
+            tolerance: 1e-8,
+            ridge: 1e-6,
+        }
+    }
+}
+
+impl GlmFit {
+    pub fn fit(x: &[f64], y: &[f64], rows: usize, link: Link, cfg: &IrlsCfg) -> Result<Self> {
+        let cols = x.len() / rows;
+        let mut beta = vec![0.0; cols];
+        let mut iterations = 0;
+        let mut converged = false;
+
+        while iterations < cfg.max_iters {
+            iterations += 1;
+            // score X'(y - μ)f/V and expected information X'WX, accumulated one row at a time
+            let mut gradient = vec![0.0; cols];
+            let mut hessian = vec![0.0; cols * cols];
+            for (row, y) in x.chunks(cols).zip(y) {
+                let eta = dot(row, &beta);
+                let mu = link.mean(eta).clamp(1e-10, 1.0 - 1e-10);
+                let d = link.derivative(eta);
+                let v = mu * (1.0 - mu);
+                let w = (d * d / v).max(1e-10);
+                let r = (y - mu) * d / v;
+                for i in 0..cols {
+                    gradient[i] += row[i] * r;
+                    for j in i..cols {
+                        hessian[i * cols + j] += w * row[i] * row[j];
+                    }
+                }
+            }
+            for i in 0..cols {
+                gradient[i] -= cfg.ridge * beta[i];
+                hessian[i * cols + i] += cfg.ridge;
+                for j in 0..i {
+                    hessian[i * cols + j] = hessian[j * cols + i];
+                }
+            }
+            let step = solve(&hessian, &gradient, cols)?;
+            beta.iter_mut().zip(&step).for_each(|(b, s)| *b += s);
+
+            if step.iter().map(|s| s.abs()).fold(0.0, f64::max) < cfg.tolerance {
+                converged = true;
+                break;
+            }
+        }
+        Ok(GlmFit {
+            link,
+            coefficients: beta,
+            iterations,
+            converged,
+        })
+    }
+    /// Probability scores for the row-dominant X (same column layout as the fit).
+    pub fn predict(&self, x: &[f64]) -> Vec<f64> {
+        x.chunks(self.coefficients.len())
+            .map(|row| self.link.mean(dot(row, &self.coefficients)))
+            .collect()
+    }
+}
+
+///
+/// Least squares on the row-dominant X with a small ridge: (X'WX + λI) β = X'Wy.  `weights`
+/// defaults to 1 for every row.
+///
+pub fn ols(
+    x: &[f64],
+    y: &[f64],
+    weights: Option<&[f64]>,
+    rows: usize,
+    ridge: f64,
+) -> Result<Vec<f64>> {
+    let cols = x.len() / rows;
+    let mut xtx = vec![0.0; cols * cols];
+    let mut xty = vec![0.0; cols];
+    for (r, (row, y)) in x.chunks(cols).zip(y).enumerate() {
+        let w = weights.map_or(1.0, |w| w[r]);
+        for i in 0..cols {
+            xty[i] += w * row[i] * y;
+            for j in i..cols {
+                xtx[i * cols + j] += w * row[i] * row[j];
+            }
+        }
+    }
+    for i in 0..cols {
+        xtx[i * cols + i] += ridge;
+        for j in 0..i {
+            xtx[i * cols + j] = xtx[j * cols + i];
+        }
+    }
+    solve(&xtx, &xty, cols)
+}
+
+///
+/// Poisson regression (log link) by Newton-Raphson; returns the coefficients.
+///
+pub fn poisson(x: &[f64], y: &[f64], rows: usize, cfg: &IrlsCfg) -> Result<Vec<f64>> {
+    let cols = x.len() / rows;
+    // start at the log of the mean on the bias slot (the last column)
+    let mut beta = vec![0.0; cols];
+    beta[cols - 1] = (y.iter().sum::<f64>() / rows as f64).max(1e-10).ln();
+    let mut iterations = 0;
+    let mut converged = false;
+
+    while iterations < cfg.max_iters {
+        iterations += 1;
+        let mut gradient = vec![0.0; cols];
+        let mut hessian = vec![0.0; cols * cols];
+        for (row, y) in x.chunks(cols).zip(y) {
+            let mu = dot(row, &beta).min(50.0).exp();
+            for i in 0..cols {
+                gradient[i] += row[i] * (y - mu);
+                for j in i..cols {
+                    hessian[i * cols + j] += mu * row[i] * row[j];
+                }
+            }
+        }
+        for i in 0..cols {
+            gradient[i] -= cfg.ridge * beta[i];
+            hessian[i * cols + i] += cfg.ridge;
+            for j in 0..i {
+                hessian[i * cols + j] = hessian[j * cols + i];
+            }
+        }
+        let step = solve(&hessian, &gradient, cols)?;
+        beta.iter_mut().zip(&step).for_each(|(b, s)| *b += s);
+
+        if step.iter().map(|s| s.abs()).fold(0.0, f64::max) < cfg.tolerance {
+            converged = true;
+            break;
+        }
+    }
+    if !converged {
+        event!(
+            Level::WARN,
+            "⚠️ poisson regression did not converge in {} iterations",
+            iterations
+        );
+    }
+    Ok(beta)
+}
+
+///
+/// Multinomial logit for k >= 2 arms, fitted with Newton-Raphson on the full block Hessian.
+/// Arm 0 is the reference (its coefficients are fixed at zero); `coefficients[j - 1]` holds the
+/// coefficients of arm j.
+///
+#[derive(Debug, Clone)]
+pub struct MultinomialFit {
+    pub arms: usize,
+    pub coefficients: Vec<Vec<f64>>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+impl MultinomialFit {
+    /// `labels` holds the arm index of each row
+    pub fn fit(
+        x: &[f64],
+        labels: &[usize],
+        arms: usize,
+        rows: usize,
+        cfg: &IrlsCfg,
+    ) -> Result<Self> {
+        let cols = x.len() / rows;
+        let free = arms - 1;
+        let size = free * cols;
+        let mut fit = MultinomialFit {
+            arms,
+            coefficients: vec![vec![0.0; cols]; free],
+            iterations: 0,
+            converged: false,
+        };
+
+        while fit.iterations < cfg.max_iters {
+            fit.iterations += 1;
+            let mut gradient = vec![0.0; size];
+            let mut hessian = vec![0.0; size * size];
+            for (row, label) in x.chunks(cols).zip(labels) {
+                let p = fit.probabilities(row);
+                for j in 0..free {
+                    let r = (*label == j + 1) as u8 as f64 - p[j + 1];
+                    for a in 0..cols {
+                        gradient[j * cols + a] += row[a] * r;
+                    }
+                    // H_jl = Σ x x' p_j (δ_jl - p_l), upper triangle of the blocks
+                    for l in j..free {
+                        let w = p[j + 1] * ((j == l) as u8 as f64 - p[l + 1]);
+                        for a in 0..cols {
+                            for b in 0..cols {
+                                hessian[(j * cols + a) * size + l * cols + b] +=
+                                    w * row[a] * row[b];
+                            }
+                        }
+                    }
+                }
+            }
+            for i in 0..size {
+                gradient[i] -= cfg.ridge * fit.coefficients[i / cols][i % cols];
+                hessian[i * size + i] += cfg.ridge;
+                for k in 0..i {
+                    hessian[i * size + k] = hessian[k * size + i];
+                }
+            }
+            let step = solve(&hessian, &gradient, size)?;
+            for (i, s) in step.iter().enumerate() {
+                fit.coefficients[i / cols][i % cols] += s;
+            }
+            if step.iter().map(|s| s.abs()).fold(0.0, f64::max) < cfg.tolerance {
+                fit.converged = true;
+                break;
+            }
+        }
+        Ok(fit)
+    }
+    /// Softmax over the arms for one row
+    fn probabilities(&self, row: &[f64]) -> Vec<f64> {
+        let eta: Vec<f64> = std::iter::once(0.0)
+            .chain(self.coefficients.iter().map(|beta| dot(row, beta)))
+            .collect();
+        let max = eta.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let exp: Vec<f64> = eta.iter().map(|e| (e - max).exp()).collect();
+        let total: f64 = exp.iter().sum();
+        exp.iter().map(|e| e / total).collect()
+    }
+    /// Row-dominant probabilities, `arms` per row, for the row-dominant X
+    pub fn predict(&self, x: &[f64]) -> Vec<f64> {
+        let cols = self.coefficients[0].len();
+        debug_assert_eq!(self.arms, self.coefficients.len() + 1);
+        x.chunks(cols)
+            .flat_map(|row| self.probabilities(row))
+            .collect()
+    }
+}
+
+pub(crate) fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// y = 1 when x > 0 with some overlap around zero
+    fn sample() -> (Vec<f64>, Vec<f64>, usize) {
+        let rows = 200;
+        let mut x = Vec::with_capacity(rows * 2);
+        let mut y = Vec::with_capacity(rows);
+        for i in 0..rows {
+            let v = (i as f64 - 100.0) / 25.0;
+            x.extend_from_slice(&[v, 1.0]);
+            y.push(if (v > 0.0) ^ (i % 7 == 0) { 1.0 } else { 0.0 });
+        }
+        (x, y, rows)
+    }
+    #[test]
+    fn test_logit_and_probit_agree_on_direction() {
+        let (x, y, rows) = sample();
+        for link in [Link::Logit, Link::Probit] {
+            let fit = GlmFit::fit(&x, &y, rows, link, &IrlsCfg::default()).unwrap();
+            assert!(fit.converged);
+            assert!(fit.coefficients[0] > 0.0);
+            let scores = fit.predict(&x);
+            assert!(scores[0] < 0.5 && scores[rows - 1] > 0.5);
+        }
+    }
+    #[test]
+    fn test_ols_and_poisson_recover_coefficients() {
+        let rows = 100;
+        let x: Vec<f64> = (0..rows).flat_map(|i| [i as f64 / 50.0, 1.0]).collect();
+        let y: Vec<f64> = (0..rows).map(|i| 3.0 * i as f64 / 50.0 - 1.0).collect();
+        let beta = ols(&x, &y, None, rows, 0.0).unwrap();
+        assert!((beta[0] - 3.0).abs() < 1e-9 && (beta[1] + 1.0).abs() < 1e-9);
+
+        let counts: Vec<f64> = (0..rows)
+            .map(|i| (0.5 + 0.8 * i as f64 / 50.0).exp().round())
+            .collect();
+        let beta = poisson(&x, &counts, rows, &IrlsCfg::default()).unwrap();
+        assert!((beta[0] - 0.8).abs() < 0.1);
+    }
+    #[test]
+    fn test_multinomial_with_two_arms_is_logit() {
+        let (x, y, rows) = sample();
+        let labels: Vec<usize> = y.iter().map(|y| *y as usize).collect();
+        let cfg = IrlsCfg::default();
+        let logit = GlmFit::fit(&x, &y, rows, Link::Logit, &cfg).unwrap();
+        let multi = MultinomialFit::fit(&x, &labels, 2, rows, &cfg).unwrap();
+        assert!(multi.converged);
+        let scores = multi.predict(&x);
+        for (i, p) in logit.predict(&x).iter().enumerate() {
+            assert!((scores[i * 2 + 1] - p).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::glm::{IrlsCfg, MultinomialFit};
+use crate::propensity::{Predictors, PredictorsOwned};
+use crate::stats::{mean, variance};
+use crate::terms::{numeric_columns, Term};
+
+///
+/// The arms of a multi-valued treatment read from a categorical (or integer coded) column,
+/// e.g. messaging variants `A`, `B`, `C` or dosage levels `0`, `1`, `2`.
+///
+/// Arms are ordered numerically when every value is a number, lexically otherwise; the
+/// reference arm (the first) can be chosen.
+///
+#[derive(Debug, Clone)]
+pub struct Arms {
+    pub field: String,
+    pub names: Vec<String>,
+    /// arm index of each row
+    pub labels: Vec<usize>,
+}
+
+impl Arms {
+    pub fn new(treatment: &Series, reference: Option<&str>) -> Result<Self> {
+        let values: Vec<String> = treatment
+            .cast(&DataType::Utf8)?
+            .utf8()?
+            .into_iter()
+            .map(|v| {
+                v.map(|v| v.to_string())
+                    .ok_or_else(|| eyre!("Null treatment value in {}", treatment.name()))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut names = values.clone();
+        match names.iter().all(|v| v.parse::<f64>().is_ok()) {
+            true => names.sort_by(|a, b| {
+                a.parse::<f64>()
+                    .unwrap()
+                    .total_cmp(&b.parse::<f64>().unwrap())
+            }),
+            false => names.sort(),
+        }
+        names.dedup();
+        if let Some(reference) = reference {
+            let idx = names.iter().position(|n| n == reference).ok_or_else(|| {
+                eyre!(
+                    "Reference arm {} not found in {}",
+                    reference,
+                    treatment.name()
+                )
+            })?;
+            let reference = names.remove(idx);
+            names.insert(0, reference);
+        }
+        if names.len() < 2 {
+            return Err(eyre!(
+                "{} needs at least two arms: {:?}",
+                treatment.name(),
+                names
+            ));
+        }
+        let labels = values
+            .iter()
+            .map(|v| names.iter().position(|n| n == v).unwrap())
+            .collect();
+
+        Ok(Arms {
+            field: treatment.name().to_string(),
+            names,
+            labels,
+        })
+    }
+    /// Number of arms
+    pub fn k(&self) -> usize {
+        self.names.len()
+    }
+    pub fn count(&self, arm: usize) -> usize {
+        self.labels.iter().filter(|l| **l == arm).count()
+    }
+}
+
+///
+/// Configuration for the generalized propensity score (GPS) of a multi-valued treatment: a
+/// multinomial logit of the arm on the predictors.  Appends one score column per arm and the
+/// generalized IPW weight:
+///
+/// * `{name}_{arm}`     P(T = arm | X)
+/// * `{name}_weight`    1 / P(T = t_i | X), times P(T = t_i) when stabilized
+///
+#[derive(Debug, Clone)]
+pub struct GpsCfg {
+    pub treatment: String,
+    pub predictors: PredictorsOwned,
+    pub terms: Vec<Term>,
+    pub categorical: Vec<String>,
+    pub reference: Option<String>,
+    pub stabilized: bool,
+    pub name: String,
+}
+
+impl GpsCfg {
+    pub fn new(treatment: &str, predictors: Predictors<'_>) -> Self {
+        GpsCfg {
+            treatment: treatment.to_string(),
+            predictors: predictors.into(),
+            terms: Vec::new(),
+            categorical: Vec::new(),
+            reference: None,
+            stabilized: true,
+            name: "gps".to_string(),
+        }
+    }
+    /// See [`crate::terms::parse_terms`]
+    pub fn terms(mut self, terms: Vec<Term>) -> Self {
+        self.terms = terms;
+        self
+    }
+    pub fn categorical(mut self, categorical: Vec<&str>) -> Self {
+        self.categorical = categorical.iter().map(|c| c.to_string()).collect();
+        self
+    }
+    /// The arm the others are compared to (default: the first arm)
+    pub fn reference(mut self, arm: &str) -> Self {
+        self.reference = Some(arm.to_string());
+        self
+    }
+    pub fn stabilized(mut self, stabilized: bool) -> Self {
+        self.stabilized = stabilized;
+        self
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+    pub fn score_name(&self, arm: &str) -> String {
+        format!("{}_{}", self.name, arm)
+    }
+    pub fn weight_name(&self) -> String {
+        self.name.to_owned() + "_weight"
+    }
+}
+
+///
+/// Scores are row-dominant with one probability per arm.
+///
+#[derive(Debug, Clone)]
+pub struct GpsFindings {
+    pub arms: Arms,
+    pub scores: Vec<f64>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+impl GpsFindings {
+    pub fn fit(x: &[f64], rows: usize, arms: Arms) -> Result<Self> {
+        let fit = MultinomialFit::fit(x, &arms.labels, arms.k(), rows, &IrlsCfg::default())?;
+        if !fit.converged {
+            event!(
+                Level::WARN,
+                "⚠️ multinomial logit did not converge in {} iterations",
+                fit.iterations
+            );
+        }
+        Ok(GpsFindings {
+            scores: fit.predict(x),
+            arms,
+            iterations: fit.iterations,
+            converged: fit.converged,
+        })
+    }
+    pub fn rows(&self) -> usize {
+        self.arms.labels.len()
+    }
+    pub fn score(&self, row: usize, arm: usize) -> f64 {
+        self.scores[row * self.arms.k() + arm]
+    }
+    /// Probability of the arm each subject received
+    pub fn received(&self) -> Vec<f64> {
+        (0..self.rows())
+            .map(|r| self.score(r, self.arms.labels[r]))
+            .collect()
+    }
+    ///
+    /// Generalized IPW weights (ATE): 1 / P(T = t_i | X_i), multiplied by the marginal share of
+    /// the arm when stabilized.
+    ///
+    pub fn weights(&self, stabilized: bool) -> Vec<f64> {
+        let n = self.rows() as f64;
+        let shares: Vec<f64> = (0..self.arms.k())
+            .map(|a| self.arms.count(a) as f64 / n)
+            .collect();
+        self.received()
+            .iter()
+            .zip(&self.arms.labels)
+            .map(|(p, arm)| match stabilized {
+                true => shares[*arm] / p.max(1e-10),
+                false => 1.0 / p.max(1e-10),
+            })
+            .collect()
+    }
+    /// Mean negative log-likelihood of the arm received
+    pub fn log_loss(&self) -> f64 {
+        let received = self.received();
+        received.iter().map(|p| -p.max(1e-15).ln()).sum::<f64>() / received.len() as f64
+    }
+    ///
+    /// The `{name}_{arm}` score columns and the `{name}_weight` column in row order.
+    ///
+    pub fn to_columns(&self, cfg: &GpsCfg) -> Vec<Series> {
+        let mut columns: Vec<Series> = self
+            .arms
+            .names
+            .iter()
+            .enumerate()
+            .map(|(a, arm)| {
+                let scores: Vec<f64> = (0..self.rows()).map(|r| self.score(r, a)).collect();
+                Series::new(&cfg.score_name(arm), scores)
+            })
+            .collect();
+        columns.push(Series::new(
+            &cfg.weight_name(),
+            self.weights(cfg.stabilized),
+        ));
+        columns
+    }
+    pub fn report(&self) -> String {
+        let arms: Vec<String> = (0..self.arms.k())
+            .map(|a| format!("{} (n={})", self.arms.names[a], self.arms.count(a)))
+            .collect();
+        format!(
+            "multinomial logit GPS on {}\n  arms: {}\n  log loss: {:.4}  iterations: {}\n",
+            self.arms.field,
+            arms.join(", "),
+            self.log_loss(),
+            self.iterations
+        )
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Pairwise balance
+///
+/// Standardized mean difference of a covariate between two arms, before and after weighting.
+/// Both use the unweighted pooled standard deviation, so the change reflects the means only.
+///
+#[derive(Debug, Clone)]
+pub struct PairBalance {
+    pub arms: (String, String),
+    pub covariate: String,
+    pub smd: f64,
+    pub weighted_smd: f64,
+}
+
+///
+/// Every pair of arms by every covariate (categorical covariates expand to dummies).
+///
+#[derive(Debug, Clone)]
+pub struct Balance {
+    pub pairs: Vec<PairBalance>,
+}
+
+impl Balance {
+    pub fn new(
+        df: &DataFrame,
+        arms: &Arms,
+        weights: &[f64],
+        covariates: &[String],
+    ) -> Result<Self> {
+        let mut pairs = Vec::new();
+        for covariate in covariates {
+            for (name, values) in numeric_columns(df, covariate)? {
+                for a in 0..arms.k() {
+                    for b in (a + 1)..arms.k() {
+                        let (smd, weighted_smd) = smd(&values, &arms.labels, weights, a, b);
+                        pairs.push(PairBalance {
+                            arms: (arms.names[a].clone(), arms.names[b].clone()),
+                            covariate: name.clone(),
+                            smd,
+                            weighted_smd,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(Balance { pairs })
+    }
+    /// Largest weighted |SMD| over every pair and covariate
+    pub fn max_abs_smd(&self) -> f64 {
+        self.pairs
+            .iter()
+            .map(|p| p.weighted_smd.abs())
+            .fold(0.0, f64::max)
+    }
+}
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Pairwise balance (|SMD| > 0.1 flagged)")?;
+        for p in &self.pairs {
+            writeln!(
+                f,
+                "  {} vs {}  {:<40} {:>8.4} {:>8.4} {}",
+                p.arms.0,
+                p.arms.1,
+                p.covariate,
+                p.smd,
+                p.weighted_smd,
+                if p.weighted_smd.abs() > 0.1 {
+                    "⚠️"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// (unweighted, weighted) SMD of arm a minus arm b
+fn smd(values: &[f64], labels: &[usize], weights: &[f64], a: usize, b: usize) -> (f64, f64) {
+    let arm = |k: usize| -> (Vec<f64>, Vec<f64>) {
+        values
+            .iter()
+            .zip(labels)
+            .zip(weights)
+            .filter(|((_, l), _)| **l == k)
+            .map(|((v, _), w)| (*v, *w))
+            .unzip()
+    };
+    let ((va, wa), (vb, wb)) = (arm(a), arm(b));
+    let weighted_mean = |v: &[f64], w: &[f64]| {
+        v.iter().zip(w).map(|(v, w)| v * w).sum::<f64>() / w.iter().sum::<f64>()
+    };
+    let sd = ((variance(&va) + variance(&vb)) / 2.0).sqrt();
+    let sd = if sd > 0.0 { sd } else { 1.0 };
+    (
+        (mean(&va) - mean(&vb)) / sd,
+        (weighted_mean(&va, &wa) - weighted_mean(&vb, &wb)) / sd,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_arms_order_and_reference() {
+        let s = Series::new("arm", &["2", "10", "1", "2"]);
+        let arms = Arms::new(&s, None).unwrap();
+        assert_eq!(vec!["1", "2", "10"], arms.names);
+        assert_eq!(vec![1, 2, 0, 1], arms.labels);
+
+        let s = Series::new("arm", &["B", "control", "A"]);
+        let arms = Arms::new(&s, Some("control")).unwrap();
+        assert_eq!(vec!["control", "A", "B"], arms.names);
+    }
+    #[test]
+    fn test_weighting_improves_balance() {
+        // arm depends on x; three arms
+        let rows = 300;
+        let mut x = Vec::new();
+        let mut values = Vec::new();
+        let mut labels = Vec::new();
+        for i in 0..rows {
+            let v = (i % 100) as f64 / 50.0 - 1.0;
+            let arm = match (i * 7919) % 10 {
+                k if (k as f64) < 4.0 + 3.0 * v => 0,
+                k if (k as f64) < 7.0 => 1,
+                _ => 2,
+            };
+            x.extend_from_slice(&[v, 1.0]);
+            values.push(v);
+            labels.push(arm);
+        }
+        let arms = Arms {
+            field: "arm".to_string(),
+            names: vec!["0".into(), "1".into(), "2".into()],
+            labels,
+        };
+        let findings = GpsFindings::fit(&x, rows, arms).unwrap();
+        for r in 0..rows {
+            let total: f64 = (0..3).map(|a| findings.score(r, a)).sum();
+            assert!((total - 1.0).abs() < 1e-9);
+        }
+        let weights = findings.weights(true);
+        let (raw, weighted) = smd(&values, &findings.arms.labels, &weights, 0, 2);
+        assert!(weighted.abs() < raw.abs());
+    }
+}
@@ -0,0 +1,330 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use serde::Serialize;
+use std::fmt;
+
+use crate::columns::series_to_f64;
+use crate::design::resolve_role;
+use crate::header::Header;
+
+///
+/// Treated and control subjects entering and leaving one stage of the pipeline.
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerEntry {
+    pub stage: String,
+    pub treated_in: usize,
+    pub controls_in: usize,
+    pub treated_out: usize,
+    pub controls_out: usize,
+}
+impl LedgerEntry {
+    pub fn excluded(&self) -> usize {
+        self.treated_in + self.controls_in - self.treated_out - self.controls_out
+    }
+}
+
+///
+/// Where the subjects went between the raw rows and the analyzed cohort.  The ledger follows
+/// the cohort as a row mask over the matrix: each stage keeps a subset of the rows still in
+/// the cohort and appends its counts.
+///
+/// Stages mark rows rather than drop them; when the matrix is filtered to the cohort (see
+/// [`AttritionLedger::cohort_of`]) the next stage realigns to it.  Subjects with a null
+/// treatment are in neither arm.
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct AttritionLedger {
+    pub treatment: String,
+    pub stages: Vec<LedgerEntry>,
+    #[serde(skip)]
+    cohort: Vec<bool>,
+}
+
+impl AttritionLedger {
+    ///
+    /// The `load` stage: every row of the matrix.  `treatment` is a column name or a fuzzy
+    /// search term (see [`crate::design::StudyDesignCfg`]).
+    ///
+    pub fn load(df: &DataFrame, treatment: &str) -> Result<Self> {
+        let treatment = resolve_role(&Header::from(df), "treatment", treatment)?;
+        let mut ledger = AttritionLedger {
+            treatment,
+            stages: Vec::new(),
+            cohort: vec![true; df.height()],
+        };
+        ledger.record("load", df, &vec![true; df.height()])?;
+        Ok(ledger)
+    }
+    /// Rows of the matrix still in the cohort
+    pub fn cohort(&self) -> &[bool] {
+        &self.cohort
+    }
+    /// The matrix filtered to the cohort
+    pub fn cohort_of(&self, df: &DataFrame) -> Result<DataFrame> {
+        let cohort = self.aligned(df)?;
+        Ok(df.filter(&BooleanChunked::from_slice("cohort", &cohort))?)
+    }
+    fn aligned(&self, df: &DataFrame) -> Result<Vec<bool>> {
+        let remaining = self.cohort.iter().filter(|c| **c).count();
+        match df.height() {
+            h if h == self.cohort.len() => Ok(self.cohort.clone()),
+            h if h == remaining => Ok(vec![true; h]),
+            h => Err(eyre!(
+                "The matrix has {} rows; the ledger follows {} with {} in the cohort",
+                h,
+                self.cohort.len(),
+                remaining
+            )),
+        }
+    }
+    fn arms(&self, df: &DataFrame, rows: &[bool]) -> Result<(usize, usize)> {
+        let t = df.column(&self.treatment)?.cast(&DataType::Float64)?;
+        let (mut treated, mut controls) = (0, 0);
+        for (t, row) in t.f64()?.into_iter().zip(rows) {
+            match (row, t) {
+                (true, Some(t)) if t > 0.5 => treated += 1,
+                (true, Some(_)) => controls += 1,
+                _ => {}
+            }
+        }
+        Ok((treated, controls))
+    }
+    ///
+    /// Keep the rows of the cohort for which `kept` is true.
+    ///
+    pub fn record(&mut self, stage: &str, df: &DataFrame, kept: &[bool]) -> Result<&LedgerEntry> {
+        if kept.len() != df.height() {
+            return Err(eyre!(
+                "The {} stage kept {} rows of {}",
+                stage,
+                kept.len(),
+                df.height()
+            ));
+        }
+        let cohort = self.aligned(df)?;
+        let out: Vec<bool> = cohort.iter().zip(kept).map(|(c, k)| *c && *k).collect();
+        let (treated_in, controls_in) = self.arms(df, &cohort)?;
+        let (treated_out, controls_out) = self.arms(df, &out)?;
+        self.cohort = out;
+        self.stages.push(LedgerEntry {
+            stage: stage.to_string(),
+            treated_in,
+            controls_in,
+            treated_out,
+            controls_out,
+        });
+        Ok(&self.stages[self.stages.len() - 1])
+    }
+    ///
+    /// Keep the rows where the boolean `column` is true, e.g. `include` after the include tag
+    /// or the eligibility rules.
+    ///
+    pub fn flag(&mut self, stage: &str, df: &DataFrame, column: &str) -> Result<&LedgerEntry> {
+        let kept: Vec<bool> = df
+            .column(column)?
+            .bool()?
+            .into_iter()
+            .map(|v| v == Some(true))
+            .collect();
+        self.record(stage, df, &kept)
+    }
+    ///
+    /// The `null handling` stage: keep the rows without a null in any of `fields`.
+    ///
+    pub fn complete_cases(&mut self, df: &DataFrame, fields: &[&str]) -> Result<&LedgerEntry> {
+        let mut kept = vec![true; df.height()];
+        for field in fields {
+            for (k, null) in kept.iter_mut().zip(&df.column(field)?.is_null()) {
+                *k = *k && null != Some(true);
+            }
+        }
+        self.record("null handling", df, &kept)
+    }
+    ///
+    /// The `common support` stage: keep the scores inside the overlap of the treated and control
+    /// score ranges, [max of the minima, min of the maxima].
+    ///
+    pub fn common_support(&mut self, df: &DataFrame, score: &str) -> Result<&LedgerEntry> {
+        let (e, t) = self.scores(df, score)?;
+        let range = |treated: bool| {
+            e.iter()
+                .zip(&t)
+                .filter(|(_, t)| **t == Some(treated))
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), (e, _)| {
+                    (lo.min(*e), hi.max(*e))
+                })
+        };
+        let ((lo1, hi1), (lo0, hi0)) = (range(true), range(false));
+        let (lo, hi) = (lo1.max(lo0), hi1.min(hi0));
+        let kept: Vec<bool> = e.iter().map(|e| *e >= lo && *e <= hi).collect();
+        self.record("common support", df, &kept)
+    }
+    ///
+    /// The `caliper` stage: keep the subjects with a subject of the other arm within `caliper`
+    /// of their score.
+    ///
+    pub fn caliper(&mut self, df: &DataFrame, score: &str, caliper: f64) -> Result<&LedgerEntry> {
+        let (e, t) = self.scores(df, score)?;
+        let sorted = |treated: bool| {
+            let mut arm: Vec<f64> = e
+                .iter()
+                .zip(&t)
+                .filter(|(_, t)| **t == Some(treated))
+                .map(|(e, _)| *e)
+                .collect();
+            arm.sort_by(|a, b| a.total_cmp(b));
+            arm
+        };
+        let (treated, controls) = (sorted(true), sorted(false));
+        let within = |arm: &[f64], e: f64| {
+            let i = arm.partition_point(|x| *x < e);
+            (i < arm.len() && arm[i] - e <= caliper) || (i > 0 && e - arm[i - 1] <= caliper)
+        };
+        let kept: Vec<bool> = e
+            .iter()
+            .zip(&t)
+            .map(|(e, t)| match t {
+                Some(true) => within(&controls, *e),
+                Some(false) => within(&treated, *e),
+                None => false,
+            })
+            .collect();
+        self.record("caliper", df, &kept)
+    }
+    ///
+    /// The `matching` stage: keep the subjects with a positive matching weight, e.g.
+    /// `match_weight` (see [`crate::matching::MatchCfg::weight_name`]).
+    ///
+    pub fn matched(&mut self, df: &DataFrame, weight: &str) -> Result<&LedgerEntry> {
+        let kept: Vec<bool> = df
+            .column(weight)?
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .map(|w| w.unwrap_or(0.0) > 0.0)
+            .collect();
+        self.record("matching", df, &kept)
+    }
+    /// Scores and arms of the cohort rows (arm `None` outside the cohort)
+    fn scores(&self, df: &DataFrame, score: &str) -> Result<(Vec<f64>, Vec<Option<bool>>)> {
+        let cohort = self.aligned(df)?;
+        let e = series_to_f64(df.column(score)?)?;
+        let t = df
+            .column(&self.treatment)?
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .zip(&cohort)
+            .map(|(t, c)| match c {
+                true => t.map(|t| t > 0.5),
+                false => None,
+            })
+            .collect();
+        Ok((e, t))
+    }
+    ///
+    /// `stage`, `treated_in`, `controls_in`, `treated_out`, `controls_out`, `excluded`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let column = |f: fn(&LedgerEntry) -> usize| -> Vec<u32> {
+            self.stages.iter().map(|s| f(s) as u32).collect()
+        };
+        Ok(DataFrame::new(vec![
+            Series::new(
+                "stage",
+                self.stages
+                    .iter()
+                    .map(|s| s.stage.as_str())
+                    .collect::<Vec<_>>(),
+            ),
+            Series::new("treated_in", column(|s| s.treated_in)),
+            Series::new("controls_in", column(|s| s.controls_in)),
+            Series::new("treated_out", column(|s| s.treated_out)),
+            Series::new("controls_out", column(|s| s.controls_out)),
+            Series::new("excluded", column(|s| s.excluded())),
+        ])?)
+    }
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+impl fmt::Display for AttritionLedger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Attrition of {} (treated / controls)", self.treatment)?;
+        writeln!(
+            f,
+            "  {:<16} {:>17} {:>17} {:>9}",
+            "stage", "in", "out", "excluded"
+        )?;
+        for (i, s) in self.stages.iter().enumerate() {
+            write!(
+                f,
+                "  {:<16} {:>8} / {:<6} {:>8} / {:<6} {:>9}",
+                s.stage,
+                s.treated_in,
+                s.controls_in,
+                s.treated_out,
+                s.controls_out,
+                s.excluded()
+            )?;
+            if i + 1 < self.stages.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attrition_ledger() {
+        let mut df = df!(
+            "subject_idx" => [Some(0u32), Some(1), None, Some(3), Some(4), Some(5), Some(6), Some(7)],
+            "treated" => [1.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 0.0],
+            "q_state" => [Some("NY"), Some("CA"), Some("TX"), None, Some("CA"), Some("NY"), Some("TX"), Some("CA")],
+            "score" => [0.9, 0.1, 0.5, 0.4, 0.6, 0.55, 0.35, 0.3]
+        )
+        .unwrap();
+        let include = df.column("subject_idx").unwrap().is_not_null();
+        df.with_column(Series::new("include", include)).unwrap();
+
+        let mut ledger = AttritionLedger::load(&df, "treated").unwrap();
+        ledger.flag("include tag", &df, "include").unwrap();
+        ledger.complete_cases(&df, &["q_state"]).unwrap();
+        let support = ledger.common_support(&df, "score").unwrap();
+        // treated [0.35, 0.9], controls [0.1, 0.55] overlap on [0.35, 0.55]
+        assert_eq!(
+            (3, 3, 1, 1),
+            (
+                support.treated_in,
+                support.controls_in,
+                support.treated_out,
+                support.controls_out
+            )
+        );
+
+        // the filtered matrix realigns to the cohort
+        let df = ledger.cohort_of(&df).unwrap();
+        assert_eq!(2, df.height());
+        let caliper = ledger.caliper(&df, "score", 0.25).unwrap();
+        assert_eq!(0, caliper.excluded());
+
+        assert_eq!(5, ledger.to_dataframe().unwrap().height());
+        assert_eq!(
+            vec![8, 7, 6, 2, 2],
+            ledger
+                .stages
+                .iter()
+                .map(|s| s.treated_out + s.controls_out)
+                .collect::<Vec<_>>()
+        );
+        assert!(ledger
+            .to_json()
+            .unwrap()
+            .contains("\"stage\": \"common support\""));
+    }
+}
@@ -1,15 +1,71 @@
+pub(crate) mod aipw;
+pub(crate) mod bootstrap;
+pub(crate) mod cem;
+pub(crate) mod columns;
 pub(crate) mod config;
+pub(crate) mod cross_fit;
+pub(crate) mod derived;
+pub(crate) mod design;
+pub(crate) mod dose_response;
+pub(crate) mod effect;
+pub(crate) mod eligibility;
+pub(crate) mod event_study;
+pub(crate) mod field_name;
+pub(crate) mod flow;
+pub(crate) mod forest;
+pub(crate) mod formula;
+pub(crate) mod glm;
+pub(crate) mod gps;
 pub(crate) mod header;
+pub(crate) mod ledger;
+pub(crate) mod matching;
 pub(crate) mod matrix;
+pub(crate) mod panel;
+pub(crate) mod placebo;
+pub(crate) mod power;
 pub(crate) mod propensity;
+pub(crate) mod propensity_model;
+pub(crate) mod segments;
+pub(crate) mod sensitivity;
+pub(crate) mod stats;
+pub(crate) mod synth;
+pub(crate) mod terms;
 pub(crate) mod tnc_analysis_cfg;
+pub(crate) mod trend;
+pub(crate) mod uplift;
 
 pub mod prelude {
+    pub use crate::aipw::{AipwCfg, AipwFindings, OutcomeModel};
+    pub use crate::bootstrap::{BootstrapCfg, BootstrapFindings, Interval, Resample};
+    pub use crate::cem::{Cem, CemCfg, Coarsening};
     pub use crate::config::FieldNamesCfg;
+    pub use crate::cross_fit::{CrossFitCfg, CrossFitFindings};
+    pub use crate::derived::Derivation;
+    pub use crate::design::{StudyDesign, StudyDesignCfg};
+    pub use crate::dose_response::{DosePoint, DoseResponse, DoseResponseCfg, IntensityModel};
+    pub use crate::effect::{AdjustedEffect, Effect, EffectCfg};
+    pub use crate::eligibility::{
+        Attrition, AttritionStep, EligibilityCfg, EligibilityRule, Rule, EXCLUSION_REASON, INCLUDE,
+    };
+    pub use crate::event_study::{EventCoefficient, EventStudy, EventStudyCfg};
+    pub use crate::field_name::{pre_period, pre_periods, single_periods, ParsedField, TimeWindow};
+    pub use crate::formula::ModelDef;
+    pub use crate::gps::{Arms, Balance, GpsCfg, GpsFindings, PairBalance};
+    pub use crate::ledger::{AttritionLedger, LedgerEntry};
+    pub use crate::matching::{Distance, MatchCfg, MatchMode, Matching};
     pub use crate::matrix::Matrix;
-    pub use crate::propensity::PropensityCfg;
+    pub use crate::placebo::{PlaceboCfg, PlaceboKind, PlaceboReport, PlaceboTest};
+    pub use crate::power::{PowerCfg, PowerFindings};
+    pub use crate::propensity::{PropensityCfg, PropensityFindings};
+    pub use crate::propensity_model::{FittedPropensity, Metrics, ModelFamily, PropensityModel};
     pub use crate::read_config;
+    pub use crate::segments::{Correction, SegmentCfg, SegmentEffect, SegmentReport, Segmentation};
+    pub use crate::sensitivity::{EValue, RosenbaumBound, RosenbaumBounds};
+    pub use crate::synth::{Placebo, SynthCfg, SynthPoint, SyntheticControl};
+    pub use crate::terms::{parse_terms, Term};
     pub use crate::tnc_analysis_cfg::Config;
+    pub use crate::trend::{TrendCfg, TrendFeature};
+    pub use crate::uplift::{UpliftCfg, UpliftFindings, UpliftMethod, UpliftPoint};
 }
 
 use nom::branch::alt;
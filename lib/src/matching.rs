@@ -0,0 +1,693 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::columns::series_to_f64;
+use crate::flow::MinCostFlow;
+use crate::stats::{cholesky, forward_substitute};
+use crate::terms::numeric_columns;
+
+///
+/// How to measure the distance between a treated and a control subject.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distance {
+    /// |score_t - score_c| on the propensity score column
+    Propensity,
+    /// Mahalanobis distance on the covariates (categorical fields expand to dummies)
+    Mahalanobis(Vec<String>),
+    /// Euclidean distance over the period fields, e.g. the pre-period `time::` columns of a
+    /// measure (see [`crate::trend::TrendCfg::fields`])
+    TimeSeries(Vec<String>),
+}
+
+///
+/// * `Greedy`   nearest neighbour in order of the score, 1:ratio
+/// * `Optimal`  1:ratio minimizing the total distance over all pairs (min-cost flow)
+/// * `Full`     variable ratio, minimizing the total distance (min-cost edge cover): every
+///   subject with an eligible partner ends up in a set of one treated with many controls or
+///   one control with many treated
+///
+/// The optimal modes solve one flow problem per exact-match stratum; without exact constraints
+/// or a caliper every treated-control pair is an edge, so keep the cohorts small.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MatchMode {
+    #[default]
+    Greedy,
+    Optimal,
+    Full,
+}
+
+///
+/// Configuration for matching treated to control subjects.  The matches are appended to the
+/// matrix as three columns that every matching mode shares:
+///
+/// * `{name}_id`        matched set id, shared by the treated subject and its controls (null when unmatched)
+/// * `{name}_distance`  mean distance to the other side of the set
+/// * `{name}_weight`    ATT weight: 1 for treated, treated/controls in the set for controls, 0 when unmatched
+///
+#[derive(Debug, Clone)]
+pub struct MatchCfg {
+    pub treatment: String,
+    pub distance: Distance,
+    /// propensity score column: the distance for [`Distance::Propensity`], the caliper otherwise
+    pub score: Option<String>,
+    /// max |score_t - score_c|; pairs outside the caliper are never matched
+    pub caliper: Option<f64>,
+    /// fields that must be equal within a matched set, e.g. `q_state`
+    pub exact: Vec<String>,
+    /// controls per treated subject (ignored by [`MatchMode::Full`])
+    pub ratio: usize,
+    pub mode: MatchMode,
+    pub name: String,
+}
+
+impl MatchCfg {
+    pub fn propensity(treatment: &str, score: &str) -> Self {
+        MatchCfg {
+            treatment: treatment.to_string(),
+            distance: Distance::Propensity,
+            score: Some(score.to_string()),
+            caliper: None,
+            exact: Vec::new(),
+            ratio: 1,
+            mode: MatchMode::default(),
+            name: "match".to_string(),
+        }
+    }
+    pub fn mahalanobis(treatment: &str, covariates: Vec<&str>) -> Self {
+        MatchCfg {
+            distance: Distance::Mahalanobis(covariates.iter().map(|c| c.to_string()).collect()),
+            score: None,
+            ..MatchCfg::propensity(treatment, "")
+        }
+    }
+    pub fn time_series(treatment: &str, periods: Vec<&str>) -> Self {
+        MatchCfg {
+            distance: Distance::TimeSeries(periods.iter().map(|c| c.to_string()).collect()),
+            score: None,
+            ..MatchCfg::propensity(treatment, "")
+        }
+    }
+    /// Propensity caliper applied before computing the distance
+    pub fn caliper(mut self, score: &str, caliper: f64) -> Self {
+        self.score = Some(score.to_string());
+        self.caliper = Some(caliper);
+        self
+    }
+    pub fn exact(mut self, fields: Vec<&str>) -> Self {
+        self.exact = fields.iter().map(|f| f.to_string()).collect();
+        self
+    }
+    pub fn ratio(mut self, ratio: usize) -> Self {
+        self.ratio = ratio.max(1);
+        self
+    }
+    pub fn mode(mut self, mode: MatchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+    pub fn id_name(&self) -> String {
+        self.name.to_owned() + "_id"
+    }
+    pub fn distance_name(&self) -> String {
+        self.name.to_owned() + "_distance"
+    }
+    pub fn weight_name(&self) -> String {
+        self.name.to_owned() + "_weight"
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// The matching problem
+///
+/// Row-level inputs to the matching algorithms.  Each row is a point in `dims` dimensions
+/// (the score, the whitened covariates or the period values) so that the distance is Euclidean.
+///
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub treated: Vec<usize>,
+    pub controls: Vec<usize>,
+    pub points: Vec<f64>,
+    pub dims: usize,
+    pub score: Option<Vec<f64>>,
+    pub caliper: Option<f64>,
+    pub strata: Option<Vec<String>>,
+}
+
+impl Problem {
+    pub fn new(df: &DataFrame, cfg: &MatchCfg) -> Result<Self> {
+        let treatment = series_to_f64(df.column(&cfg.treatment)?)?;
+        let (treated, controls): (Vec<usize>, Vec<usize>) =
+            (0..treatment.len()).partition(|i| treatment[*i] > 0.5);
+
+        let score = match &cfg.score {
+            Some(score) => Some(series_to_f64(df.column(score)?)?),
+            None => None,
+        };
+        let (points, dims) = match &cfg.distance {
+            Distance::Propensity => (
+                score
+                    .clone()
+                    .ok_or_else(|| eyre!("Propensity matching requires a score column"))?,
+                1,
+            ),
+            Distance::Mahalanobis(covariates) => whitened(df, covariates)?,
+            Distance::TimeSeries(periods) => series_points(df, periods)?,
+        };
+        let strata = match cfg.exact.is_empty() {
+            true => None,
+            false => Some(strata_keys(df, &cfg.exact)?),
+        };
+
+        Ok(Problem {
+            treated,
+            controls,
+            points,
+            dims,
+            score,
+            caliper: cfg.caliper,
+            strata,
+        })
+    }
+    ///
+    /// Distance between two rows; `None` when the exact or caliper constraints rule the pair out.
+    ///
+    pub fn distance(&self, t: usize, c: usize) -> Option<f64> {
+        if let Some(strata) = &self.strata {
+            if strata[t] != strata[c] {
+                return None;
+            }
+        }
+        if let (Some(score), Some(caliper)) = (&self.score, self.caliper) {
+            if (score[t] - score[c]).abs() > caliper {
+                return None;
+            }
+        }
+        let (a, b) = (
+            &self.points[t * self.dims..(t + 1) * self.dims],
+            &self.points[c * self.dims..(c + 1) * self.dims],
+        );
+        Some(
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f64>()
+                .sqrt(),
+        )
+    }
+    pub fn rows(&self) -> usize {
+        self.points.len() / self.dims
+    }
+    /// (treated, controls) per exact-match stratum
+    fn groups(&self) -> Vec<(Vec<usize>, Vec<usize>)> {
+        match &self.strata {
+            None => vec![(self.treated.clone(), self.controls.clone())],
+            Some(strata) => {
+                let mut groups: BTreeMap<&str, (Vec<usize>, Vec<usize>)> = BTreeMap::new();
+                for t in &self.treated {
+                    groups.entry(strata[*t].as_str()).or_default().0.push(*t);
+                }
+                for c in &self.controls {
+                    groups.entry(strata[*c].as_str()).or_default().1.push(*c);
+                }
+                groups.into_values().collect()
+            }
+        }
+    }
+    /// eligible (treated idx, control idx, distance) within a group
+    fn edges(&self, treated: &[usize], controls: &[usize]) -> Vec<(usize, usize, f64)> {
+        let mut edges = Vec::new();
+        for (i, t) in treated.iter().enumerate() {
+            for (j, c) in controls.iter().enumerate() {
+                if let Some(d) = self.distance(*t, *c) {
+                    edges.push((i, j, d));
+                }
+            }
+        }
+        edges
+    }
+}
+
+/// The pre-period trajectory of each row, one point per period
+fn series_points(df: &DataFrame, periods: &[String]) -> Result<(Vec<f64>, usize)> {
+    if periods.is_empty() {
+        return Err(eyre!("Time series matching requires at least one period"));
+    }
+    let columns = periods
+        .iter()
+        .map(|p| series_to_f64(df.column(p)?))
+        .collect::<Result<Vec<_>>>()?;
+    let points = (0..df.height())
+        .flat_map(|r| columns.iter().map(move |c| c[r]))
+        .collect();
+    Ok((points, periods.len()))
+}
+
+///
+/// Centre and whiten the covariates with the Cholesky factor of their covariance so that the
+/// Mahalanobis distance is the Euclidean distance between the transformed rows.
+///
+fn whitened(df: &DataFrame, covariates: &[String]) -> Result<(Vec<f64>, usize)> {
+    let mut columns: Vec<Vec<f64>> = Vec::new();
+    for covariate in covariates {
+        columns.extend(numeric_columns(df, covariate)?.into_iter().map(|(_, v)| v));
+    }
+    let (rows, dims) = (df.height(), columns.len());
+    if dims == 0 {
+        return Err(eyre!(
+            "Mahalanobis matching requires at least one covariate"
+        ));
+    }
+    let means: Vec<f64> = columns
+        .iter()
+        .map(|c| c.iter().sum::<f64>() / rows as f64)
+        .collect();
+    let mut cov = vec![0.0; dims * dims];
+    for i in 0..dims {
+        for j in 0..=i {
+            let c: f64 = (0..rows)
+                .map(|r| (columns[i][r] - means[i]) * (columns[j][r] - means[j]))
+                .sum::<f64>()
+                / (rows as f64 - 1.0);
+            cov[i * dims + j] = c;
+            cov[j * dims + i] = c;
+        }
+    }
+    // a full set of dummies is singular; a small ridge keeps the factorization defined
+    let ridge = 1e-6 * (0..dims).map(|i| cov[i * dims + i]).sum::<f64>().max(1e-8);
+    (0..dims).for_each(|i| cov[i * dims + i] += ridge);
+    let l = cholesky(&cov, dims)?;
+
+    let points: Vec<f64> = (0..rows)
+        .flat_map(|r| {
+            let centred: Vec<f64> = columns.iter().zip(&means).map(|(c, m)| c[r] - m).collect();
+            forward_substitute(&l, &centred, dims)
+        })
+        .collect();
+    Ok((points, dims))
+}
+
+///
+/// One key per row built from the values of the fields (nulls are their own value).
+///
+pub(crate) fn strata_keys(df: &DataFrame, fields: &[String]) -> Result<Vec<String>> {
+    let mut keys = vec![String::new(); df.height()];
+    for field in fields {
+        let values = df.column(field)?.cast(&DataType::Utf8)?;
+        for (key, value) in keys.iter_mut().zip(values.utf8()?) {
+            key.push_str(value.unwrap_or("∅"));
+            key.push('|');
+        }
+    }
+    Ok(keys)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Results
+///
+/// A treated subject (or several, with full matching) and its controls.
+///
+#[derive(Debug, Clone)]
+pub struct MatchedSet {
+    pub treated: Vec<usize>,
+    pub controls: Vec<usize>,
+    pub distance: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Matching {
+    pub method: &'static str,
+    pub sets: Vec<MatchedSet>,
+    pub rows: usize,
+    pub treated: usize,
+    pub controls: usize,
+}
+
+impl Matching {
+    pub fn matched_treated(&self) -> usize {
+        self.sets.iter().map(|s| s.treated.len()).sum()
+    }
+    pub fn matched_controls(&self) -> usize {
+        self.sets.iter().map(|s| s.controls.len()).sum()
+    }
+    /// Sum over the treated-control pairs in every set
+    pub fn total_distance(&self) -> f64 {
+        self.sets.iter().map(|s| s.distance).sum()
+    }
+    /// Mean over the treated-control pairs; `None` when nothing was matched
+    pub fn mean_distance(&self) -> Option<f64> {
+        let pairs: usize = self
+            .sets
+            .iter()
+            .map(|s| s.treated.len() * s.controls.len())
+            .sum();
+        match pairs {
+            0 => None,
+            pairs => Some(self.total_distance() / pairs as f64),
+        }
+    }
+    ///
+    /// The `{name}_id`, `{name}_distance` and `{name}_weight` columns in row order.
+    ///
+    pub fn to_columns(&self, cfg: &MatchCfg) -> Vec<Series> {
+        let mut ids: Vec<Option<u32>> = vec![None; self.rows];
+        let mut distances: Vec<Option<f64>> = vec![None; self.rows];
+        let mut weights: Vec<f64> = vec![0.0; self.rows];
+        for (id, set) in self.sets.iter().enumerate() {
+            let (n_t, n_c) = (set.treated.len() as f64, set.controls.len() as f64);
+            for t in &set.treated {
+                ids[*t] = Some(id as u32);
+                distances[*t] = Some(set.distance / n_t);
+                weights[*t] = 1.0;
+            }
+            for c in &set.controls {
+                ids[*c] = Some(id as u32);
+                distances[*c] = Some(set.distance / n_c);
+                weights[*c] = n_t / n_c;
+            }
+        }
+        vec![
+            Series::new(&cfg.id_name(), ids),
+            Series::new(&cfg.distance_name(), distances),
+            Series::new(&cfg.weight_name(), weights),
+        ]
+    }
+}
+impl fmt::Display for Matching {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Matching ({})", self.method)?;
+        writeln!(
+            f,
+            "  treated matched: {} of {}",
+            self.matched_treated(),
+            self.treated
+        )?;
+        writeln!(
+            f,
+            "  controls used:   {} of {}",
+            self.matched_controls(),
+            self.controls
+        )?;
+        writeln!(f, "  matched sets:    {}", self.sets.len())?;
+        writeln!(f, "  total distance:  {:.4}", self.total_distance())?;
+        match self.mean_distance() {
+            Some(d) => write!(f, "  mean distance:   {:.4}", d),
+            None => write!(f, "  mean distance:   -"),
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Algorithms
+///
+/// Greedy nearest neighbour without replacement.  Treated subjects are processed from the
+/// highest score down (hardest to match first) or in row order without a score.
+///
+pub fn greedy(problem: &Problem, ratio: usize) -> Matching {
+    let mut order = problem.treated.clone();
+    if let Some(score) = &problem.score {
+        order.sort_by(|a, b| score[*b].total_cmp(&score[*a]));
+    }
+    let mut available = vec![true; problem.rows()];
+    let mut sets = Vec::new();
+
+    for t in order {
+        let mut candidates: Vec<(usize, f64)> = problem
+            .controls
+            .iter()
+            .filter(|c| available[**c])
+            .filter_map(|c| problem.distance(t, *c).map(|d| (*c, d)))
+            .collect();
+        if candidates.is_empty() {
+            continue;
+        }
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+        candidates.truncate(ratio);
+        candidates.iter().for_each(|(c, _)| available[*c] = false);
+        sets.push(MatchedSet {
+            treated: vec![t],
+            controls: candidates.iter().map(|(c, _)| *c).collect(),
+            distance: candidates.iter().map(|(_, d)| d).sum(),
+        });
+    }
+    event!(Level::DEBUG, "✅ greedy matching: {} sets", sets.len());
+
+    Matching {
+        method: "greedy",
+        sets,
+        rows: problem.rows(),
+        treated: problem.treated.len(),
+        controls: problem.controls.len(),
+    }
+}
+
+///
+/// 1:ratio matching that minimizes the total distance.  Maximizes the number of pairs first
+/// (calipers and exact constraints may leave some treated unmatched), then the cost.
+///
+pub fn optimal(problem: &Problem, ratio: usize) -> Matching {
+    let mut sets = Vec::new();
+    for (treated, controls) in problem.groups() {
+        let (nt, nc) = (treated.len(), controls.len());
+        // 0: source, 1: sink, 2..: treated, then controls
+        let mut mcf = MinCostFlow::new(2 + nt + nc);
+        (0..nt).for_each(|i| {
+            mcf.add_edge(0, 2 + i, ratio as i64, 0.0);
+        });
+        (0..nc).for_each(|j| {
+            mcf.add_edge(2 + nt + j, 1, 1, 0.0);
+        });
+        let edges: Vec<(usize, usize, f64, usize)> = problem
+            .edges(&treated, &controls)
+            .into_iter()
+            .map(|(i, j, d)| (i, j, d, mcf.add_edge(2 + i, 2 + nt + j, 1, d)))
+            .collect();
+        mcf.run(0, 1, false);
+
+        let mut by_treated: Vec<MatchedSet> = treated
+            .iter()
+            .map(|t| MatchedSet {
+                treated: vec![*t],
+                controls: Vec::new(),
+                distance: 0.0,
+            })
+            .collect();
+        for (i, j, d, id) in edges {
+            if mcf.flow(id) > 0 {
+                by_treated[i].controls.push(controls[j]);
+                by_treated[i].distance += d;
+            }
+        }
+        sets.extend(by_treated.into_iter().filter(|s| !s.controls.is_empty()));
+    }
+    event!(Level::DEBUG, "✅ optimal matching: {} sets", sets.len());
+
+    Matching {
+        method: "optimal",
+        sets,
+        rows: problem.rows(),
+        treated: problem.treated.len(),
+        controls: problem.controls.len(),
+    }
+}
+
+///
+/// Full matching as a min-cost edge cover of the eligible treated-control pairs.  The optimal
+/// cover is a forest of stars, i.e. matched sets with one treated and many controls or one
+/// control and many treated.
+///
+/// cover = Σ min-edge(v) + min-cost matching on w(t, c) - min(t) - min(c)
+///
+pub fn full(problem: &Problem) -> Matching {
+    let mut sets = Vec::new();
+    for (treated, controls) in problem.groups() {
+        let (nt, nc) = (treated.len(), controls.len());
+        let edges = problem.edges(&treated, &controls);
+
+        // cheapest edge at each vertex (treated then controls)
+        let mut cheapest: Vec<Option<(usize, f64)>> = vec![None; nt + nc];
+        for (k, (i, j, d)) in edges.iter().enumerate() {
+            for v in [*i, nt + *j] {
+                match cheapest[v] {
+                    Some((_, best)) if best <= *d => {}
+                    _ => cheapest[v] = Some((k, *d)),
+                }
+            }
+        }
+
+        let mut mcf = MinCostFlow::new(2 + nt + nc);
+        (0..nt).for_each(|i| {
+            mcf.add_edge(0, 2 + i, 1, 0.0);
+        });
+        (0..nc).for_each(|j| {
+            mcf.add_edge(2 + nt + j, 1, 1, 0.0);
+        });
+        let mut savings: Vec<(usize, usize)> = Vec::new();
+        for (k, (i, j, d)) in edges.iter().enumerate() {
+            let (mi, mj) = (cheapest[*i].unwrap().1, cheapest[nt + *j].unwrap().1);
+            let reduced = d - mi - mj;
+            if reduced < 0.0 {
+                savings.push((k, mcf.add_edge(2 + i, 2 + nt + j, 1, reduced)));
+            }
+        }
+        mcf.run(0, 1, true);
+
+        let mut cover: Vec<usize> = savings
+            .iter()
+            .filter(|(_, id)| mcf.flow(*id) > 0)
+            .map(|(k, _)| *k)
+            .collect();
+        let mut covered = vec![false; nt + nc];
+        for k in &cover {
+            covered[edges[*k].0] = true;
+            covered[nt + edges[*k].1] = true;
+        }
+        for v in 0..(nt + nc) {
+            if let (false, Some((k, _))) = (covered[v], cheapest[v]) {
+                if !cover.contains(&k) {
+                    cover.push(k);
+                }
+            }
+        }
+
+        // stars: group the cover edges by their connected component
+        let mut parent: Vec<usize> = (0..(nt + nc)).collect();
+        fn root(parent: &mut [usize], v: usize) -> usize {
+            let mut r = v;
+            while parent[r] != r {
+                r = parent[r];
+            }
+            parent[v] = r;
+            r
+        }
+        for k in &cover {
+            let (a, b) = (
+                root(&mut parent, edges[*k].0),
+                root(&mut parent, nt + edges[*k].1),
+            );
+            parent[a] = b;
+        }
+        let mut stars: BTreeMap<usize, MatchedSet> = BTreeMap::new();
+        for k in &cover {
+            let (i, j, d) = edges[*k];
+            let set = stars.entry(root(&mut parent, i)).or_insert(MatchedSet {
+                treated: Vec::new(),
+                controls: Vec::new(),
+                distance: 0.0,
+            });
+            if !set.treated.contains(&treated[i]) {
+                set.treated.push(treated[i]);
+            }
+            if !set.controls.contains(&controls[j]) {
+                set.controls.push(controls[j]);
+            }
+            set.distance += d;
+        }
+        sets.extend(stars.into_values());
+    }
+    event!(Level::DEBUG, "✅ full matching: {} sets", sets.len());
+
+    Matching {
+        method: "full",
+        sets,
+        rows: problem.rows(),
+        treated: problem.treated.len(),
+        controls: problem.controls.len(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn problem(points: Vec<f64>, treated: Vec<usize>, controls: Vec<usize>) -> Problem {
+        Problem {
+            treated,
+            controls,
+            points,
+            dims: 1,
+            score: None,
+            caliper: None,
+            strata: None,
+        }
+    }
+    #[test]
+    fn test_greedy_pairs_nearest() {
+        let p = problem(vec![0.1, 0.9, 0.12, 0.85, 0.5], vec![0, 1], vec![2, 3, 4]);
+        let m = greedy(&p, 1);
+        assert_eq!(2, m.sets.len());
+        assert_eq!(vec![2], m.sets[0].controls);
+        assert_eq!(vec![3], m.sets[1].controls);
+        assert!((m.total_distance() - 0.07).abs() < 1e-12);
+    }
+    #[test]
+    fn test_nothing_within_the_caliper() {
+        let points = vec![0.1, 0.9, 0.5];
+        let mut p = problem(points.clone(), vec![0, 1], vec![2]);
+        p.score = Some(points);
+        p.caliper = Some(0.05);
+        let m = greedy(&p, 1);
+        assert!(m.sets.is_empty());
+        assert_eq!(None, m.mean_distance());
+        assert!(m.to_string().ends_with("-"));
+    }
+    #[test]
+    fn test_optimal_lowers_total_distance() {
+        // greedy takes t0-c2 first and leaves t1 with the far control
+        let p = problem(vec![0.5, 0.6, 0.55, 0.2], vec![0, 1], vec![2, 3]);
+        let g = greedy(&p, 1);
+        let o = optimal(&p, 1);
+        assert_eq!(2, o.sets.len());
+        assert!(o.total_distance() <= g.total_distance() + 1e-12);
+    }
+    #[test]
+    fn test_full_matching_uses_every_subject() {
+        // two treated near one control, two controls near the other treated
+        let p = problem(
+            vec![0.10, 0.11, 0.90, 0.105, 0.88, 0.91],
+            vec![0, 1, 2],
+            vec![3, 4, 5],
+        );
+        let m = full(&p);
+        assert_eq!(3, m.matched_treated());
+        assert_eq!(3, m.matched_controls());
+        assert_eq!(2, m.sets.len());
+    }
+    #[test]
+    fn test_exact_strata_block_pairs() {
+        let mut p = problem(vec![0.1, 0.12, 0.5], vec![0], vec![1, 2]);
+        p.strata = Some(vec!["a".into(), "b".into(), "a".into()]);
+        let m = greedy(&p, 1);
+        assert_eq!(vec![2], m.sets[0].controls);
+    }
+    #[test]
+    fn test_time_series_matches_the_trajectory() {
+        // the same mean, but c2 rises like t0 while c1 falls
+        let df = df!(
+            "treated" => [1.0, 0.0, 0.0],
+            "MeaType::m_unitcount.time::1" => [1.0, 3.0, 1.1],
+            "MeaType::m_unitcount.time::2" => [2.0, 2.0, 2.0],
+            "MeaType::m_unitcount.time::3" => [3.0, 1.0, 2.9]
+        )
+        .unwrap();
+        let cfg = MatchCfg::time_series(
+            "treated",
+            vec![
+                "MeaType::m_unitcount.time::1",
+                "MeaType::m_unitcount.time::2",
+                "MeaType::m_unitcount.time::3",
+            ],
+        );
+        let p = Problem::new(&df, &cfg).unwrap();
+        assert_eq!(3, p.dims);
+        assert_eq!(vec![2], greedy(&p, 1).sets[0].controls);
+    }
+}
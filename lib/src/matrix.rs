@@ -4,15 +4,40 @@ use tracing::{event, Level};
 
 use polars::prelude::*;
 
+use crate::aipw::{aipw, AipwCfg, AipwFindings};
+use crate::bootstrap::{bootstrap, BootstrapCfg, BootstrapFindings};
+use crate::cem::{cem, Cem, CemCfg};
+use crate::cross_fit::{cross_fit, CrossFitCfg, CrossFitFindings};
+use crate::derived::{base_fields, Derivation};
+use crate::design::{StudyDesign, StudyDesignCfg};
+use crate::dose_response::{dose_response, DoseResponse, DoseResponseCfg, IntensityFit};
+use crate::effect::{adjusted_effect, AdjustedEffect, Effect, EffectCfg};
+use crate::eligibility::{apply_eligibility, Attrition, EligibilityCfg};
+use crate::event_study::{event_study, EventStudy, EventStudyCfg};
+use crate::formula::ModelDef;
+use crate::gps::{Arms, Balance, GpsCfg, GpsFindings};
 use crate::header::Header;
+use crate::ledger::AttritionLedger;
+use crate::matching::{full, greedy, optimal, MatchCfg, MatchMode, Matching, Problem};
 // use crate::to_dummies::CategoryField;
-use crate::propensity::{build_dummies, BinaryTarget, Predictors, PredictorsOwned, PropensityCfg};
+use crate::columns::{optional_weights, series_to_f64, set_ids};
+use crate::panel::{from_panel, to_panel};
+use crate::placebo::{placebo_tests, weighted_difference, PlaceboCfg, PlaceboReport};
+use crate::power::{power, PowerCfg, PowerFindings};
+use crate::propensity::{
+    build_dummies, BinaryTarget, Predictors, PredictorsOwned, PropensityCfg, PropensityFindings,
+};
+use crate::propensity_model::Metrics;
+use crate::segments::{configured_effect, segmented_effects, SegmentCfg, SegmentReport};
+use crate::sensitivity::{set_differences, EValue, RosenbaumBounds};
+use crate::synth::{synthetic_control, SynthCfg, SyntheticControl};
+use crate::terms::{expand_terms, resolve_terms, Term};
 use crate::to_row_dominant;
+use crate::trend::{trend_features, TrendCfg};
+use crate::uplift::{uplift, UpliftCfg, UpliftFindings};
 use crate::FieldNamesCfg;
 use crate::{get_fuzzy_binary_target, get_fuzzy_predictors};
 
-use propensity_score::prelude::*;
-
 // temporary
 const OUT_FILE: &str = "./res/logit-data.parquet";
 
@@ -96,11 +121,16 @@ impl Matrix<DataFrame> {
     /// Converts the predictors in the matrix to a 1D array.  The dummy variables need to be built here
     /// to avoid having to reference them post instantiation.
     ///
+    /// Interaction, polynomial and spline terms are appended after the dummies (see
+    /// [`crate::terms::Term`]).
+    ///
     /// Dependency: User knows the original column names, pre-dummie making.
     ///
     pub fn to_row_dominant(
         &self,
         columns: &PredictorsOwned,
+        terms: &[Term],
+        categorical: &[String],
         // mask: Option<&Mask<'a>>,
     ) -> Result<(Vec<f64>, usize)> {
         // select from the dataframe
@@ -111,9 +141,24 @@ impl Matrix<DataFrame> {
         }; */
         event!(Level::DEBUG, "Columns sent to build X? {:?}", &columns);
 
-        let columns: Vec<&str> = columns.into();
-        let df = self.select(columns)?;
-        let df = build_dummies(df, None, None)?;
+        let terms = resolve_terms(terms, &self.header())?;
+        let mut columns: Vec<&str> = columns.into();
+        for field in terms
+            .iter()
+            .filter(|t| matches!(t, Term::Main(_)))
+            .flat_map(|t| t.fields())
+        {
+            if !columns.contains(&field) {
+                columns.push(field);
+            }
+        }
+        let mut df = self.select(columns)?;
+        for field in categorical {
+            let forced = df.column(field)?.cast(&DataType::Utf8)?;
+            df.with_column(forced)?;
+        }
+        let mut df = build_dummies(df, None, None)?;
+        df.hstack_mut(&expand_terms(self, &terms, categorical)?)?;
 
         to_row_dominant(&df)
     }
@@ -141,15 +186,86 @@ impl Matrix<DataFrame> {
     ///
     pub fn with_propensity(mut self, cfg: PropensityCfg) -> Result<Self> {
         event!(Level::DEBUG, "📋 logit cfg:\n{:?}", &cfg,);
+
+        let scores = match &cfg.cross_fit {
+            Some(cross_fit_cfg) => {
+                let findings = self.cross_fit_propensity(&cfg, cross_fit_cfg)?;
+                event!(Level::INFO, "\n📋 {}", findings.report());
+                self.with_column(Series::new(&cfg.fold_name(), findings.fold_ids))?;
+                findings.scores
+            }
+            None => {
+                let findings = self.fit_propensity(&cfg)?;
+                event!(Level::INFO, "\n📋 {}", findings.report());
+                findings.scores
+            }
+        };
+
+        // create a prediction and append to the matrix
+        self.with_column(Series::new(&cfg.name, scores))?;
+
+        let new_df = self.bin_from_column(&cfg.name, &cfg.bin_name(), None)?;
+
+        Ok(new_df)
+    }
+    ///
+    /// In-sample propensity scores using the configured model family.
+    ///
+    pub fn fit_propensity(&self, cfg: &PropensityCfg) -> Result<PropensityFindings> {
         //
         // TODO: MAKE SURE NO NULLS
         // let (x, row_count) = self.to_row_dominant(&cfg.predictors, cfg.mask.as_ref())?;
         //
-        let (x, row_count) = self.to_row_dominant(&cfg.predictors /* None */)?;
+        let (x, row_count) = self.to_row_dominant(
+            &cfg.predictors,
+            &cfg.terms,
+            &cfg.categorical, /* None */
+        )?;
         event!(Level::INFO, "✅ X as 1D with row count:{}", row_count);
         event!(Level::DEBUG, "{:#?}", self.show_meta()?);
-        // build y
-        let y: &Series = self.column(&cfg.target.as_str())?;
+        let y = self.binary_target_values(cfg)?;
+
+        let model = cfg.model.model();
+        let scores = model.scores(&x, &y, row_count)?;
+
+        Ok(PropensityFindings {
+            model: model.name(),
+            metrics: Metrics::new(&y, &scores),
+            scores,
+        })
+    }
+    ///
+    /// Out-of-fold propensity scores: each subject is scored by a model fitted on the other
+    /// folds.  Returns the scores with the cross-validated metrics.
+    ///
+    pub fn cross_fit_propensity(
+        &self,
+        cfg: &PropensityCfg,
+        cross_fit_cfg: &CrossFitCfg,
+    ) -> Result<CrossFitFindings> {
+        let (x, row_count) = self.to_row_dominant(&cfg.predictors, &cfg.terms, &cfg.categorical)?;
+        event!(Level::INFO, "✅ X as 1D with row count:{}", row_count);
+        let y = self.binary_target_values(cfg)?;
+
+        cross_fit(cfg.model.model().as_ref(), &x, &y, row_count, cross_fit_cfg)
+    }
+    ///
+    /// The binary target as `Vec<f64>`, y for the propensity model.  A categorical target with
+    /// two arms is accepted; the second arm (see [`Arms`]) is coded 1.
+    ///
+    fn binary_target_values(&self, cfg: &PropensityCfg) -> Result<Vec<f64>> {
+        let y: &Series = self.column(cfg.target.as_str())?;
+        if let DataType::Utf8 = y.dtype() {
+            let arms = Arms::new(y, None)?;
+            if arms.k() != 2 {
+                return Err(eyre!(
+                    "{} has {} arms; use the generalized propensity score (GpsCfg)",
+                    &cfg.target,
+                    arms.k()
+                ));
+            }
+            return Ok(arms.labels.iter().map(|l| *l as f64).collect());
+        }
         let y = y
             .cast(&DataType::Float64)
             .expect("Cast to Float64 failed")
@@ -169,30 +285,339 @@ impl Matrix<DataFrame> {
             y.len()
         );
         debug_assert!(
-            y.len() == row_count,
+            y.len() == self.height(),
             "The y and X logit inputs have different row counts"
         );
+        Ok(y)
+    }
+    ///
+    /// Generalized propensity score for a multi-valued (categorical) treatment.
+    ///
+    pub fn fit_gps(&self, cfg: &GpsCfg) -> Result<GpsFindings> {
+        let (x, row_count) = self.to_row_dominant(&cfg.predictors, &cfg.terms, &cfg.categorical)?;
+        event!(Level::INFO, "✅ X as 1D with row count:{}", row_count);
+        let arms = Arms::new(self.column(&cfg.treatment)?, cfg.reference.as_deref())?;
 
-        // .map(|s| Ok(s.f64()?.into_iter()))
-        let objective = Objective::from_vecs(x, y, row_count)?;
-
-        let optimizer_cfg = CfgBuilder::new().max_iters(100).logging(false).build();
-
-        let findings = logit::run(&objective, optimizer_cfg)?;
-
-        event!(Level::INFO, "\n📋 logit findings\n{}", findings.report()?);
-
-        // create a prediction and append to the matrix
-        self.with_column(Series::new(
-            &cfg.name,
-            Vec::from(
-                findings.predict(false), // binary = false, show_sample
-            ),
-        ))?;
-
-        let new_df = self.bin_from_column(&cfg.name, &cfg.bin_name(), None)?;
-
-        Ok(new_df)
+        GpsFindings::fit(&x, row_count, arms)
+    }
+    ///
+    /// Appends one `{name}_{arm}` score column per arm and the `{name}_weight` GIPW weight.
+    ///
+    pub fn with_gps(mut self, cfg: &GpsCfg) -> Result<Self> {
+        let findings = self.fit_gps(cfg)?;
+        event!(Level::INFO, "\n📋 {}", findings.report());
+        for column in findings.to_columns(cfg) {
+            self.with_column(column)?;
+        }
+        Ok(self)
+    }
+    ///
+    /// Pairwise balance between the arms, unweighted and with the GIPW weights.
+    ///
+    pub fn gps_balance(
+        &self,
+        cfg: &GpsCfg,
+        findings: &GpsFindings,
+        covariates: &[&str],
+    ) -> Result<Balance> {
+        let covariates: Vec<String> = covariates.iter().map(|c| c.to_string()).collect();
+        Balance::new(
+            self,
+            &findings.arms,
+            &findings.weights(cfg.stabilized),
+            &covariates,
+        )
+    }
+    ///
+    /// Appends `{name}`, the GPS of the intensity each subject received (see
+    /// [`DoseResponseCfg`]).
+    ///
+    pub fn with_intensity_gps(mut self, cfg: &DoseResponseCfg) -> Result<Self> {
+        let (x, row_count) = self.to_row_dominant(&cfg.predictors, &cfg.terms, &cfg.categorical)?;
+        let t = series_to_f64(self.column(&cfg.treatment)?)?;
+        let fit = IntensityFit::fit(cfg.model, &x, &t, row_count)?;
+        let gps: Vec<f64> = t
+            .iter()
+            .zip(fit.eta(&x))
+            .map(|(t, eta)| fit.density(*t, eta))
+            .collect();
+        self.with_column(Series::new(&cfg.name, gps))?;
+        Ok(self)
+    }
+    ///
+    /// Dose-response curve of the outcome across treatment intensities with bootstrap bands.
+    /// See [`DoseResponse::to_dataframe`] for the table.
+    ///
+    pub fn dose_response(&self, cfg: &DoseResponseCfg) -> Result<DoseResponse> {
+        let (x, row_count) = self.to_row_dominant(&cfg.predictors, &cfg.terms, &cfg.categorical)?;
+        event!(Level::INFO, "✅ X as 1D with row count:{}", row_count);
+        let t = series_to_f64(self.column(&cfg.treatment)?)?;
+        let y = series_to_f64(self.column(&cfg.outcome)?)?;
+        let found = dose_response(cfg, &x, &t, &y)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// Match treated to control subjects (see [`MatchCfg`] for the distances and constraints).
+    ///
+    pub fn match_subjects(&self, cfg: &MatchCfg) -> Result<Matching> {
+        let problem = Problem::new(self, cfg)?;
+        Ok(match cfg.mode {
+            MatchMode::Greedy => greedy(&problem, cfg.ratio),
+            MatchMode::Optimal => optimal(&problem, cfg.ratio),
+            MatchMode::Full => full(&problem),
+        })
+    }
+    ///
+    /// Appends the `{name}_id`, `{name}_distance` and `{name}_weight` columns.
+    ///
+    pub fn with_matching(mut self, cfg: &MatchCfg) -> Result<Self> {
+        let matching = self.match_subjects(cfg)?;
+        event!(Level::INFO, "\n📋 {}", matching);
+        if matching.sets.is_empty() {
+            event!(Level::WARN, "⚠️ no subjects matched; check the caliper");
+        }
+        for column in matching.to_columns(cfg) {
+            self.with_column(column)?;
+        }
+        Ok(self)
+    }
+    ///
+    /// Coarsened exact matching on the categorical and binned fields (see [`CemCfg`]).
+    ///
+    pub fn coarsened_exact_match(&self, cfg: &CemCfg) -> Result<Cem> {
+        cem(self, cfg)
+    }
+    ///
+    /// The time-windowed measures in long format,
+    /// `(subject_idx, measure, product, time_start, time_end, value)`.
+    ///
+    pub fn to_panel(&self) -> Result<DataFrame> {
+        let panel = to_panel(self, "subject_idx")?;
+        event!(Level::INFO, "✅ panel with {} rows", panel.height());
+        Ok(panel)
+    }
+    ///
+    /// The wide matrix of a panel built by [`Matrix::to_panel`].
+    ///
+    pub fn from_panel(panel: &DataFrame) -> Result<Matrix<DataFrame>> {
+        Ok(from_panel(panel, "subject_idx")?.into())
+    }
+    ///
+    /// Appends the derived fields of `base`, a field or a measure (every product and time
+    /// window of it), named `<field>.derivedField::<name>`.  For a measure, windows without a
+    /// pre-period have no delta.  Rows with a null base value get a null derived value.
+    ///
+    pub fn with_derived(mut self, base: &str, derivations: &[Derivation]) -> Result<Self> {
+        let names = self.get_column_names_owned();
+        let names: Vec<&str> = names.iter().map(|n| n.as_str()).collect();
+        let fields = base_fields(base, &names)?;
+        let mut columns = Vec::new();
+        for field in &fields {
+            for derivation in derivations {
+                match derivation.derive(&self, field) {
+                    Ok(column) => columns.push(column),
+                    Err(e) if *derivation == Derivation::Delta && fields.len() > 1 => {
+                        event!(Level::DEBUG, "no delta for {}: {}", field, e)
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        event!(
+            Level::INFO,
+            "✅ {} derived fields of {}",
+            columns.len(),
+            base
+        );
+        for column in columns {
+            self.with_column(column)?;
+        }
+        Ok(self)
+    }
+    ///
+    /// Appends the pre-period slope, level and volatility of a measure (see [`TrendCfg`]).
+    ///
+    pub fn with_trend_features(mut self, cfg: &TrendCfg) -> Result<Self> {
+        for column in trend_features(&self, cfg)? {
+            event!(Level::INFO, "✅ trend feature: {}", column.name());
+            self.with_column(column)?;
+        }
+        Ok(self)
+    }
+    ///
+    /// Appends the `{name}_id` and `{name}_weight` CEM columns.
+    ///
+    pub fn with_cem(mut self, cfg: &CemCfg) -> Result<Self> {
+        let found = self.coarsened_exact_match(cfg)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        for column in found.to_columns(cfg) {
+            self.with_column(column)?;
+        }
+        Ok(self)
+    }
+    ///
+    /// Rosenbaum bounds for a matched design: `sets` is the matched set id column (e.g.
+    /// `match_id`), evaluated at each Γ.
+    ///
+    pub fn rosenbaum_bounds(
+        &self,
+        treatment: &str,
+        outcome: &str,
+        sets: &str,
+        gammas: &[f64],
+    ) -> Result<RosenbaumBounds> {
+        let differences = set_differences(
+            &series_to_f64(self.column(treatment)?)?,
+            &series_to_f64(self.column(outcome)?)?,
+            &set_ids(self, sets)?,
+        );
+        let found = RosenbaumBounds::new(&differences, gammas)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// E-value of the lift, optionally with the weights of a weighting design.
+    ///
+    pub fn e_value(&self, treatment: &str, outcome: &str, weight: Option<&str>) -> Result<EValue> {
+        let found = EValue::new(
+            &series_to_f64(self.column(treatment)?)?,
+            &series_to_f64(self.column(outcome)?)?,
+            optional_weights(self, weight)?.as_deref(),
+        )?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// Minimum detectable effect (and the sample size needed for a target effect) of a planned
+    /// test on the eligible population; see [`PowerCfg`].
+    ///
+    pub fn power(&self, cfg: &PowerCfg) -> Result<PowerFindings> {
+        let found = power(self, cfg)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// The lift of the outcome, CUPED / regression adjusted when configured, next to the
+    /// unadjusted difference in means.
+    ///
+    pub fn effect(&self, cfg: &EffectCfg) -> Result<AdjustedEffect> {
+        let found = adjusted_effect(self, cfg)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// The configured effect rerun within each segment, with the heterogeneity test and
+    /// corrected p-values (see [`SegmentCfg`]).
+    ///
+    pub fn segmented_effects(&self, cfg: &SegmentCfg) -> Result<SegmentReport> {
+        self.segmented_effects_with(cfg, configured_effect(&cfg.effect))
+    }
+    ///
+    /// Segmented effects with any estimator; it receives the rows of the segment.
+    ///
+    pub fn segmented_effects_with<F>(&self, cfg: &SegmentCfg, estimator: F) -> Result<SegmentReport>
+    where
+        F: Fn(&DataFrame) -> Result<Effect>,
+    {
+        let report = segmented_effects(self, cfg, estimator)?;
+        event!(Level::INFO, "\n📋 {}", report);
+        Ok(report)
+    }
+    ///
+    /// Uplift score of every subject with the Qini and uplift-curve tables
+    /// (see [`UpliftFindings::to_dataframe`]).
+    ///
+    pub fn uplift(&self, cfg: &UpliftCfg) -> Result<UpliftFindings> {
+        let (x, row_count) = self.to_row_dominant(&cfg.predictors, &cfg.terms, &cfg.categorical)?;
+        event!(Level::INFO, "✅ X as 1D with row count:{}", row_count);
+        let weights = optional_weights(self, cfg.weight.as_deref())?;
+        let found = uplift(
+            cfg,
+            &x,
+            &series_to_f64(self.column(&cfg.treatment)?)?,
+            &series_to_f64(self.column(&cfg.outcome)?)?,
+            weights.as_deref(),
+        )?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// Appends the uplift score column, `cfg.name`.
+    ///
+    pub fn with_uplift(mut self, cfg: &UpliftCfg) -> Result<Self> {
+        let found = self.uplift(cfg)?;
+        self.with_column(Series::new(&cfg.name, found.scores))?;
+        Ok(self)
+    }
+    ///
+    /// Per-period treated-minus-control differences of a measure around the treatment start,
+    /// with the joint pre-trend test (see [`EventStudyCfg`]).
+    ///
+    pub fn event_study(&self, cfg: &EventStudyCfg) -> Result<EventStudy> {
+        let found = event_study(self, cfg)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// Synthetic control of the treated units' mean series from convex donor weights, with
+    /// placebo-in-space inference (see [`SynthCfg`]).
+    ///
+    pub fn synthetic_control(&self, cfg: &SynthCfg) -> Result<SyntheticControl> {
+        let found = synthetic_control(self, cfg)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// Doubly robust ATE and ATT from the propensity score column and per-arm outcome
+    /// regressions (see [`AipwCfg`]).
+    ///
+    pub fn aipw(&self, cfg: &AipwCfg) -> Result<AipwFindings> {
+        let (x, row_count) = self.to_row_dominant(&cfg.predictors, &cfg.terms, &cfg.categorical)?;
+        event!(Level::INFO, "✅ X as 1D with row count:{}", row_count);
+        let found = aipw(
+            cfg,
+            &x,
+            &series_to_f64(self.column(&cfg.treatment)?)?,
+            &series_to_f64(self.column(&cfg.outcome)?)?,
+            &series_to_f64(self.column(&cfg.score)?)?,
+        )?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
+    }
+    ///
+    /// Placebo tests with the (weighted) difference in means of the design.
+    ///
+    pub fn placebo_tests(&self, cfg: &PlaceboCfg) -> Result<PlaceboReport> {
+        self.placebo_tests_with(cfg, weighted_difference(cfg))
+    }
+    ///
+    /// Placebo tests with any estimator; it receives the matrix and the outcome field.
+    ///
+    pub fn placebo_tests_with<F>(&self, cfg: &PlaceboCfg, estimator: F) -> Result<PlaceboReport>
+    where
+        F: Fn(&DataFrame, &str) -> Result<Effect>,
+    {
+        let report = placebo_tests(self, cfg, estimator)?;
+        event!(Level::INFO, "\n📋 {}", report);
+        Ok(report)
+    }
+    ///
+    /// Bootstrap intervals for the effects returned by `pipeline`, which is rerun from scratch
+    /// (propensity fit, binning or matching, effect) on each resample of this matrix.
+    ///
+    pub fn bootstrap<F>(
+        &self,
+        cfg: &BootstrapCfg,
+        names: &[&str],
+        pipeline: F,
+    ) -> Result<BootstrapFindings>
+    where
+        F: Fn(Matrix<DataFrame>) -> Result<Vec<f64>> + Sync,
+    {
+        let found = bootstrap(self, cfg, names, pipeline)?;
+        event!(Level::INFO, "\n📋 {}", found);
+        Ok(found)
     }
     ///
     /// Generates bins from a column.  The column needs to be a continuous variable with values
@@ -249,20 +674,24 @@ impl Matrix<DataFrame> {
     pub fn header(&self) -> Header<'_> {
         Header::new(self.get_column_names())
     }
-    /// Make this part of the initialization sequence
+    ///
+    /// Tag the subjects with a non-null `subject_idx` (see [`EligibilityCfg::default`]).
+    ///
     pub fn with_include_tag(&mut self) -> (usize, usize) {
-        let include_col = Series::new("include", self.column("subject_idx").unwrap().is_not_null());
-        self.with_column(include_col).unwrap();
-
-        let null_count: usize = self.column("subject_idx").unwrap().null_count();
-        let mask = self.column("include").unwrap().bool().unwrap();
-        let included = self.filter(mask).unwrap().height();
-        let excluded = self.height() - self.filter(mask).unwrap().height();
-
-        // let excluded_count = ec.height();
-        debug_assert!(null_count == excluded, "The excluded column is flawed");
-
-        (included, excluded)
+        let attrition = self
+            .with_eligibility(&EligibilityCfg::default())
+            .expect("Failed to tag the included subjects");
+        (attrition.included(), attrition.excluded())
+    }
+    ///
+    /// Appends the `include` and `exclusion_reason` columns for the eligibility rules and
+    /// returns the attrition table.
+    ///
+    pub fn with_eligibility(&mut self, cfg: &EligibilityCfg) -> Result<Attrition> {
+        let (df, attrition) = apply_eligibility(self.inner.clone(), cfg)?;
+        event!(Level::INFO, "\n📋 {}", attrition);
+        self.inner = df;
+        Ok(attrition)
     }
     /// predictors
     /// Use "q_" & "derived" in the Matrix.  Override with cfg.
@@ -273,6 +702,27 @@ impl Matrix<DataFrame> {
     pub fn binary_target(&self, cfg: FieldNamesCfg) -> BinaryTarget<'_> {
         get_fuzzy_binary_target(self.get_column_names(), cfg).into()
     }
+    ///
+    /// Resolve the treatment, outcome and eligibility roles against the header (see
+    /// [`StudyDesign::resolve`]).
+    ///
+    pub fn study_design(&self, cfg: &StudyDesignCfg) -> Result<StudyDesign> {
+        let design = StudyDesign::resolve(self, cfg)?;
+        event!(Level::INFO, "\n📋 {}", design);
+        Ok(design)
+    }
+    /// Keep the subjects eligible for the study
+    pub fn eligible(self, design: &StudyDesign) -> Result<Self> {
+        Ok(design.eligible(&self)?.into())
+    }
+    /// Keep the subjects still in the cohort of the attrition ledger
+    pub fn cohort(self, ledger: &AttritionLedger) -> Result<Self> {
+        Ok(ledger.cohort_of(&self)?.into())
+    }
+    /// Specify the model as a formula, e.g. `reach ~ . - q_state + C(q_innetwork)`
+    pub fn model_def(&self, formula: &str, cfg: &FieldNamesCfg) -> Result<ModelDef<'_>> {
+        ModelDef::parse(formula, &self.header(), cfg)
+    }
     /// write so that target-binary is the first arrow
     pub fn write_to_file<P: AsRef<std::path::Path>>(&mut self, path: Option<P>) -> Result<()> {
         let mut file = match path {
@@ -300,25 +750,6 @@ schema: {:#?}
     }
 }
 // -------------------------------------------------------------------------------------
-// transient type for printing
-pub struct ModelDef<'a> {
-    dependent: &'a str,
-    predictors: Vec<&'a str>,
-}
-impl<'a> From<(&'a str, Vec<&'a str>)> for ModelDef<'a> {
-    fn from((dependent, predictors): (&'a str, Vec<&'a str>)) -> Self {
-        ModelDef {
-            dependent,
-            predictors,
-        }
-    }
-}
-impl<'a> fmt::Display for ModelDef<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} ~ {}", self.dependent, self.predictors.join(" + "))
-    }
-}
-// -------------------------------------------------------------------------------------
 // debug - show type
 //
 /*
@@ -0,0 +1,187 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::HashMap;
+use tracing::{event, Level};
+
+use crate::field_name::{ParsedField, PRODUCT_KEY, TIME_KEY};
+
+pub const MEASURE: &str = "measure";
+pub const PRODUCT: &str = "product";
+pub const TIME_START: &str = "time_start";
+pub const TIME_END: &str = "time_end";
+pub const VALUE: &str = "value";
+
+///
+/// The time-windowed measures of the matrix in long format, one row per subject and field:
+///
+/// `(id, measure, product, time_start, time_end, value)`
+///
+/// Fields are taken when the name parses to a measure with a `time::` window and at most a
+/// `product::` qualifier; derived and otherwise qualified fields have no place in the panel
+/// and are left out.  A single period `time::14` has `time_start == time_end`.
+///
+pub fn to_panel(df: &DataFrame, id: &str) -> Result<DataFrame> {
+    let ids = df.column(id)?;
+    let mut panel: Option<DataFrame> = None;
+    let mut skipped = 0;
+    for series in df.get_columns() {
+        let field = ParsedField::parse(series.name());
+        let window = match field.time() {
+            Some(window) => window,
+            None => continue,
+        };
+        if field
+            .components
+            .iter()
+            .any(|(k, _)| k != TIME_KEY && k != PRODUCT_KEY)
+        {
+            skipped += 1;
+            continue;
+        }
+        let rows = series.len();
+        let mut value = series.cast(&DataType::Float64)?;
+        value.rename(VALUE);
+        let block = DataFrame::new(vec![
+            ids.clone(),
+            Series::new(MEASURE, vec![field.measure.as_str(); rows]),
+            Series::new(PRODUCT, vec![field.product(); rows]),
+            Series::new(TIME_START, vec![window.start; rows]),
+            Series::new(TIME_END, vec![window.end; rows]),
+            value,
+        ])?;
+        panel = Some(match panel {
+            Some(panel) => panel.vstack(&block)?,
+            None => block,
+        });
+    }
+    if skipped > 0 {
+        event!(
+            Level::DEBUG,
+            "{} derived or qualified time fields left out of the panel",
+            skipped
+        );
+    }
+    panel.ok_or_else(|| eyre!("No time-windowed measures in the matrix"))
+}
+
+///
+/// The inverse of [`to_panel`]: one row per id (in order of first appearance) and one column per
+/// (measure, product, window), named in the tnc app convention.
+///
+pub fn from_panel(panel: &DataFrame, id: &str) -> Result<DataFrame> {
+    let keys = panel.column(id)?.cast(&DataType::Utf8)?;
+    let measures = panel.column(MEASURE)?.utf8()?.clone();
+    let products = panel.column(PRODUCT)?.cast(&DataType::Utf8)?;
+    let starts = panel.column(TIME_START)?.cast(&DataType::Int64)?;
+    let ends = panel.column(TIME_END)?.cast(&DataType::Int64)?;
+    let values = panel.column(VALUE)?.cast(&DataType::Float64)?;
+
+    let mut rows: HashMap<String, usize> = HashMap::new();
+    let mut first: Vec<IdxSize> = Vec::new();
+    let mut fields: Vec<(String, Vec<Option<f64>>)> = Vec::new();
+    let mut columns: HashMap<String, usize> = HashMap::new();
+    let cells = keys
+        .utf8()?
+        .into_iter()
+        .zip(&measures)
+        .zip(products.utf8()?)
+        .zip(starts.i64()?.into_iter().zip(ends.i64()?))
+        .zip(values.f64()?);
+    for (r, ((((key, measure), product), (start, end)), value)) in cells.enumerate() {
+        let key = key.ok_or_else(|| eyre!("Null {} in the panel", id))?;
+        let (measure, start, end) = match (measure, start, end) {
+            (Some(m), Some(s), Some(e)) => (m, s, e),
+            _ => return Err(eyre!("Null measure or window in row {} of the panel", r)),
+        };
+        let row = *rows.entry(key.to_string()).or_insert_with(|| {
+            first.push(r as IdxSize);
+            first.len() - 1
+        });
+        let mut components = Vec::new();
+        if let Some(product) = product {
+            components.push((PRODUCT_KEY.to_string(), product.to_string()));
+        }
+        let window = match start == end {
+            true => start.to_string(),
+            false => format!("{}_{}", start, end),
+        };
+        components.push((TIME_KEY.to_string(), window));
+        let name = ParsedField {
+            measure: measure.to_string(),
+            components,
+        }
+        .to_string();
+        let column = *columns.entry(name.clone()).or_insert_with(|| {
+            fields.push((name, Vec::new()));
+            fields.len() - 1
+        });
+        let cells = &mut fields[column].1;
+        if cells.len() <= row {
+            cells.resize(row + 1, None);
+        }
+        cells[row] = value;
+    }
+
+    let n = first.len();
+    let mut wide = vec![panel.column(id)?.take(&IdxCa::from_vec(id, first))?];
+    for (name, mut cells) in fields {
+        cells.resize(n, None);
+        wide.push(Series::new(&name, cells));
+    }
+    Ok(DataFrame::new(wide)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::columns::series_to_f64;
+
+    #[test]
+    fn test_panel_round_trip() {
+        let wide = df!(
+            "subject_idx" => [0u32, 1, 2],
+            "q_state" => ["NY", "CA", "TX"],
+            "MeaType::m_unitcount.product::A.time::14" => [1.0, 2.0, 3.0],
+            "MeaType::m_unitcount.product::A.time::0_23" => [4.0, 5.0, 6.0],
+            "MeaType::m_reach.time::15" => [0.0, 1.0, 0.0],
+            "MeaType::m_unitcount.product::A.time::0_23.derivedField::decile" => [1.0, 5.0, 10.0]
+        )
+        .unwrap();
+        let panel = to_panel(&wide, "subject_idx").unwrap();
+        assert_eq!(9, panel.height());
+        assert_eq!(
+            vec!["subject_idx", MEASURE, PRODUCT, TIME_START, TIME_END, VALUE],
+            panel.get_column_names()
+        );
+        assert_eq!(
+            Some(0),
+            panel.column(TIME_START).unwrap().i64().unwrap().get(3)
+        );
+        assert_eq!(
+            Some(23),
+            panel.column(TIME_END).unwrap().i64().unwrap().get(3)
+        );
+        assert_eq!(None, panel.column(PRODUCT).unwrap().utf8().unwrap().get(6));
+
+        let back = from_panel(&panel, "subject_idx").unwrap();
+        assert_eq!(
+            vec![
+                "subject_idx",
+                "MeaType::m_unitcount.product::A.time::14",
+                "MeaType::m_unitcount.product::A.time::0_23",
+                "MeaType::m_reach.time::15"
+            ],
+            back.get_column_names()
+        );
+        assert!(back
+            .column("subject_idx")
+            .unwrap()
+            .series_equal(wide.column("subject_idx").unwrap()));
+        for name in &back.get_column_names()[1..] {
+            assert_eq!(
+                series_to_f64(wide.column(name).unwrap()).unwrap(),
+                series_to_f64(back.column(name).unwrap()).unwrap()
+            );
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use color_eyre::eyre::Result;
+use polars::prelude::*;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::columns::series_to_f64;
+use crate::effect::{difference_in_means, Effect};
+use crate::field_name::pre_period;
+
+///
+/// Falsification tests for a design.  The effect estimator is rerun on outcomes it cannot have
+/// moved:
+///
+/// * the same measure over a pre-treatment `time::` window (the closest one before the outcome
+///   window unless `pre_window` names one, e.g. `0_13`)
+/// * negative-control outcomes chosen by the user
+///
+/// A placebo effect with p < alpha is flagged as evidence of residual confounding.  Without a
+/// pre-period field (e.g. a `q_` outcome) only the negative controls are run.
+///
+#[derive(Debug, Clone)]
+pub struct PlaceboCfg {
+    pub treatment: String,
+    pub outcome: String,
+    /// weights of the design, e.g. `match_weight`, `cem_weight` or `gps_weight`
+    pub weight: Option<String>,
+    pub pre_window: Option<String>,
+    pub negative_controls: Vec<String>,
+    pub alpha: f64,
+}
+
+impl PlaceboCfg {
+    pub fn new(treatment: &str, outcome: &str) -> Self {
+        PlaceboCfg {
+            treatment: treatment.to_string(),
+            outcome: outcome.to_string(),
+            weight: None,
+            pre_window: None,
+            negative_controls: Vec::new(),
+            alpha: 0.05,
+        }
+    }
+    pub fn weight(mut self, weight: &str) -> Self {
+        self.weight = Some(weight.to_string());
+        self
+    }
+    pub fn pre_window(mut self, window: &str) -> Self {
+        self.pre_window = Some(window.to_string());
+        self
+    }
+    pub fn negative_controls(mut self, outcomes: Vec<&str>) -> Self {
+        self.negative_controls = outcomes.iter().map(|o| o.to_string()).collect();
+        self
+    }
+    pub fn alpha(mut self, alpha: f64) -> Self {
+        self.alpha = alpha;
+        self
+    }
+    ///
+    /// The pre-period field of the outcome in the matrix.
+    ///
+    pub fn pre_period(&self, names: &[&str]) -> Result<String> {
+        pre_period(&self.outcome, self.pre_window.as_deref(), names)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaceboKind {
+    Primary,
+    PrePeriod,
+    NegativeControl,
+}
+impl fmt::Display for PlaceboKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaceboKind::Primary => write!(f, "primary"),
+            PlaceboKind::PrePeriod => write!(f, "pre-period"),
+            PlaceboKind::NegativeControl => write!(f, "negative control"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlaceboTest {
+    pub kind: PlaceboKind,
+    pub effect: Effect,
+    /// significant placebo effect
+    pub flagged: bool,
+}
+
+///
+/// The primary effect next to its placebo effects.
+///
+#[derive(Debug, Clone)]
+pub struct PlaceboReport {
+    pub tests: Vec<PlaceboTest>,
+    pub alpha: f64,
+}
+
+impl PlaceboReport {
+    pub fn flagged(&self) -> impl Iterator<Item = &PlaceboTest> {
+        self.tests.iter().filter(|t| t.flagged)
+    }
+    pub fn passed(&self) -> bool {
+        self.flagged().next().is_none()
+    }
+    ///
+    /// `kind`, `outcome`, `estimate`, `se`, `p_value`, `flagged`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let kinds: Vec<String> = self.tests.iter().map(|t| t.kind.to_string()).collect();
+        let outcomes: Vec<&str> = self
+            .tests
+            .iter()
+            .map(|t| t.effect.outcome.as_str())
+            .collect();
+        let column =
+            |f: fn(&PlaceboTest) -> f64| -> Vec<f64> { self.tests.iter().map(f).collect() };
+        Ok(DataFrame::new(vec![
+            Series::new("kind", kinds),
+            Series::new("outcome", outcomes),
+            Series::new("estimate", column(|t| t.effect.estimate)),
+            Series::new("se", column(|t| t.effect.se)),
+            Series::new("p_value", column(|t| t.effect.p_value())),
+            Series::new(
+                "flagged",
+                self.tests.iter().map(|t| t.flagged).collect::<Vec<_>>(),
+            ),
+        ])?)
+    }
+}
+impl fmt::Display for PlaceboReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Placebo tests (flag at p < {})", self.alpha)?;
+        for t in &self.tests {
+            writeln!(
+                f,
+                "  {:<16} {} {}",
+                t.kind.to_string(),
+                t.effect,
+                if t.flagged { "⚠️" } else { "" }
+            )?;
+        }
+        match self.passed() {
+            true => write!(f, "  ✅ no significant placebo effects"),
+            false => write!(
+                f,
+                "  ⚠️ {} significant placebo effect(s): evidence of residual confounding",
+                self.flagged().count()
+            ),
+        }
+    }
+}
+
+///
+/// Run `estimator` on the outcome, its pre-period and the negative controls.  The estimator
+/// receives the matrix and the outcome field.
+///
+pub fn placebo_tests<F>(df: &DataFrame, cfg: &PlaceboCfg, estimator: F) -> Result<PlaceboReport>
+where
+    F: Fn(&DataFrame, &str) -> Result<Effect>,
+{
+    let names = df.get_column_names();
+    let mut outcomes = vec![(PlaceboKind::Primary, cfg.outcome.clone())];
+    match cfg.pre_period(&names) {
+        Ok(pre) => outcomes.push((PlaceboKind::PrePeriod, pre)),
+        Err(e) => event!(Level::WARN, "⚠️ pre-period test skipped: {}", e),
+    }
+    outcomes.extend(
+        cfg.negative_controls
+            .iter()
+            .map(|o| (PlaceboKind::NegativeControl, o.clone())),
+    );
+
+    let tests = outcomes
+        .into_iter()
+        .map(|(kind, outcome)| {
+            let effect = estimator(df, &outcome)?;
+            let flagged = kind != PlaceboKind::Primary && effect.p_value() < cfg.alpha;
+            if flagged {
+                event!(Level::WARN, "⚠️ significant placebo effect: {}", effect);
+            }
+            Ok(PlaceboTest {
+                kind,
+                effect,
+                flagged,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(PlaceboReport {
+        tests,
+        alpha: cfg.alpha,
+    })
+}
+
+///
+/// The default estimator: the (weighted) difference in means of the design.
+///
+pub fn weighted_difference(cfg: &PlaceboCfg) -> impl Fn(&DataFrame, &str) -> Result<Effect> + '_ {
+    move |df, outcome| {
+        let weights = match &cfg.weight {
+            Some(w) => Some(series_to_f64(df.column(w)?)?),
+            None => None,
+        };
+        difference_in_means(
+            outcome,
+            &series_to_f64(df.column(&cfg.treatment)?)?,
+            &series_to_f64(df.column(outcome)?)?,
+            weights.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flags_confounded_pre_period() {
+        let rows = 200;
+        let treated: Vec<f64> = (0..rows).map(|i| (i % 2) as f64).collect();
+        // the treated were already higher before the campaign
+        let pre: Vec<f64> = (0..rows)
+            .map(|i| 2.0 * (i % 2) as f64 + ((i * 7) % 5) as f64)
+            .collect();
+        let post: Vec<f64> = pre.iter().map(|p| p + 1.0).collect();
+        let noise: Vec<f64> = (0..rows).map(|i| ((i * 13) % 7) as f64).collect();
+        let df = df!(
+            "treated" => treated,
+            "MeaType::m_unitcount.product::A.time::0_13" => pre,
+            "MeaType::m_unitcount.product::A.time::14_27" => post,
+            "q_noise" => noise
+        )
+        .unwrap();
+        let cfg = PlaceboCfg::new("treated", "MeaType::m_unitcount.product::A.time::14_27")
+            .negative_controls(vec!["q_noise"]);
+        let report = placebo_tests(&df, &cfg, weighted_difference(&cfg)).unwrap();
+
+        assert_eq!(3, report.tests.len());
+        assert_eq!(PlaceboKind::PrePeriod, report.tests[1].kind);
+        assert!(report.tests[1].flagged);
+        assert!(!report.tests[2].flagged);
+        assert!(!report.passed());
+
+        // no time:: window to shift: the negative controls still run
+        let cfg = PlaceboCfg::new("treated", "q_noise")
+            .negative_controls(vec!["MeaType::m_unitcount.product::A.time::14_27"]);
+        let report = placebo_tests(&df, &cfg, weighted_difference(&cfg)).unwrap();
+        assert_eq!(
+            vec![PlaceboKind::Primary, PlaceboKind::NegativeControl],
+            report.tests.iter().map(|t| t.kind).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::columns::{optional_weights, series_to_f64, set_ids};
+use crate::field_name::pre_period;
+use crate::stats::{mean, normal_quantile};
+
+///
+/// Power analysis of a planned two-arm test on the eligible population in the matrix.
+///
+/// MDE = (z(1 - α/2) + z(power)) σ √((1 - ρ²) D / (n p (1 - p)))
+///
+/// * σ        standard deviation of the outcome
+/// * ρ        correlation with the pre-period outcome when `cuped` (CUPED variance reduction)
+/// * p        share of the population to be treated
+/// * D        design effect: the Kish effect of the design weights, times 1 - ICC when the
+///   subjects are assigned within matched sets or strata, times 1 + (m - 1) ICC when whole
+///   clusters are assigned, times any `design_effect` given by the user
+///
+/// With a target `mde` the required sample size is reported as well.
+///
+#[derive(Debug, Clone)]
+pub struct PowerCfg {
+    pub outcome: String,
+    pub treatment_share: f64,
+    pub alpha: f64,
+    pub power: f64,
+    pub mde: Option<f64>,
+    pub cuped: bool,
+    pub pre_window: Option<String>,
+    /// matched sets or strata the subjects are assigned within, e.g. `match_id`, `cem_id`,
+    /// `prop_score_bin`
+    pub sets: Option<String>,
+    /// clusters assigned as a whole, e.g. a practice or a territory
+    pub clusters: Option<String>,
+    pub weight: Option<String>,
+    pub design_effect: f64,
+}
+
+impl PowerCfg {
+    pub fn new(outcome: &str, treatment_share: f64) -> Self {
+        PowerCfg {
+            outcome: outcome.to_string(),
+            treatment_share,
+            alpha: 0.05,
+            power: 0.8,
+            mde: None,
+            cuped: false,
+            pre_window: None,
+            sets: None,
+            clusters: None,
+            weight: None,
+            design_effect: 1.0,
+        }
+    }
+    pub fn treatment_share(mut self, share: f64) -> Self {
+        self.treatment_share = share;
+        self
+    }
+    pub fn alpha(mut self, alpha: f64) -> Self {
+        self.alpha = alpha;
+        self
+    }
+    pub fn power(mut self, power: f64) -> Self {
+        self.power = power;
+        self
+    }
+    /// Target effect (outcome units) for the required sample size
+    pub fn mde(mut self, mde: f64) -> Self {
+        self.mde = Some(mde);
+        self
+    }
+    pub fn cuped(mut self) -> Self {
+        self.cuped = true;
+        self
+    }
+    pub fn pre_window(mut self, window: &str) -> Self {
+        self.cuped = true;
+        self.pre_window = Some(window.to_string());
+        self
+    }
+    pub fn sets(mut self, field: &str) -> Self {
+        self.sets = Some(field.to_string());
+        self
+    }
+    pub fn clusters(mut self, field: &str) -> Self {
+        self.clusters = Some(field.to_string());
+        self
+    }
+    pub fn weight(mut self, weight: &str) -> Self {
+        self.weight = Some(weight.to_string());
+        self
+    }
+    pub fn design_effect(mut self, design_effect: f64) -> Self {
+        self.design_effect = design_effect;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PowerFindings {
+    pub outcome: String,
+    pub n: usize,
+    pub treatment_share: f64,
+    pub alpha: f64,
+    pub power: f64,
+    pub mean: f64,
+    pub sd: f64,
+    /// pre/post correlation when CUPED is used
+    pub rho: Option<f64>,
+    pub design_effect: f64,
+    /// minimum detectable effect with the eligible population
+    pub mde: f64,
+    /// subjects needed for the target effect
+    pub required_n: Option<usize>,
+    pub target: Option<f64>,
+}
+
+impl PowerFindings {
+    /// MDE relative to the outcome mean
+    pub fn relative_mde(&self) -> f64 {
+        self.mde / self.mean
+    }
+    pub fn sufficient(&self) -> Option<bool> {
+        self.required_n.map(|required| required <= self.n)
+    }
+}
+impl fmt::Display for PowerFindings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Power for {} (n {}, {:.0}% treated, α {}, power {})",
+            self.outcome,
+            self.n,
+            self.treatment_share * 100.0,
+            self.alpha,
+            self.power
+        )?;
+        writeln!(f, "  mean {:.4}  sd {:.4}", self.mean, self.sd)?;
+        if let Some(rho) = self.rho {
+            writeln!(f, "  pre/post correlation {:.4}", rho)?;
+        }
+        writeln!(f, "  design effect {:.4}", self.design_effect)?;
+        write!(
+            f,
+            "  MDE {:.4} ({:.2}% of the mean)",
+            self.mde,
+            self.relative_mde() * 100.0
+        )?;
+        if let (Some(target), Some(required)) = (self.target, self.required_n) {
+            write!(
+                f,
+                "\n  {} subjects needed for an effect of {} {}",
+                required,
+                target,
+                if required <= self.n { "✅" } else { "⚠️" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// z(1 - α/2) + z(power)
+fn z_total(alpha: f64, power: f64) -> f64 {
+    normal_quantile(1.0 - alpha / 2.0) + normal_quantile(power)
+}
+
+pub fn mde(
+    n: usize,
+    share: f64,
+    sd: f64,
+    rho: f64,
+    design_effect: f64,
+    alpha: f64,
+    power: f64,
+) -> f64 {
+    z_total(alpha, power)
+        * sd
+        * ((1.0 - rho * rho) * design_effect / (n as f64 * share * (1.0 - share))).sqrt()
+}
+
+pub fn required_n(
+    effect: f64,
+    share: f64,
+    sd: f64,
+    rho: f64,
+    design_effect: f64,
+    alpha: f64,
+    power: f64,
+) -> usize {
+    let n = (z_total(alpha, power) * sd / effect).powi(2) * (1.0 - rho * rho) * design_effect
+        / (share * (1.0 - share));
+    n.ceil() as usize
+}
+
+///
+/// Kish design effect of the weights, n Σw² / (Σw)².
+///
+pub fn kish_design_effect(weights: &[f64]) -> f64 {
+    let (sum, squares) = weights
+        .iter()
+        .filter(|w| **w > 0.0)
+        .fold((0.0, 0.0), |(s, q), w| (s + w, q + w * w));
+    let n = weights.iter().filter(|w| **w > 0.0).count() as f64;
+    n * squares / (sum * sum)
+}
+
+///
+/// 1 + (m - 1) ICC, with m the mean cluster size: the variance inflation when whole clusters are
+/// assigned to an arm.  Rows with a null cluster are singletons.
+///
+pub fn cluster_design_effect(y: &[f64], clusters: &[Option<String>]) -> f64 {
+    match icc(y, clusters) {
+        Some((icc, m)) => 1.0 + (m - 1.0) * icc,
+        None => 1.0,
+    }
+}
+
+///
+/// 1 - ICC: var(paired or stratified estimator) / var(unpaired) when the subjects are assigned
+/// within matched sets or strata.  Exact for pairs, where var(y₁ - y₀) = 2σ²(1 - ICC).
+///
+pub fn matched_design_effect(y: &[f64], sets: &[Option<String>]) -> f64 {
+    match icc(y, sets) {
+        Some((icc, _)) => 1.0 - icc,
+        None => 1.0,
+    }
+}
+
+/// ANOVA estimate of the intra-class correlation and the mean group size
+fn icc(y: &[f64], clusters: &[Option<String>]) -> Option<(f64, f64)> {
+    let mut groups: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    let mut singletons = Vec::new();
+    for (y, c) in y.iter().zip(clusters) {
+        match c {
+            Some(c) => groups.entry(c.as_str()).or_default().push(*y),
+            None => singletons.push(vec![*y]),
+        }
+    }
+    let groups: Vec<Vec<f64>> = groups.into_values().chain(singletons).collect();
+    let (n, g) = (y.len() as f64, groups.len() as f64);
+    if g < 2.0 || n <= g {
+        return None;
+    }
+    let grand = mean(y);
+    let between: f64 = groups
+        .iter()
+        .map(|c| c.len() as f64 * (mean(c) - grand).powi(2))
+        .sum::<f64>()
+        / (g - 1.0);
+    let within: f64 = groups
+        .iter()
+        .map(|c| {
+            let m = mean(c);
+            c.iter().map(|y| (y - m).powi(2)).sum::<f64>()
+        })
+        .sum::<f64>()
+        / (n - g);
+    let m0 = (n - groups
+        .iter()
+        .map(|c| (c.len() * c.len()) as f64)
+        .sum::<f64>()
+        / n)
+        / (g - 1.0);
+    let icc = ((between - within) / (between + (m0 - 1.0) * within)).max(0.0);
+    Some((icc, n / g))
+}
+
+/// Weighted mean and standard deviation, n / (n - 1) corrected
+fn weighted_moments(y: &[f64], w: &[f64]) -> (f64, f64) {
+    let total: f64 = w.iter().sum();
+    let m = y.iter().zip(w).map(|(y, w)| y * w).sum::<f64>() / total;
+    let n = y.len() as f64;
+    let ss: f64 = y.iter().zip(w).map(|(y, w)| w * (y - m).powi(2)).sum();
+    (m, (ss / total * n / (n - 1.0)).sqrt())
+}
+fn correlation(a: &[f64], b: &[f64], w: &[f64]) -> f64 {
+    let total: f64 = w.iter().sum();
+    let weighted_mean = |v: &[f64]| v.iter().zip(w).map(|(v, w)| v * w).sum::<f64>() / total;
+    let (ma, mb) = (weighted_mean(a), weighted_mean(b));
+    let (mut cov, mut va, mut vb) = (0.0, 0.0, 0.0);
+    for ((a, b), w) in a.iter().zip(b).zip(w) {
+        cov += w * (a - ma) * (b - mb);
+        va += w * (a - ma).powi(2);
+        vb += w * (b - mb).powi(2);
+    }
+    cov / (va * vb).sqrt()
+}
+
+///
+/// Power of the design on the eligible population: the rows tagged by `with_include_tag` when
+/// the matrix has an `include` column, every row otherwise.  With design weights only the rows
+/// with a positive weight (e.g. the matched subjects) are in the sample, and the moments are
+/// weighted.
+///
+pub fn power(df: &DataFrame, cfg: &PowerCfg) -> Result<PowerFindings> {
+    let df = match df.column("include") {
+        Ok(include) => df.filter(include.bool()?)?,
+        Err(_) => df.clone(),
+    };
+    if cfg.treatment_share <= 0.0 || cfg.treatment_share >= 1.0 {
+        return Err(eyre!(
+            "The treatment share must be in (0, 1), found {}",
+            cfg.treatment_share
+        ));
+    }
+    let (df, w) = match optional_weights(&df, cfg.weight.as_deref())? {
+        Some(weights) => {
+            let kept: Vec<bool> = weights.iter().map(|w| *w > 0.0).collect();
+            let df = df.filter(&BooleanChunked::from_slice("weighted", &kept))?;
+            (df, weights.into_iter().filter(|w| *w > 0.0).collect())
+        }
+        None => {
+            let w = vec![1.0; df.height()];
+            (df, w)
+        }
+    };
+    let y = series_to_f64(df.column(&cfg.outcome)?)?;
+    let n = y.len();
+    if n < 2 {
+        return Err(eyre!("Power needs at least two subjects"));
+    }
+    let (mean, sd) = weighted_moments(&y, &w);
+    let rho = match cfg.cuped {
+        true => {
+            let field = pre_period(
+                &cfg.outcome,
+                cfg.pre_window.as_deref(),
+                &df.get_column_names(),
+            )?;
+            Some(correlation(&y, &series_to_f64(df.column(&field)?)?, &w))
+        }
+        false => None,
+    };
+    let mut design_effect = cfg.design_effect;
+    if cfg.weight.is_some() {
+        design_effect *= kish_design_effect(&w);
+    }
+    if let Some(field) = &cfg.sets {
+        design_effect *= matched_design_effect(&y, &set_ids(&df, field)?);
+    }
+    if let Some(field) = &cfg.clusters {
+        design_effect *= cluster_design_effect(&y, &set_ids(&df, field)?);
+    }
+
+    let r = rho.unwrap_or(0.0);
+    let share = cfg.treatment_share;
+    Ok(PowerFindings {
+        outcome: cfg.outcome.clone(),
+        n,
+        treatment_share: share,
+        alpha: cfg.alpha,
+        power: cfg.power,
+        mean,
+        sd,
+        rho,
+        design_effect,
+        mde: mde(n, share, sd, r, design_effect, cfg.alpha, cfg.power),
+        required_n: cfg
+            .mde
+            .map(|effect| required_n(effect, share, sd, r, design_effect, cfg.alpha, cfg.power)),
+        target: cfg.mde,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mde_and_sample_size_agree() {
+        // the textbook 0.2 sd effect at 80% power needs ~393 per arm
+        let n = required_n(0.2, 0.5, 1.0, 0.0, 1.0, 0.05, 0.8);
+        assert!((785..=786).contains(&n));
+        assert!((mde(n, 0.5, 1.0, 0.0, 1.0, 0.05, 0.8) - 0.2).abs() < 1e-3);
+        // CUPED with ρ = 0.6 saves 36%
+        let cuped = required_n(0.2, 0.5, 1.0, 0.6, 1.0, 0.05, 0.8);
+        assert!((cuped as f64 / n as f64 - 0.64).abs() < 0.01);
+    }
+    #[test]
+    fn test_design_effects() {
+        assert!((kish_design_effect(&[1.0; 10]) - 1.0).abs() < 1e-12);
+        assert!(kish_design_effect(&[1.0, 1.0, 4.0, 0.5]) > 1.0);
+        // identical outcomes within pairs: ICC 1.  Assigning whole pairs doubles the variance;
+        // assigning within the pairs removes it
+        let y: Vec<f64> = (0..20).map(|i| (i / 2) as f64).collect();
+        let pairs: Vec<Option<String>> = (0..20).map(|i| Some((i / 2).to_string())).collect();
+        assert!((cluster_design_effect(&y, &pairs) - 2.0).abs() < 1e-9);
+        assert!(matched_design_effect(&y, &pairs).abs() < 1e-9);
+        // pairs unrelated to the outcome: no gain
+        let y: Vec<f64> = (0..20).map(|i| (i % 2) as f64).collect();
+        assert!((matched_design_effect(&y, &pairs) - 1.0).abs() < 1e-9);
+    }
+    #[test]
+    fn test_power_on_the_weighted_sample() {
+        let rows = 40;
+        let pre: Vec<f64> = (0..rows).map(|i| ((i * 7) % 11) as f64).collect();
+        let post: Vec<f64> = (0..rows).map(|i| pre[i] + ((i * 3) % 5) as f64).collect();
+        // row 0 is not eligible and row 1 was not matched
+        let include: Vec<bool> = (0..rows).map(|i| i != 0).collect();
+        let weight: Vec<f64> = (0..rows).map(|i| if i == 1 { 0.0 } else { 1.0 }).collect();
+        let df = df!(
+            "MeaType::m_unitcount.product::A.time::0_13" => &pre,
+            "MeaType::m_unitcount.product::A.time::14_27" => &post,
+            "include" => include,
+            "match_weight" => weight
+        )
+        .unwrap();
+        let cfg = PowerCfg::new("MeaType::m_unitcount.product::A.time::14_27", 0.5).cuped();
+        let found = power(&df, &cfg.clone().weight("match_weight")).unwrap();
+
+        // the same as the unweighted power of the 38 matched subjects
+        let matched = df.slice(2, rows - 2).drop("match_weight").unwrap();
+        let expected = power(&matched, &cfg).unwrap();
+        assert_eq!((38, 38), (found.n, expected.n));
+        assert!((found.sd - expected.sd).abs() < 1e-12);
+        assert!((found.rho.unwrap() - expected.rho.unwrap()).abs() < 1e-12);
+        assert!(found.rho.unwrap() > 0.5);
+        assert!((found.mde - expected.mde).abs() < 1e-12);
+        assert!((found.design_effect - 1.0).abs() < 1e-12);
+    }
+}
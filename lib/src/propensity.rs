@@ -1,3 +1,7 @@
+use crate::cross_fit::CrossFitCfg;
+use crate::formula::ModelDef;
+use crate::propensity_model::{Metrics, ModelFamily};
+use crate::terms::Term;
 use crate::Mask;
 use color_eyre::eyre::Result;
 use polars::prelude::*;
@@ -18,6 +22,13 @@ pub struct PropensityCfg {
     // pub mask: Option<Mask<'a>>,
     pub bin_count: u32,
     pub name: String,
+    /// Score each subject out-of-fold when set; otherwise score in-sample.
+    pub cross_fit: Option<CrossFitCfg>,
+    pub model: ModelFamily,
+    /// interaction, polynomial and spline terms added to the design matrix
+    pub terms: Vec<Term>,
+    /// predictors expanded to dummies regardless of their dtype
+    pub categorical: Vec<String>,
 }
 // Some(self.column("include").unwrap().bool().unwrap()),
 
@@ -30,6 +41,10 @@ pub struct PropensityCfgBuilder<'a> {
     mask: Option<Mask<'a>>,
     bin_count: u32,
     name: &'a str,
+    cross_fit: Option<CrossFitCfg>,
+    model: ModelFamily,
+    terms: Vec<Term>,
+    categorical: Vec<&'a str>,
 }
 
 impl<'a> PropensityCfgBuilder<'a> {
@@ -40,6 +55,10 @@ impl<'a> PropensityCfgBuilder<'a> {
             mask: None,
             bin_count: 5,
             name: "propensity",
+            cross_fit: None,
+            model: ModelFamily::default(),
+            terms: Vec::new(),
+            categorical: Vec::new(),
         }
     }
 
@@ -53,6 +72,30 @@ impl<'a> PropensityCfgBuilder<'a> {
         self
     }
 
+    /// Score with a model fitted on the other `folds - 1` folds.
+    pub fn cross_fit(mut self, folds: usize, seed: u64) -> Self {
+        self.cross_fit = Some(CrossFitCfg { folds, seed });
+        self
+    }
+
+    /// Model family for treatment assignment (default: logit)
+    pub fn model(mut self, model: ModelFamily) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// See [`crate::terms::parse_terms`]
+    pub fn terms(mut self, terms: Vec<Term>) -> Self {
+        self.terms = terms;
+        self
+    }
+
+    /// Treat these predictors as categorical (dummies) even when numeric
+    pub fn categorical(mut self, categorical: Vec<&'a str>) -> Self {
+        self.categorical = categorical;
+        self
+    }
+
     ///
     /// Todo: Used Owned versions of the builder types
     ///
@@ -65,10 +108,33 @@ impl<'a> PropensityCfgBuilder<'a> {
             // mask: self.mask,
             bin_count: self.bin_count,
             name,
+            cross_fit: self.cross_fit,
+            model: self.model,
+            terms: self.terms,
+            categorical: self.categorical.iter().map(|c| c.to_string()).collect(),
         }
     }
 }
 
+///
+/// In-sample scores with the fit diagnostics.  See [`crate::cross_fit::CrossFitFindings`] for the
+/// out-of-fold version.
+///
+#[derive(Debug, Clone)]
+pub struct PropensityFindings {
+    pub model: &'static str,
+    pub scores: Vec<f64>,
+    pub metrics: Metrics,
+}
+impl PropensityFindings {
+    pub fn report(&self) -> String {
+        format!(
+            "{} propensity (in-sample)\n  {}\n",
+            self.model, self.metrics
+        )
+    }
+}
+
 ///
 /// Entry point for building the PropensityCfg.  See exit: .build().
 ///
@@ -79,9 +145,20 @@ impl PropensityCfg {
     ) -> PropensityCfgBuilder<'a> {
         PropensityCfgBuilder::new(target, predictors)
     }
+    ///
+    /// Start the builder from a parsed formula (see [`crate::formula::ModelDef::parse`]).
+    ///
+    pub fn from_model<'a>(model: &ModelDef<'a>) -> PropensityCfgBuilder<'a> {
+        PropensityCfgBuilder::new(model.target().into(), model.predictors().to_vec().into())
+            .terms(model.terms().to_vec())
+            .categorical(model.categorical().to_vec())
+    }
     pub fn bin_name(&self) -> String {
         self.name.to_owned() + "_bin"
     }
+    pub fn fold_name(&self) -> String {
+        self.name.to_owned() + "_fold"
+    }
 }
 ///
 /// Owned versions for use in the final configuration. Required b/c configuration cannot borrow
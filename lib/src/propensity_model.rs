@@ -0,0 +1,198 @@
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use propensity_score::prelude::*;
+
+use crate::forest::{Forest, ForestCfg};
+use crate::glm::{GlmFit, IrlsCfg, Link};
+use crate::stats::{auc, brier, log_loss};
+
+///
+/// A model of treatment assignment.  Fits on the row-dominant X (with bias slot) and binary y,
+/// returning something that can score any X with the same column layout.
+///
+/// Every family produces a probability in [0, 1] so that the score column, the bins and the
+/// [`Metrics`] are the same regardless of the family.
+///
+pub trait PropensityModel: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn fit(&self, x: &[f64], y: &[f64], rows: usize) -> Result<Box<dyn FittedPropensity>>;
+    /// In-sample scores of the rows the model is fitted on
+    fn scores(&self, x: &[f64], y: &[f64], rows: usize) -> Result<Vec<f64>> {
+        Ok(self.fit(x, y, rows)?.predict(x))
+    }
+}
+
+pub trait FittedPropensity: Send + Sync {
+    /// Probability scores for the row-dominant X
+    fn predict(&self, x: &[f64]) -> Vec<f64>;
+}
+
+///
+/// Select the model family in the propensity configuration.
+///
+/// # Example
+///
+/// ```
+/// use tnc_analysis_lib::prelude::ModelFamily;
+///
+/// let model: ModelFamily = serde_json::from_str(r#"{ "type": "Probit" }"#).unwrap();
+/// assert!(model == ModelFamily::Probit);
+///
+/// let json = r#"{ "type": "RandomForest", "trees": 200, "max-depth": 6, "min-leaf": 20, "seed": 1 }"#;
+/// let model: ModelFamily = serde_json::from_str(&json).unwrap();
+/// assert!(matches!(model, ModelFamily::RandomForest { trees: 200, .. }));
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ModelFamily {
+    #[default]
+    Logit,
+    Probit,
+    RandomForest {
+        trees: usize,
+        #[serde(rename = "max-depth")]
+        max_depth: usize,
+        #[serde(rename = "min-leaf")]
+        min_leaf: usize,
+        #[serde(default)]
+        mtry: Option<usize>,
+        seed: u64,
+    },
+}
+impl ModelFamily {
+    pub fn model(&self) -> Box<dyn PropensityModel> {
+        match self {
+            ModelFamily::Logit => Box::new(Logit),
+            ModelFamily::Probit => Box::new(Glm(Link::Probit)),
+            ModelFamily::RandomForest {
+                trees,
+                max_depth,
+                min_leaf,
+                mtry,
+                seed,
+            } => Box::new(RandomForest(ForestCfg {
+                trees: *trees,
+                max_depth: *max_depth,
+                min_leaf: *min_leaf,
+                mtry: *mtry,
+                seed: *seed,
+            })),
+        }
+    }
+}
+
+///
+/// The `propensity_score` logit for the in-sample scores.  That optimizer only predicts on the
+/// rows it was fitted with, so held-out rows (cross-fitting) are scored with the IRLS fit.
+///
+#[derive(Debug)]
+pub struct Logit;
+
+impl PropensityModel for Logit {
+    fn name(&self) -> &'static str {
+        "logit"
+    }
+    fn fit(&self, x: &[f64], y: &[f64], rows: usize) -> Result<Box<dyn FittedPropensity>> {
+        Glm(Link::Logit).fit(x, y, rows)
+    }
+    fn scores(&self, x: &[f64], y: &[f64], rows: usize) -> Result<Vec<f64>> {
+        let objective = Objective::from_vecs(x.to_vec(), y.to_vec(), rows)?;
+
+        let optimizer_cfg = CfgBuilder::new().max_iters(100).logging(false).build();
+
+        let findings = logit::run(&objective, optimizer_cfg)?;
+
+        event!(Level::INFO, "\n📋 logit findings\n{}", findings.report()?);
+
+        Ok(Vec::from(
+            findings.predict(false), // binary = false, show_sample
+        ))
+    }
+}
+
+/// Logit and probit fitted with IRLS
+#[derive(Debug)]
+pub struct Glm(pub Link);
+
+impl PropensityModel for Glm {
+    fn name(&self) -> &'static str {
+        match self.0 {
+            Link::Logit => "logit",
+            Link::Probit => "probit",
+        }
+    }
+    fn fit(&self, x: &[f64], y: &[f64], rows: usize) -> Result<Box<dyn FittedPropensity>> {
+        let fit = GlmFit::fit(x, y, rows, self.0, &IrlsCfg::default())?;
+        if !fit.converged {
+            event!(
+                Level::WARN,
+                "⚠️  {} did not converge after {} iterations",
+                self.name(),
+                fit.iterations
+            );
+        }
+        Ok(Box::new(fit))
+    }
+}
+impl FittedPropensity for GlmFit {
+    fn predict(&self, x: &[f64]) -> Vec<f64> {
+        GlmFit::predict(self, x)
+    }
+}
+
+#[derive(Debug)]
+pub struct RandomForest(pub ForestCfg);
+
+struct FittedForest {
+    forest: Forest,
+    cols: usize,
+}
+impl PropensityModel for RandomForest {
+    fn name(&self) -> &'static str {
+        "random forest"
+    }
+    fn fit(&self, x: &[f64], y: &[f64], rows: usize) -> Result<Box<dyn FittedPropensity>> {
+        let forest = Forest::fit(x, y, rows, &self.0)?;
+        Ok(Box::new(FittedForest {
+            forest,
+            cols: x.len() / rows,
+        }))
+    }
+}
+impl FittedPropensity for FittedForest {
+    fn predict(&self, x: &[f64]) -> Vec<f64> {
+        self.forest.predict(x, self.cols)
+    }
+}
+
+///
+/// Fit diagnostics shared by every model family.
+///
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub n: usize,
+    pub log_loss: f64,
+    pub brier: f64,
+    pub auc: f64,
+}
+impl Metrics {
+    pub fn new(y: &[f64], scores: &[f64]) -> Self {
+        Metrics {
+            n: y.len(),
+            log_loss: log_loss(y, scores),
+            brier: brier(y, scores),
+            auc: auc(y, scores),
+        }
+    }
+}
+impl std::fmt::Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "n: {:>8}  log-loss: {:.4}  brier: {:.4}  auc: {:.4}",
+            self.n, self.log_loss, self.brier, self.auc
+        )
+    }
+}
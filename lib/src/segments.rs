@@ -0,0 +1,376 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::columns::set_ids;
+use crate::effect::{adjusted_effect, Effect, EffectCfg};
+use crate::stats::{benjamini_hochberg, chi_square_sf, holm, quantile_bins};
+
+///
+/// How the segment field splits the matrix.
+///
+/// * `Categories`      one segment per value, e.g. `q_specialty`, `q_state`
+/// * `Quantiles(k)`    k equal-count bins of a numeric field, e.g. 10 for deciles
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Segmentation {
+    #[default]
+    Categories,
+    Quantiles(usize),
+}
+
+///
+/// Adjustment of the segment p-values for the number of segments.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Correction {
+    None,
+    /// family-wise error rate
+    #[default]
+    Holm,
+    /// false discovery rate
+    BenjaminiHochberg,
+}
+
+impl Correction {
+    pub fn adjust(&self, p: &[f64]) -> Vec<f64> {
+        match self {
+            Correction::None => p.to_vec(),
+            Correction::Holm => holm(p),
+            Correction::BenjaminiHochberg => benjamini_hochberg(p),
+        }
+    }
+}
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Correction::None => write!(f, "none"),
+            Correction::Holm => write!(f, "Holm"),
+            Correction::BenjaminiHochberg => write!(f, "Benjamini-Hochberg"),
+        }
+    }
+}
+
+///
+/// Lift by segment.  The effect configuration (weights, CUPED, covariates) is reused within each
+/// segment; segments where the estimator fails (e.g. fewer than two treated subjects) are
+/// skipped and listed in the report.
+///
+#[derive(Debug, Clone)]
+pub struct SegmentCfg {
+    pub effect: EffectCfg,
+    pub segment: String,
+    pub segmentation: Segmentation,
+    pub correction: Correction,
+    pub alpha: f64,
+}
+
+impl SegmentCfg {
+    pub fn new(effect: EffectCfg, segment: &str) -> Self {
+        SegmentCfg {
+            effect,
+            segment: segment.to_string(),
+            segmentation: Segmentation::default(),
+            correction: Correction::default(),
+            alpha: 0.05,
+        }
+    }
+    pub fn segmentation(mut self, segmentation: Segmentation) -> Self {
+        self.segmentation = segmentation;
+        self
+    }
+    pub fn deciles(self) -> Self {
+        self.segmentation(Segmentation::Quantiles(10))
+    }
+    pub fn correction(mut self, correction: Correction) -> Self {
+        self.correction = correction;
+        self
+    }
+    pub fn alpha(mut self, alpha: f64) -> Self {
+        self.alpha = alpha;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentEffect {
+    pub segment: String,
+    pub effect: Effect,
+    pub p_adjusted: f64,
+}
+
+///
+/// Segment effects with the Cochran Q test of heterogeneity: Q = Σ wᵢ (θᵢ - θ̄)² with
+/// wᵢ = 1 / seᵢ² and θ̄ the inverse-variance weighted mean, χ² with k - 1 degrees of freedom
+/// under a common effect.  I² is the share of the variation beyond chance.
+///
+#[derive(Debug, Clone)]
+pub struct SegmentReport {
+    pub field: String,
+    pub segments: Vec<SegmentEffect>,
+    pub skipped: Vec<String>,
+    pub pooled: f64,
+    pub q: f64,
+    pub q_p_value: f64,
+    pub i_squared: f64,
+    pub correction: Correction,
+    pub alpha: f64,
+}
+
+impl SegmentReport {
+    pub fn significant(&self) -> impl Iterator<Item = &SegmentEffect> {
+        self.segments.iter().filter(|s| s.p_adjusted < self.alpha)
+    }
+    pub fn heterogeneous(&self) -> bool {
+        self.q_p_value < self.alpha
+    }
+    ///
+    /// `segment`, `estimate`, `se`, `lower`, `upper`, `treated`, `controls`, `p_value`,
+    /// `p_adjusted`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let names: Vec<&str> = self.segments.iter().map(|s| s.segment.as_str()).collect();
+        let column = |f: &dyn Fn(&SegmentEffect) -> f64| -> Vec<f64> {
+            self.segments.iter().map(f).collect()
+        };
+        let count = |f: fn(&Effect) -> usize| -> Vec<u32> {
+            self.segments.iter().map(|s| f(&s.effect) as u32).collect()
+        };
+        let level = 1.0 - self.alpha;
+        Ok(DataFrame::new(vec![
+            Series::new("segment", names),
+            Series::new("estimate", column(&|s| s.effect.estimate)),
+            Series::new("se", column(&|s| s.effect.se)),
+            Series::new("lower", column(&|s| s.effect.ci(level).0)),
+            Series::new("upper", column(&|s| s.effect.ci(level).1)),
+            Series::new("treated", count(|e| e.treated)),
+            Series::new("controls", count(|e| e.controls)),
+            Series::new("p_value", column(&|s| s.effect.p_value())),
+            Series::new("p_adjusted", column(&|s| s.p_adjusted)),
+        ])?)
+    }
+}
+impl fmt::Display for SegmentReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Lift by {} ({} segments, {} p-values)",
+            self.field,
+            self.segments.len(),
+            self.correction
+        )?;
+        let level = 1.0 - self.alpha;
+        for s in &self.segments {
+            let (lo, hi) = s.effect.ci(level);
+            writeln!(
+                f,
+                "  {:<24} {:>10.4} [{:.4}, {:.4}]  n {}/{}  p {:.4}  adjusted {:.4} {}",
+                s.segment,
+                s.effect.estimate,
+                lo,
+                hi,
+                s.effect.treated,
+                s.effect.controls,
+                s.effect.p_value(),
+                s.p_adjusted,
+                if s.p_adjusted < self.alpha { "✅" } else { "" }
+            )?;
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "  skipped: {}", self.skipped.join(", "))?;
+        }
+        write!(
+            f,
+            "  heterogeneity Q {:.4} (df {}, p {:.4}), I² {:.1}%, pooled {:.4} {}",
+            self.q,
+            self.segments.len().saturating_sub(1),
+            self.q_p_value,
+            self.i_squared * 100.0,
+            self.pooled,
+            if self.heterogeneous() { "⚠️" } else { "" }
+        )
+    }
+}
+
+///
+/// Segment label of each row; rows with a null value belong to no segment.
+///
+fn labels(df: &DataFrame, field: &str, segmentation: Segmentation) -> Result<Vec<Option<String>>> {
+    match segmentation {
+        Segmentation::Categories => set_ids(df, field),
+        Segmentation::Quantiles(k) => {
+            let column = df.column(field)?.cast(&DataType::Float64)?;
+            let values: Vec<Option<f64>> = column.f64()?.into_iter().collect();
+            let present: Vec<f64> = values.iter().flatten().copied().collect();
+            let width = k.to_string().len();
+            let mut bins = quantile_bins(&present, k).into_iter();
+            Ok(values
+                .iter()
+                .map(|v| {
+                    v.and_then(|_| bins.next())
+                        .map(|bin| format!("{:0width$}/{}", bin, k, width = width))
+                })
+                .collect())
+        }
+    }
+}
+
+///
+/// Rerun `estimator` on the rows of each segment.
+///
+pub fn segmented_effects<F>(df: &DataFrame, cfg: &SegmentCfg, estimator: F) -> Result<SegmentReport>
+where
+    F: Fn(&DataFrame) -> Result<Effect>,
+{
+    let mut groups: BTreeMap<String, Vec<IdxSize>> = BTreeMap::new();
+    for (row, label) in labels(df, &cfg.segment, cfg.segmentation)?
+        .into_iter()
+        .enumerate()
+    {
+        if let Some(label) = label {
+            groups.entry(label).or_default().push(row as IdxSize);
+        }
+    }
+
+    let mut effects = Vec::new();
+    let mut skipped = Vec::new();
+    for (segment, rows) in groups {
+        let sample = df.take(&IdxCa::from_vec("", rows))?;
+        match estimator(&sample) {
+            Ok(effect) if effect.se > 0.0 && effect.se.is_finite() => {
+                effects.push((segment, effect))
+            }
+            Ok(_) => skipped.push(segment),
+            Err(e) => {
+                event!(Level::DEBUG, "segment {} skipped: {}", segment, e);
+                skipped.push(segment)
+            }
+        }
+    }
+    if effects.is_empty() {
+        return Err(eyre!("No segment of {} could be estimated", cfg.segment));
+    }
+    if !skipped.is_empty() {
+        event!(
+            Level::WARN,
+            "⚠️ {} segments of {} skipped",
+            skipped.len(),
+            cfg.segment
+        );
+    }
+
+    let w: Vec<f64> = effects.iter().map(|(_, e)| 1.0 / (e.se * e.se)).collect();
+    let pooled = effects
+        .iter()
+        .zip(&w)
+        .map(|((_, e), w)| w * e.estimate)
+        .sum::<f64>()
+        / w.iter().sum::<f64>();
+    let q: f64 = effects
+        .iter()
+        .zip(&w)
+        .map(|((_, e), w)| w * (e.estimate - pooled).powi(2))
+        .sum();
+    let df_q = (effects.len() - 1) as f64;
+    let (q_p_value, i_squared) = match df_q > 0.0 {
+        true => (chi_square_sf(q, df_q), ((q - df_q) / q).max(0.0)),
+        false => (1.0, 0.0),
+    };
+
+    let p: Vec<f64> = effects.iter().map(|(_, e)| e.p_value()).collect();
+    let segments = effects
+        .into_iter()
+        .zip(cfg.correction.adjust(&p))
+        .map(|((segment, effect), p_adjusted)| SegmentEffect {
+            segment,
+            effect,
+            p_adjusted,
+        })
+        .collect();
+
+    Ok(SegmentReport {
+        field: cfg.segment.clone(),
+        segments,
+        skipped,
+        pooled,
+        q,
+        q_p_value,
+        i_squared,
+        correction: cfg.correction,
+        alpha: cfg.alpha,
+    })
+}
+
+///
+/// The default estimator: the configured (adjusted) effect.
+///
+pub fn configured_effect(cfg: &EffectCfg) -> impl Fn(&DataFrame) -> Result<Effect> + '_ {
+    move |df| Ok(adjusted_effect(df, cfg)?.adjusted)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample() -> DataFrame {
+        let rows = 600;
+        let segment: Vec<&str> = (0..rows).map(|i| ["a", "b", "c"][(i / 2) % 3]).collect();
+        let treated: Vec<f64> = (0..rows).map(|i| (i % 2) as f64).collect();
+        // the lift is 0 in a, 1 in b and 3 in c
+        let y: Vec<f64> = (0..rows)
+            .map(|i| {
+                let lift = [0.0, 1.0, 3.0][(i / 2) % 3];
+                lift * treated[i] + ((i * 37) % 11) as f64 / 5.0
+            })
+            .collect();
+        df!("q_segment" => segment, "treated" => treated, "y" => y).unwrap()
+    }
+    #[test]
+    fn test_segments_detect_heterogeneity() {
+        let df = sample();
+        let cfg = SegmentCfg::new(EffectCfg::new("treated", "y"), "q_segment");
+        let report = segmented_effects(&df, &cfg, configured_effect(&cfg.effect)).unwrap();
+
+        assert_eq!(3, report.segments.len());
+        assert!((report.segments[2].effect.estimate - 3.0).abs() < 0.3);
+        assert!(report.heterogeneous());
+        assert!(report.i_squared > 0.9);
+        assert!(report
+            .segments
+            .iter()
+            .all(|s| s.p_adjusted >= s.effect.p_value()));
+        assert_eq!(2, report.significant().count());
+        assert_eq!(3, report.to_dataframe().unwrap().height());
+    }
+    #[test]
+    fn test_decile_labels() {
+        let df = df!("v" => (0..100).map(|i| i as f64).collect::<Vec<_>>()).unwrap();
+        let labels = labels(&df, "v", Segmentation::Quantiles(10)).unwrap();
+        assert_eq!(Some("01/10".to_string()), labels[0]);
+        assert_eq!(Some("10/10".to_string()), labels[99]);
+        assert_eq!(
+            10,
+            labels
+                .iter()
+                .filter(|l| l.as_deref() == Some("05/10"))
+                .count()
+        );
+
+        // the nulls are in no segment; the quartiles are of the other values
+        let df = df!("v" => [Some(1.0), None, Some(2.0), Some(3.0), None, Some(4.0)]).unwrap();
+        let quartiles = super::labels(&df, "v", Segmentation::Quantiles(4)).unwrap();
+        assert_eq!(
+            vec![
+                Some("1/4"),
+                None,
+                Some("2/4"),
+                Some("3/4"),
+                None,
+                Some("4/4")
+            ],
+            quartiles.iter().map(|l| l.as_deref()).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::stats::{mean, normal_cdf, ranks, variance};
+
+// -------------------------------------------------------------------------------------------------
+// Rosenbaum bounds
+///
+/// Sensitivity of a matched design to hidden bias.  Γ is the most the odds of treatment may
+/// differ between two matched subjects because of an unmeasured confounder; Γ = 1 is the
+/// randomization test.
+///
+/// For each Γ: the bounds on the one-sided p-value of the Wilcoxon signed-rank test (lift > 0)
+/// and on the Hodges–Lehmann estimate of the lift.
+///
+#[derive(Debug, Clone)]
+pub struct RosenbaumBound {
+    pub gamma: f64,
+    pub p_lower: f64,
+    pub p_upper: f64,
+    pub hl_lower: f64,
+    pub hl_upper: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RosenbaumBounds {
+    /// matched sets with a treated and a control outcome
+    pub pairs: usize,
+    pub bounds: Vec<RosenbaumBound>,
+}
+
+impl RosenbaumBounds {
+    ///
+    /// `differences` holds treated minus control outcome per matched set.
+    ///
+    pub fn new(differences: &[f64], gammas: &[f64]) -> Result<Self> {
+        if differences.is_empty() {
+            return Err(eyre!("Rosenbaum bounds require at least one matched pair"));
+        }
+        if let Some(gamma) = gammas.iter().find(|g| **g < 1.0) {
+            return Err(eyre!("Γ must be >= 1, found {}", gamma));
+        }
+        let bounds = gammas
+            .iter()
+            .map(|gamma| {
+                // the chance a pair favours treatment ranges over [1 / (1 + Γ), Γ / (1 + Γ)]
+                let (upper, lower) = (gamma / (1.0 + gamma), 1.0 / (1.0 + gamma));
+                RosenbaumBound {
+                    gamma: *gamma,
+                    p_lower: signed_rank_p(differences, lower),
+                    p_upper: signed_rank_p(differences, upper),
+                    hl_lower: hodges_lehmann(differences, upper),
+                    hl_upper: hodges_lehmann(differences, lower),
+                }
+            })
+            .collect();
+        Ok(RosenbaumBounds {
+            pairs: differences.len(),
+            bounds,
+        })
+    }
+    /// Smallest Γ at which the upper p-value bound reaches alpha (the design's sensitivity)
+    pub fn critical_gamma(&self, alpha: f64) -> Option<f64> {
+        self.bounds
+            .iter()
+            .find(|b| b.p_upper >= alpha)
+            .map(|b| b.gamma)
+    }
+    ///
+    /// `gamma`, `p_lower`, `p_upper`, `hl_lower`, `hl_upper`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let column =
+            |f: fn(&RosenbaumBound) -> f64| -> Vec<f64> { self.bounds.iter().map(f).collect() };
+        Ok(DataFrame::new(vec![
+            Series::new("gamma", column(|b| b.gamma)),
+            Series::new("p_lower", column(|b| b.p_lower)),
+            Series::new("p_upper", column(|b| b.p_upper)),
+            Series::new("hl_lower", column(|b| b.hl_lower)),
+            Series::new("hl_upper", column(|b| b.hl_upper)),
+        ])?)
+    }
+}
+impl fmt::Display for RosenbaumBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Rosenbaum bounds ({} matched sets)", self.pairs)?;
+        writeln!(
+            f,
+            "  {:>6} {:>10} {:>10} {:>10} {:>10}",
+            "Γ", "p min", "p max", "HL min", "HL max"
+        )?;
+        for b in &self.bounds {
+            writeln!(
+                f,
+                "  {:>6.2} {:>10.4} {:>10.4} {:>10.4} {:>10.4}",
+                b.gamma, b.p_lower, b.p_upper, b.hl_lower, b.hl_upper
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Signed-rank statistic T+ with the ranks of the non-zero |d|
+fn signed_rank(differences: &[f64]) -> (f64, Vec<f64>) {
+    let nonzero: Vec<f64> = differences.iter().copied().filter(|d| *d != 0.0).collect();
+    let magnitudes: Vec<f64> = nonzero.iter().map(|d| d.abs()).collect();
+    let ranks = ranks(&magnitudes);
+    let t = nonzero
+        .iter()
+        .zip(&ranks)
+        .filter(|(d, _)| **d > 0.0)
+        .map(|(_, r)| r)
+        .sum();
+    (t, ranks)
+}
+
+/// One-sided p-value of T+ when each pair is positive with probability `p` (normal approximation)
+fn signed_rank_p(differences: &[f64], p: f64) -> f64 {
+    let (t, ranks) = signed_rank(differences);
+    if ranks.is_empty() {
+        return 1.0;
+    }
+    let expected = p * ranks.iter().sum::<f64>();
+    let var = p * (1.0 - p) * ranks.iter().map(|r| r * r).sum::<f64>();
+    1.0 - normal_cdf((t - expected) / var.sqrt())
+}
+
+/// The shift τ at which T+(d - τ) equals its expectation when pairs are positive with probability p
+fn hodges_lehmann(differences: &[f64], p: f64) -> f64 {
+    let excess = |tau: f64| -> f64 {
+        let shifted: Vec<f64> = differences.iter().map(|d| d - tau).collect();
+        let (t, ranks) = signed_rank(&shifted);
+        t - p * ranks.iter().sum::<f64>()
+    };
+    let (mut lo, mut hi) = differences
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), d| {
+            (lo.min(*d), hi.max(*d))
+        });
+    // excess is non-increasing in τ
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        match excess(mid) > 0.0 {
+            true => lo = mid,
+            false => hi = mid,
+        }
+        if hi - lo < 1e-10 * (1.0 + hi.abs()) {
+            break;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+///
+/// Treated minus control mean outcome per matched set (sets missing either side are skipped).
+///
+pub fn set_differences(treatment: &[f64], outcome: &[f64], sets: &[Option<String>]) -> Vec<f64> {
+    let mut by_set: BTreeMap<&str, (Vec<f64>, Vec<f64>)> = BTreeMap::new();
+    for ((t, y), set) in treatment.iter().zip(outcome).zip(sets) {
+        if let Some(set) = set {
+            let entry = by_set.entry(set.as_str()).or_default();
+            match *t > 0.5 {
+                true => entry.0.push(*y),
+                false => entry.1.push(*y),
+            }
+        }
+    }
+    by_set
+        .values()
+        .filter(|(t, c)| !t.is_empty() && !c.is_empty())
+        .map(|(t, c)| mean(t) - mean(c))
+        .collect()
+}
+
+// -------------------------------------------------------------------------------------------------
+// E-values
+///
+/// How strongly (on the risk ratio scale) an unmeasured confounder would need to be associated
+/// with both treatment and outcome to explain away the effect (VanderWeele & Ding, 2017).
+///
+/// Binary outcomes use the risk ratio of the (weighted) means; continuous outcomes use the
+/// standardized difference d with RR ≈ exp(0.91·d).  The interval uses the Kish effective
+/// sample size of each arm.
+///
+#[derive(Debug, Clone)]
+pub struct EValue {
+    pub risk_ratio: f64,
+    pub ci: (f64, f64),
+    /// E-value of the point estimate
+    pub point: f64,
+    /// E-value of the confidence limit closest to 1 (1 when the interval includes 1)
+    pub limit: f64,
+}
+
+impl EValue {
+    pub fn from_risk_ratio(risk_ratio: f64, ci: (f64, f64)) -> Self {
+        let limit = match (ci.0 > 1.0, ci.1 < 1.0) {
+            (true, _) => e_value(ci.0),
+            (_, true) => e_value(ci.1),
+            _ => 1.0,
+        };
+        EValue {
+            risk_ratio,
+            ci,
+            point: e_value(risk_ratio),
+            limit,
+        }
+    }
+    ///
+    /// From the outcome of each subject, the treatment and optional (IPW) weights.
+    ///
+    pub fn new(treatment: &[f64], outcome: &[f64], weights: Option<&[f64]>) -> Result<Self> {
+        let ones = vec![1.0; outcome.len()];
+        let weights = weights.unwrap_or(&ones);
+        let arm = |treated: bool| -> (Vec<f64>, Vec<f64>) {
+            treatment
+                .iter()
+                .zip(outcome.iter().zip(weights))
+                .filter(|(t, _)| (**t > 0.5) == treated)
+                .map(|(_, (y, w))| (*y, *w))
+                .unzip()
+        };
+        let ((y1, w1), (y0, w0)) = (arm(true), arm(false));
+        if y1.is_empty() || y0.is_empty() {
+            return Err(eyre!("E-values require treated and control subjects"));
+        }
+        let (m1, n1) = weighted_mean(&y1, &w1);
+        let (m0, n0) = weighted_mean(&y0, &w0);
+        let z = 1.959_963_984_540_054;
+
+        let binary = outcome.iter().all(|y| *y == 0.0 || *y == 1.0);
+        let (risk_ratio, ci) = match binary {
+            true => {
+                if m0 <= 0.0 || m1 <= 0.0 {
+                    return Err(eyre!("E-values require events in both arms"));
+                }
+                let se = ((1.0 - m1) / (n1 * m1) + (1.0 - m0) / (n0 * m0)).sqrt();
+                let log_rr = (m1 / m0).ln();
+                (m1 / m0, ((log_rr - z * se).exp(), (log_rr + z * se).exp()))
+            }
+            false => {
+                let sd = ((variance(&y1) + variance(&y0)) / 2.0).sqrt();
+                if sd.is_nan() || sd <= 0.0 {
+                    return Err(eyre!("E-values require outcome variation"));
+                }
+                let d = (m1 - m0) / sd;
+                let se = (1.0 / n1 + 1.0 / n0).sqrt();
+                let rr = |d: f64| (0.91 * d).exp();
+                (rr(d), (rr(d - z * se), rr(d + z * se)))
+            }
+        };
+        Ok(EValue::from_risk_ratio(risk_ratio, ci))
+    }
+}
+impl fmt::Display for EValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "E-value: risk ratio {:.3} [{:.3}, {:.3}]",
+            self.risk_ratio, self.ci.0, self.ci.1
+        )?;
+        writeln!(f, "  point estimate:  {:.3}", self.point)?;
+        write!(f, "  confidence limit: {:.3}", self.limit)
+    }
+}
+
+/// RR + sqrt(RR·(RR - 1)), after inverting protective ratios
+fn e_value(risk_ratio: f64) -> f64 {
+    let rr = match risk_ratio < 1.0 {
+        true => 1.0 / risk_ratio,
+        false => risk_ratio,
+    };
+    rr + (rr * (rr - 1.0)).sqrt()
+}
+
+/// (weighted mean, Kish effective sample size)
+fn weighted_mean(values: &[f64], weights: &[f64]) -> (f64, f64) {
+    let total: f64 = weights.iter().sum();
+    let squares: f64 = weights.iter().map(|w| w * w).sum();
+    (
+        values.iter().zip(weights).map(|(v, w)| v * w).sum::<f64>() / total,
+        total * total / squares,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rosenbaum_bounds_widen_with_gamma() {
+        let differences: Vec<f64> = (0..60)
+            .map(|i| 1.0 + ((i * 13) % 9) as f64 / 2.0 - 2.0)
+            .collect();
+        let found = RosenbaumBounds::new(&differences, &[1.0, 1.5, 2.0, 4.0]).unwrap();
+        let first = &found.bounds[0];
+        assert!((first.p_lower - first.p_upper).abs() < 1e-12);
+        assert!((first.hl_lower - first.hl_upper).abs() < 1e-6);
+        for pair in found.bounds.windows(2) {
+            assert!(pair[1].p_upper >= pair[0].p_upper);
+            assert!(pair[1].hl_lower <= pair[0].hl_lower);
+        }
+        assert!(first.p_upper < 0.05);
+        assert!(found.critical_gamma(0.05).is_some());
+    }
+    #[test]
+    fn test_e_value() {
+        // VanderWeele & Ding: RR 3.9 gives an E-value of 7.26
+        assert!((e_value(3.9) - 7.263).abs() < 1e-3);
+        assert!((e_value(1.0 / 3.9) - 7.263).abs() < 1e-3);
+        let found = EValue::from_risk_ratio(1.2, (0.9, 1.6));
+        assert_eq!(1.0, found.limit);
+    }
+}
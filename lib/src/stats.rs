@@ -0,0 +1,433 @@
+use color_eyre::eyre::{eyre, Result};
+
+// -------------------------------------------------------------------------------------------------
+// Small numeric helpers shared by the estimators.  Everything works on plain slices so that the
+// polars specific work stays in the matrix module.
+// -------------------------------------------------------------------------------------------------
+pub fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+/// Sample variance (n - 1)
+pub fn variance(values: &[f64]) -> f64 {
+    if values.len() < 2 {
+        return f64::NAN;
+    }
+    let m = mean(values);
+    values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (values.len() - 1) as f64
+}
+///
+/// Quantile with linear interpolation between order statistics (type 7).
+///
+pub fn quantile(values: &[f64], q: f64) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let h = (sorted.len() - 1) as f64 * q.clamp(0.0, 1.0);
+    let (lo, hi) = (h.floor() as usize, h.ceil() as usize);
+    sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo])
+}
+///
+/// 1-based equal-count bin of each value (1..=k) with cut points at the k-quantiles.
+///
+pub fn quantile_bins(values: &[f64], k: usize) -> Vec<usize> {
+    let cuts: Vec<f64> = (1..k)
+        .map(|i| quantile(values, i as f64 / k as f64))
+        .collect();
+    values
+        .iter()
+        .map(|v| cuts.iter().filter(|c| *c < v).count() + 1)
+        .collect()
+}
+pub fn sigmoid(z: f64) -> f64 {
+    1.0 / (1.0 + (-z).exp())
+}
+pub fn normal_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+///
+/// Standard normal CDF via the complementary error function (Chebyshev fit, |ε| < 1.2e-7).
+///
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+///
+/// Inverse of the standard normal cdf (Acklam, relative error < 1.2e-9 after one Newton step).
+///
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    if p <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p >= 1.0 {
+        return f64::INFINITY;
+    }
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    let x = if p < 0.02425 {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - 0.02425 {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    };
+    // refine against the cdf
+    let e = normal_cdf(x) - p;
+    x - e / normal_pdf(x).max(1e-300)
+}
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t
+        * (-z * z - 1.26551223
+            + t * (1.00002368
+                + t * (0.37409196
+                    + t * (0.09678418
+                        + t * (-0.18628806
+                            + t * (0.27886807
+                                + t * (-1.13520398
+                                    + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+            .exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+///
+/// Mean negative log-likelihood of the binary outcome.  Scores are clipped to avoid `ln(0)`.
+///
+pub fn log_loss(y: &[f64], scores: &[f64]) -> f64 {
+    let eps = 1e-15;
+    let total: f64 = y
+        .iter()
+        .zip(scores)
+        .map(|(y, p)| {
+            let p = p.clamp(eps, 1.0 - eps);
+            -(y * p.ln() + (1.0 - y) * (1.0 - p).ln())
+        })
+        .sum();
+    total / y.len() as f64
+}
+pub fn brier(y: &[f64], scores: &[f64]) -> f64 {
+    let total: f64 = y.iter().zip(scores).map(|(y, p)| (p - y).powi(2)).sum();
+    total / y.len() as f64
+}
+///
+/// Area under the ROC curve using the rank-sum (Mann-Whitney) form.  Ties share the average
+/// rank.
+///
+pub fn auc(y: &[f64], scores: &[f64]) -> f64 {
+    let ranks = ranks(scores);
+    let (mut rank_sum, mut n_pos, mut n_neg) = (0.0, 0.0, 0.0);
+    for (y, r) in y.iter().zip(&ranks) {
+        if *y > 0.5 {
+            rank_sum += r;
+            n_pos += 1.0;
+        } else {
+            n_neg += 1.0;
+        }
+    }
+    if n_pos == 0.0 || n_neg == 0.0 {
+        return f64::NAN;
+    }
+    (rank_sum - n_pos * (n_pos + 1.0) / 2.0) / (n_pos * n_neg)
+}
+///
+/// 1-based ranks with ties assigned the average rank.
+///
+pub fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut stop = start + 1;
+        while stop < order.len() && values[order[stop]] == values[order[start]] {
+            stop += 1;
+        }
+        let avg = (start + stop + 1) as f64 / 2.0;
+        for idx in &order[start..stop] {
+            ranks[*idx] = avg;
+        }
+        start = stop;
+    }
+    ranks
+}
+///
+/// Solve `A x = b` for a square, row-dominant `A` using Gaussian elimination with partial
+/// pivoting.
+///
+pub fn solve(a: &[f64], b: &[f64], n: usize) -> Result<Vec<f64>> {
+    debug_assert!(
+        a.len() == n * n && b.len() == n,
+        "solve: dimensions do not agree"
+    );
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    for k in 0..n {
+        let pivot = (k..n)
+            .max_by(|i, j| a[i * n + k].abs().total_cmp(&a[j * n + k].abs()))
+            .unwrap();
+        if a[pivot * n + k].abs() < 1e-12 {
+            return Err(eyre!("Singular matrix: column {} has no usable pivot", k));
+        }
+        if pivot != k {
+            for c in 0..n {
+                a.swap(k * n + c, pivot * n + c);
+            }
+            b.swap(k, pivot);
+        }
+        for i in (k + 1)..n {
+            let factor = a[i * n + k] / a[k * n + k];
+            if factor == 0.0 {
+                continue;
+            }
+            for c in k..n {
+                a[i * n + c] -= factor * a[k * n + c];
+            }
+            b[i] -= factor * b[k];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for k in (0..n).rev() {
+        let tail: f64 = ((k + 1)..n).map(|c| a[k * n + c] * x[c]).sum();
+        x[k] = (b[k] - tail) / a[k * n + k];
+    }
+    Ok(x)
+}
+///
+/// Lower triangular L with `A = L L'` for a symmetric positive definite, row-dominant `A`.
+///
+pub fn cholesky(a: &[f64], n: usize) -> Result<Vec<f64>> {
+    let mut l = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| l[i * n + k] * l[j * n + k]).sum();
+            if i == j {
+                let d = a[i * n + i] - sum;
+                if d <= 0.0 {
+                    return Err(eyre!("Matrix is not positive definite at column {}", i));
+                }
+                l[i * n + j] = d.sqrt();
+            } else {
+                l[i * n + j] = (a[i * n + j] - sum) / l[j * n + j];
+            }
+        }
+    }
+    Ok(l)
+}
+/// Solve `L x = b` for lower triangular L
+pub fn forward_substitute(l: &[f64], b: &[f64], n: usize) -> Vec<f64> {
+    let mut x = vec![0.0; n];
+    for i in 0..n {
+        let sum: f64 = (0..i).map(|k| l[i * n + k] * x[k]).sum();
+        x[i] = (b[i] - sum) / l[i * n + i];
+    }
+    x
+}
+///
+/// ln Γ(x) for x > 0 (Lanczos, g = 7)
+///
+pub fn ln_gamma(x: f64) -> f64 {
+    const G: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = G[1..]
+        .iter()
+        .enumerate()
+        .fold(G[0], |acc, (i, g)| acc + g / (x + i as f64 + 1.0));
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+///
+/// Upper tail of the chi-square distribution, P(X > x) with `df` degrees of freedom.  The
+/// regularized incomplete gamma uses its series below a + 1 and the continued fraction above.
+///
+pub fn chi_square_sf(x: f64, df: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let (a, x) = (df / 2.0, x / 2.0);
+    let prefix = (-x + a * x.ln() - ln_gamma(a)).exp();
+    if x < a + 1.0 {
+        let (mut term, mut sum, mut n) = (1.0 / a, 1.0 / a, a);
+        while term.abs() > sum.abs() * 1e-15 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+        }
+        1.0 - prefix * sum
+    } else {
+        // modified Lentz
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..500 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            d = if d.abs() < tiny { tiny } else { d };
+            c = b + an / c;
+            c = if c.abs() < tiny { tiny } else { c };
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        prefix * h
+    }
+}
+///
+/// Holm step-down adjusted p-values (family-wise error), in the input order.
+///
+pub fn holm(p: &[f64]) -> Vec<f64> {
+    let m = p.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|a, b| p[*a].total_cmp(&p[*b]));
+    let mut adjusted = vec![0.0; m];
+    let mut running: f64 = 0.0;
+    for (rank, i) in order.into_iter().enumerate() {
+        running = running.max(((m - rank) as f64 * p[i]).min(1.0));
+        adjusted[i] = running;
+    }
+    adjusted
+}
+///
+/// Benjamini-Hochberg step-up adjusted p-values (false discovery rate), in the input order.
+///
+pub fn benjamini_hochberg(p: &[f64]) -> Vec<f64> {
+    let m = p.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|a, b| p[*b].total_cmp(&p[*a]));
+    let mut adjusted = vec![0.0; m];
+    let mut running: f64 = 1.0;
+    for (k, i) in order.into_iter().enumerate() {
+        let rank = m - k;
+        running = running.min(m as f64 * p[i] / rank as f64);
+        adjusted[i] = running;
+    }
+    adjusted
+}
+///
+/// Copy the selected rows out of a row-dominant buffer.
+///
+pub fn take_rows(x: &[f64], cols: usize, rows: &[usize]) -> Vec<f64> {
+    let mut data = Vec::with_capacity(rows.len() * cols);
+    for r in rows {
+        data.extend_from_slice(&x[r * cols..(r + 1) * cols]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ranks_with_ties() {
+        assert_eq!(vec![1.0, 2.5, 2.5, 4.0], ranks(&[0.1, 0.5, 0.5, 0.9]));
+    }
+    #[test]
+    fn test_auc_perfect_separation() {
+        let y = [0.0, 0.0, 1.0, 1.0];
+        assert_eq!(1.0, auc(&y, &[0.1, 0.2, 0.8, 0.9]));
+        assert_eq!(0.0, auc(&y, &[0.9, 0.8, 0.2, 0.1]));
+    }
+    #[test]
+    fn test_normal_cdf() {
+        assert!((normal_cdf(0.0) - 0.5).abs() < 1e-7);
+        assert!((normal_cdf(1.96) - 0.975).abs() < 1e-4);
+        assert!((normal_cdf(-1.96) - 0.025).abs() < 1e-4);
+    }
+    #[test]
+    fn test_normal_quantile() {
+        assert!(normal_quantile(0.5).abs() < 1e-7);
+        assert!((normal_quantile(0.975) - 1.959_964).abs() < 1e-5);
+        assert!((normal_quantile(0.001) + 3.090_232).abs() < 1e-5);
+    }
+    #[test]
+    fn test_ln_gamma() {
+        assert!(ln_gamma(1.0).abs() < 1e-12);
+        assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-10);
+        assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-10);
+    }
+    #[test]
+    fn test_chi_square_sf() {
+        assert!((chi_square_sf(3.841_459, 1.0) - 0.05).abs() < 1e-6);
+        assert!((chi_square_sf(18.307_04, 10.0) - 0.05).abs() < 1e-6);
+        assert!((chi_square_sf(2.0, 2.0) - (-1.0f64).exp()).abs() < 1e-12);
+    }
+    #[test]
+    fn test_multiple_comparisons() {
+        let p = [0.01, 0.04, 0.03, 0.2];
+        let round =
+            |p: Vec<f64>| -> Vec<f64> { p.iter().map(|p| (p * 1e6).round() / 1e6).collect() };
+        assert_eq!(vec![0.04, 0.09, 0.09, 0.2], round(holm(&p)));
+        assert_eq!(
+            vec![0.04, 0.053333, 0.053333, 0.2],
+            round(benjamini_hochberg(&p))
+        );
+    }
+    #[test]
+    fn test_solve() {
+        // 2x + y = 5; x + 3y = 10
+        let x = solve(&[2.0, 1.0, 1.0, 3.0], &[5.0, 10.0], 2).unwrap();
+        assert!((x[0] - 1.0).abs() < 1e-10);
+        assert!((x[1] - 3.0).abs() < 1e-10);
+    }
+}
@@ -0,0 +1,423 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::{event, Level};
+
+use crate::columns::{series_to_f64, set_ids};
+use crate::field_name::single_periods;
+
+///
+/// Synthetic control for a handful of treated units (territories, accounts).  Subjects are
+/// averaged into one time series per unit over the single-period fields of the measure; the
+/// treated units form one series.  Convex donor weights (w ≥ 0, Σw = 1) minimise the squared
+/// pre-period distance between the treated and the synthetic series.
+///
+/// Inference is by placebo in space: every donor in turn is treated as if treated, fitted on
+/// the remaining donors, and the post/pre RMSPE ratios are ranked.
+///
+#[derive(Debug, Clone)]
+pub struct SynthCfg {
+    pub treatment: String,
+    /// the unit field, e.g. `q_territory`
+    pub unit: String,
+    pub measure: String,
+    /// `key::value` qualifiers of the fields, e.g. `product::A`
+    pub filters: Vec<(String, String)>,
+    pub treatment_start: i64,
+    pub max_iters: usize,
+}
+
+impl SynthCfg {
+    pub fn new(treatment: &str, unit: &str, measure: &str, treatment_start: i64) -> Self {
+        SynthCfg {
+            treatment: treatment.to_string(),
+            unit: unit.to_string(),
+            measure: measure.to_string(),
+            filters: Vec::new(),
+            treatment_start,
+            max_iters: 10_000,
+        }
+    }
+    pub fn filter(mut self, key: &str, value: &str) -> Self {
+        self.filters.push((key.to_string(), value.to_string()));
+        self
+    }
+    pub fn max_iters(mut self, max_iters: usize) -> Self {
+        self.max_iters = max_iters;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SynthPoint {
+    pub period: i64,
+    pub treated: f64,
+    pub synthetic: f64,
+    /// treated - synthetic
+    pub gap: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Placebo {
+    pub unit: String,
+    pub pre_rmspe: f64,
+    pub post_rmspe: f64,
+}
+impl Placebo {
+    pub fn ratio(&self) -> f64 {
+        self.post_rmspe / self.pre_rmspe
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyntheticControl {
+    pub measure: String,
+    pub treatment_start: i64,
+    pub treated_units: Vec<String>,
+    /// donor units with their weight, largest first
+    pub weights: Vec<(String, f64)>,
+    pub path: Vec<SynthPoint>,
+    pub fit: Placebo,
+    pub placebos: Vec<Placebo>,
+    /// share of all units with a post/pre RMSPE ratio at least as large as the treated one
+    pub p_value: f64,
+}
+
+impl SyntheticControl {
+    /// Mean post-period gap
+    pub fn effect(&self) -> f64 {
+        let post: Vec<f64> = self
+            .path
+            .iter()
+            .filter(|p| p.period >= self.treatment_start)
+            .map(|p| p.gap)
+            .collect();
+        post.iter().sum::<f64>() / post.len() as f64
+    }
+    ///
+    /// `period`, `treated`, `synthetic`, `gap`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let column = |f: fn(&SynthPoint) -> f64| -> Vec<f64> { self.path.iter().map(f).collect() };
+        Ok(DataFrame::new(vec![
+            Series::new(
+                "period",
+                self.path.iter().map(|p| p.period).collect::<Vec<_>>(),
+            ),
+            Series::new("treated", column(|p| p.treated)),
+            Series::new("synthetic", column(|p| p.synthetic)),
+            Series::new("gap", column(|p| p.gap)),
+        ])?)
+    }
+}
+impl fmt::Display for SyntheticControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Synthetic control of {} for {} (treatment starts {})",
+            self.measure,
+            self.treated_units.join(", "),
+            self.treatment_start
+        )?;
+        for (unit, w) in self.weights.iter().filter(|(_, w)| *w > 1e-4) {
+            writeln!(f, "  {:<24} {:.4}", unit, w)?;
+        }
+        for p in &self.path {
+            writeln!(
+                f,
+                "  {:>6} {:>12.4} {:>12.4} {:>+12.4}",
+                p.period, p.treated, p.synthetic, p.gap
+            )?;
+        }
+        write!(
+            f,
+            "  effect {:.4}, RMSPE pre {:.4} post {:.4}, placebo p {:.4} ({} placebos)",
+            self.effect(),
+            self.fit.pre_rmspe,
+            self.fit.post_rmspe,
+            self.p_value,
+            self.placebos.len()
+        )
+    }
+}
+
+///
+/// Euclidean projection onto the probability simplex (Duchi et al. 2008).
+///
+fn project_simplex(v: &[f64]) -> Vec<f64> {
+    let mut u = v.to_vec();
+    u.sort_by(|a, b| b.total_cmp(a));
+    let mut sum = 0.0;
+    let mut theta = 0.0;
+    for (j, u) in u.iter().enumerate() {
+        sum += u;
+        let t = (sum - 1.0) / (j + 1) as f64;
+        if u - t > 0.0 {
+            theta = t;
+        }
+    }
+    v.iter().map(|v| (v - theta).max(0.0)).collect()
+}
+
+///
+/// Convex weights of the donor series (columns) reproducing `target` over the pre periods, by
+/// accelerated projected gradient descent (FISTA).
+///
+pub fn convex_weights(target: &[f64], donors: &[Vec<f64>], max_iters: usize) -> Vec<f64> {
+    let k = donors.len();
+    let combine = |w: &[f64]| -> Vec<f64> {
+        (0..target.len())
+            .map(|p| donors.iter().zip(w).map(|(d, w)| d[p] * w).sum())
+            .collect()
+    };
+    let gradient = |w: &[f64]| -> Vec<f64> {
+        let residual: Vec<f64> = combine(w).iter().zip(target).map(|(s, t)| s - t).collect();
+        donors
+            .iter()
+            .map(|d| d.iter().zip(&residual).map(|(d, r)| d * r).sum())
+            .collect()
+    };
+    // step 1/L with L the largest eigenvalue of D'D (power iteration)
+    let mut v = vec![1.0; k];
+    let mut lipschitz = 1e-12;
+    for _ in 0..100 {
+        let dv = combine(&v);
+        let next: Vec<f64> = donors
+            .iter()
+            .map(|d| d.iter().zip(&dv).map(|(d, r)| d * r).sum())
+            .collect();
+        let norm = next.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm == 0.0 {
+            break;
+        }
+        lipschitz = norm / v.iter().map(|x| x * x).sum::<f64>().sqrt();
+        v = next.iter().map(|x| x / norm).collect();
+    }
+
+    let mut w = vec![1.0 / k as f64; k];
+    let mut y = w.clone();
+    let mut momentum: f64 = 1.0;
+    for _ in 0..max_iters {
+        let step: Vec<f64> = y
+            .iter()
+            .zip(gradient(&y))
+            .map(|(y, g)| y - g / lipschitz)
+            .collect();
+        let next = project_simplex(&step);
+        let accelerated = (1.0 + (1.0 + 4.0 * momentum * momentum).sqrt()) / 2.0;
+        let beta = (momentum - 1.0) / accelerated;
+        y = next
+            .iter()
+            .zip(&w)
+            .map(|(n, w)| n + beta * (n - w))
+            .collect();
+        let change: f64 = next.iter().zip(&w).map(|(a, b)| (a - b).abs()).sum();
+        w = next;
+        momentum = accelerated;
+        if change < 1e-12 {
+            break;
+        }
+    }
+    w
+}
+
+fn rmspe(gaps: &[f64]) -> f64 {
+    (gaps.iter().map(|g| g * g).sum::<f64>() / gaps.len() as f64).sqrt()
+}
+
+///
+/// Fit the synthetic series of `target` from the donors; returns the weights, the synthetic
+/// series and the pre/post RMSPE.
+///
+fn fit(
+    target: &[f64],
+    donors: &[&Vec<f64>],
+    pre: usize,
+    max_iters: usize,
+) -> (Vec<f64>, Vec<f64>, f64, f64) {
+    let pre_donors: Vec<Vec<f64>> = donors.iter().map(|d| d[..pre].to_vec()).collect();
+    let w = convex_weights(&target[..pre], &pre_donors, max_iters);
+    let synthetic: Vec<f64> = (0..target.len())
+        .map(|p| donors.iter().zip(&w).map(|(d, w)| d[p] * w).sum())
+        .collect();
+    let gaps: Vec<f64> = target.iter().zip(&synthetic).map(|(t, s)| t - s).collect();
+    let (pre_rmspe, post_rmspe) = (rmspe(&gaps[..pre]), rmspe(&gaps[pre..]));
+    (w, synthetic, pre_rmspe, post_rmspe)
+}
+
+pub fn synthetic_control(df: &DataFrame, cfg: &SynthCfg) -> Result<SyntheticControl> {
+    let fields = single_periods(&cfg.measure, &cfg.filters, &df.get_column_names())?;
+    let periods: Vec<i64> = fields.keys().copied().collect();
+    let pre = periods.iter().filter(|p| **p < cfg.treatment_start).count();
+    if pre < 2 || pre == periods.len() {
+        return Err(eyre!(
+            "Synthetic control needs two pre-periods and one post-period of {}, found {} and {}",
+            cfg.measure,
+            pre,
+            periods.len() - pre
+        ));
+    }
+
+    // unit × period means, and the treated share of each unit
+    let units = set_ids(df, &cfg.unit)?;
+    let t = series_to_f64(df.column(&cfg.treatment)?)?;
+    let values = fields
+        .values()
+        .map(|f| series_to_f64(df.column(f)?))
+        .collect::<Result<Vec<_>>>()?;
+    let mut rows: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (r, unit) in units.iter().enumerate() {
+        if let Some(unit) = unit {
+            rows.entry(unit.as_str()).or_default().push(r);
+        }
+    }
+    let series = |rows: &[usize]| -> Vec<f64> {
+        values
+            .iter()
+            .map(|v| rows.iter().map(|r| v[*r]).sum::<f64>() / rows.len() as f64)
+            .collect()
+    };
+    let mut treated_rows = Vec::new();
+    let mut treated_units = Vec::new();
+    let mut donors: Vec<(String, Vec<f64>)> = Vec::new();
+    for (unit, rows) in &rows {
+        let share = rows.iter().map(|r| t[*r]).sum::<f64>() / rows.len() as f64;
+        match share > 0.5 {
+            true => {
+                treated_units.push(unit.to_string());
+                treated_rows.extend(rows.iter().copied());
+            }
+            false => donors.push((unit.to_string(), series(rows))),
+        }
+    }
+    if treated_units.is_empty() || donors.len() < 2 {
+        return Err(eyre!(
+            "Synthetic control needs a treated unit and two donors, found {} and {}",
+            treated_units.len(),
+            donors.len()
+        ));
+    }
+    let target = series(&treated_rows);
+
+    let all: Vec<&Vec<f64>> = donors.iter().map(|(_, d)| d).collect();
+    let (w, synthetic, pre_rmspe, post_rmspe) = fit(&target, &all, pre, cfg.max_iters);
+
+    // placebo in space: each donor against the others
+    let placebos: Vec<Placebo> = (0..donors.len())
+        .map(|j| {
+            let others: Vec<&Vec<f64>> = (0..donors.len())
+                .filter(|i| *i != j)
+                .map(|i| &donors[i].1)
+                .collect();
+            let (_, _, pre_rmspe, post_rmspe) = fit(&donors[j].1, &others, pre, cfg.max_iters);
+            Placebo {
+                unit: donors[j].0.clone(),
+                pre_rmspe,
+                post_rmspe,
+            }
+        })
+        .collect();
+    let fitted = Placebo {
+        unit: treated_units.join("+"),
+        pre_rmspe,
+        post_rmspe,
+    };
+    let as_extreme = placebos
+        .iter()
+        .filter(|p| p.ratio() >= fitted.ratio())
+        .count();
+    let p_value = (1 + as_extreme) as f64 / (1 + placebos.len()) as f64;
+    event!(
+        Level::DEBUG,
+        "synthetic control: {} donors, pre RMSPE {}",
+        donors.len(),
+        pre_rmspe
+    );
+
+    let mut weights: Vec<(String, f64)> = donors
+        .iter()
+        .zip(w)
+        .map(|((unit, _), w)| (unit.clone(), w))
+        .collect();
+    weights.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let path = periods
+        .iter()
+        .zip(target.iter().zip(&synthetic))
+        .map(|(period, (t, s))| SynthPoint {
+            period: *period,
+            treated: *t,
+            synthetic: *s,
+            gap: t - s,
+        })
+        .collect();
+
+    Ok(SyntheticControl {
+        measure: cfg.measure.clone(),
+        treatment_start: cfg.treatment_start,
+        treated_units,
+        weights,
+        path,
+        fit: fitted,
+        placebos,
+        p_value,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_project_simplex() {
+        let w = project_simplex(&[0.5, 0.8, -0.3]);
+        assert!((w.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(w.iter().all(|w| *w >= 0.0));
+        assert_eq!(0.0, w[2]);
+    }
+    #[test]
+    fn test_synthetic_control_recovers_the_gap() {
+        // eight donor territories of three subjects, periods 1..=10, treatment from 7
+        let path = |u: usize, p: i64| -> f64 {
+            10.0 + u as f64 * 2.0
+                + (u % 3) as f64 * p as f64
+                + ((u * 5 + p as usize * 3) % 7) as f64
+        };
+        let (mut unit, mut treated) = (Vec::new(), Vec::new());
+        let mut columns: Vec<Vec<f64>> = vec![Vec::new(); 10];
+        for u in 0..9 {
+            for _ in 0..3 {
+                unit.push(format!("t{}", u));
+                treated.push((u == 8) as u8 as f64);
+                for p in 1..=10i64 {
+                    let value = match u {
+                        // half of t1 and half of t4, plus 3 after the start
+                        8 => 0.5 * (path(1, p) + path(4, p)) + if p >= 7 { 3.0 } else { 0.0 },
+                        _ => path(u, p),
+                    };
+                    columns[(p - 1) as usize].push(value);
+                }
+            }
+        }
+        let mut series = vec![
+            Series::new("q_territory", unit),
+            Series::new("treated", treated),
+        ];
+        for (p, values) in columns.into_iter().enumerate() {
+            series.push(Series::new(
+                &format!("MeaType::m_unitcount.time::{}", p + 1),
+                values,
+            ));
+        }
+        let df = DataFrame::new(series).unwrap();
+
+        let cfg = SynthCfg::new("treated", "q_territory", "m_unitcount", 7);
+        let found = synthetic_control(&df, &cfg).unwrap();
+        assert_eq!(vec!["t8".to_string()], found.treated_units);
+        assert!(found.fit.pre_rmspe < 0.05);
+        assert!((found.effect() - 3.0).abs() < 0.1);
+        assert_eq!(8, found.placebos.len());
+        assert!((found.p_value - 1.0 / 9.0).abs() < 1e-12);
+        assert_eq!(10, found.to_dataframe().unwrap().height());
+    }
+}
@@ -0,0 +1,400 @@
+use color_eyre::eyre::{eyre, Result};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_until, take_while1};
+use nom::character::complete::{char, multispace0, one_of, u32 as parse_u32};
+use nom::combinator::{all_consuming, map};
+use nom::multi::{many0, separated_list1};
+use nom::sequence::{delimited, pair, separated_pair, tuple};
+use nom::IResult;
+use polars::prelude::*;
+use std::fmt;
+
+use crate::columns::series_to_f64;
+use crate::header::Header;
+use crate::propensity::build_dummies;
+use crate::stats::{mean, quantile, variance};
+
+///
+/// Terms added to the propensity design matrix on top of the raw predictors.
+///
+/// Terms are written in a small formula syntax, several terms joined with `+`:
+///
+/// * `a:b`           interaction only
+/// * `a * b`         main effects plus the interaction (a + b + a:b)
+/// * `poly(x, 2)`    powers 1..=2 of the standardized x
+/// * `bs(x, 5)`      cubic spline basis of the standardized x with 5 degrees of freedom
+///
+/// Field names with characters other than `[A-Za-z0-9_.]` are quoted with backticks.
+/// Categorical (utf8) fields are expanded to their dummies before forming interactions.
+///
+/// # Example
+///
+/// ```
+/// use tnc_analysis_lib::prelude::{parse_terms, Term};
+///
+/// let terms = parse_terms("q_specialty * q_innetwork + poly(`MeaType::m_unitcount.product::A.time::0_23`, 2)").unwrap();
+/// assert_eq!(4, terms.len());
+/// assert!(terms[2] == Term::Interaction(vec!["q_specialty".into(), "q_innetwork".into()]));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Main(String),
+    Interaction(Vec<String>),
+    Poly { field: String, degree: usize },
+    Spline { field: String, df: usize },
+}
+
+impl Term {
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Term::Main(field) => vec![field],
+            Term::Interaction(fields) => fields.iter().map(|f| f.as_str()).collect(),
+            Term::Poly { field, .. } | Term::Spline { field, .. } => vec![field],
+        }
+    }
+    fn map_fields(&self, f: impl Fn(&str) -> Result<String>) -> Result<Term> {
+        Ok(match self {
+            Term::Main(field) => Term::Main(f(field)?),
+            Term::Interaction(fields) => {
+                Term::Interaction(fields.iter().map(|v| f(v)).collect::<Result<_>>()?)
+            }
+            Term::Poly { field, degree } => Term::Poly {
+                field: f(field)?,
+                degree: *degree,
+            },
+            Term::Spline { field, df } => Term::Spline {
+                field: f(field)?,
+                df: *df,
+            },
+        })
+    }
+}
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::Main(field) => write!(f, "{}", quote(field)),
+            Term::Interaction(fields) => {
+                let fields: Vec<String> = fields.iter().map(|v| quote(v)).collect();
+                write!(f, "{}", fields.join(":"))
+            }
+            Term::Poly { field, degree } => write!(f, "poly({}, {})", quote(field), degree),
+            Term::Spline { field, df } => write!(f, "bs({}, {})", quote(field), df),
+        }
+    }
+}
+fn quote(field: &str) -> String {
+    match field.chars().all(is_ident_char) {
+        true => field.to_string(),
+        false => format!("`{}`", field),
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// Parsing
+pub fn parse_terms(input: &str) -> Result<Vec<Term>> {
+    let (_, terms) = all_consuming(delimited(multispace0, terms, multispace0))(input)
+        .map_err(|e| eyre!("Failed to parse terms '{}': {}", input, e))?;
+    let mut unique: Vec<Term> = Vec::with_capacity(terms.len());
+    for term in terms.into_iter().flatten() {
+        if !unique.contains(&term) {
+            unique.push(term);
+        }
+    }
+    Ok(unique)
+}
+
+pub(crate) fn terms(input: &str) -> IResult<&str, Vec<Vec<Term>>> {
+    separated_list1(ws(char('+')), term)(input)
+}
+
+pub(crate) fn term(input: &str) -> IResult<&str, Vec<Term>> {
+    alt((map(call, |t| vec![t]), product))(input)
+}
+
+fn call(input: &str) -> IResult<&str, Term> {
+    map(
+        pair(
+            alt((tag("poly"), tag("bs"))),
+            delimited(
+                ws(char('(')),
+                separated_pair(ident, ws(char(',')), parse_u32),
+                ws(char(')')),
+            ),
+        ),
+        |(func, (field, n))| match func {
+            "poly" => Term::Poly {
+                field,
+                degree: n as usize,
+            },
+            _ => Term::Spline {
+                field,
+                df: n as usize,
+            },
+        },
+    )(input)
+}
+
+///
+/// `a`, `a:b:c` or `a * b * c` (every main effect and interaction of the factors)
+///
+fn product(input: &str) -> IResult<&str, Vec<Term>> {
+    let (rest, (first, others)) = pair(ident, many0(pair(ws(one_of("*:")), ident)))(input)?;
+    let crossed = others.iter().any(|(op, _)| *op == '*');
+    let nested = others.iter().any(|(op, _)| *op == ':');
+    if crossed && nested {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let mut factors = vec![first];
+    factors.extend(others.into_iter().map(|(_, f)| f));
+
+    let terms = match (factors.len(), crossed) {
+        (1, _) => vec![Term::Main(factors.remove(0))],
+        (_, false) => vec![Term::Interaction(factors)],
+        (n, true) => {
+            // every non-empty subset, ordered by size: a, b, a:b
+            let mut subsets: Vec<Vec<String>> = (1..(1usize << n))
+                .map(|mask| {
+                    (0..n)
+                        .filter(|i| mask & (1 << i) != 0)
+                        .map(|i| factors[i].clone())
+                        .collect()
+                })
+                .collect();
+            subsets.sort_by_key(|s| s.len());
+            subsets
+                .into_iter()
+                .map(|s| match s.len() {
+                    1 => Term::Main(s.into_iter().next().unwrap()),
+                    _ => Term::Interaction(s),
+                })
+                .collect()
+        }
+    };
+    Ok((rest, terms))
+}
+
+pub(crate) fn ident(input: &str) -> IResult<&str, String> {
+    alt((
+        map(
+            delimited(char('`'), take_until("`"), char('`')),
+            String::from,
+        ),
+        map(take_while1(is_ident_char), String::from),
+    ))(input)
+}
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+pub(crate) fn ws<'a, O>(
+    inner: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    map(tuple((multispace0, inner, multispace0)), |(_, o, _)| o)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Resolution and expansion
+///
+/// Map each field in the terms to a column of the matrix: exact name first, otherwise a unique
+/// fuzzy match (e.g. `unitcount.product::A.time::0_23`).
+///
+pub fn resolve_terms(terms: &[Term], header: &Header) -> Result<Vec<Term>> {
+    terms
+        .iter()
+        .map(|term| term.map_fields(|field| resolve_field(field, header)))
+        .collect()
+}
+pub(crate) fn resolve_field(field: &str, header: &Header) -> Result<String> {
+    if header.contains(&field) {
+        return Ok(field.to_string());
+    }
+    match header.get_fuzzy_fields(field).as_slice() {
+        [(found, _)] => Ok(found.to_string()),
+        [] => Err(eyre!("Term field not found in the matrix: {}", field)),
+        many => Err(eyre!(
+            "Term field {} is ambiguous: {:?}",
+            field,
+            many.iter().map(|(f, _)| *f).collect::<Vec<_>>()
+        )),
+    }
+}
+
+///
+/// Columns for the interaction, polynomial and spline terms.  Main terms are selected with the
+/// predictors so they are not repeated here.  Fields in `categorical` enter the interactions as
+/// dummies even when numeric (`C()` in a [`crate::formula::ModelDef`]).
+///
+pub fn expand_terms(df: &DataFrame, terms: &[Term], categorical: &[String]) -> Result<Vec<Series>> {
+    let mut expanded: Vec<Series> = Vec::new();
+    for term in terms {
+        match term {
+            Term::Main(_) => {}
+            Term::Interaction(fields) => {
+                let mut acc: Vec<(String, Vec<f64>)> =
+                    vec![(String::new(), vec![1.0; df.height()])];
+                for field in fields {
+                    let columns = interaction_columns(df, field, categorical.contains(field))?;
+                    acc = acc
+                        .iter()
+                        .flat_map(|(name, values)| {
+                            columns.iter().map(move |(c_name, c_values)| {
+                                let name = match name.is_empty() {
+                                    true => c_name.clone(),
+                                    false => format!("{}:{}", name, c_name),
+                                };
+                                let values = values.iter().zip(c_values).map(|(a, b)| a * b);
+                                (name, values.collect())
+                            })
+                        })
+                        .collect();
+                }
+                expanded.extend(acc.into_iter().map(|(name, v)| Series::new(&name, v)));
+            }
+            Term::Poly { field, degree } => {
+                let z = standardized(df, field)?;
+                for k in 1..=*degree {
+                    let values: Vec<f64> = z.iter().map(|v| v.powi(k as i32)).collect();
+                    expanded.push(Series::new(&format!("poly({})_{}", field, k), values));
+                }
+            }
+            Term::Spline { field, df: dof } => {
+                if *dof == 0 {
+                    return Err(eyre!("bs({}, 0) has no columns; use df >= 1", field));
+                }
+                let z = standardized(df, field)?;
+                for (k, values) in spline_basis(&z, *dof).into_iter().enumerate() {
+                    expanded.push(Series::new(&format!("bs({})_{}", field, k + 1), values));
+                }
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+///
+/// Truncated power basis for a cubic spline: z, z², z³ and (z - κ)³₊ for knots at the
+/// quantiles of z.  `dof` columns: the powers up to min(dof, 3) and dof - 3 knots.
+///
+pub(crate) fn spline_basis(z: &[f64], dof: usize) -> Vec<Vec<f64>> {
+    let knot_count = dof.saturating_sub(3);
+    let knots: Vec<f64> = (1..=knot_count)
+        .map(|k| quantile(z, k as f64 / (knot_count + 1) as f64))
+        .collect();
+    let mut basis: Vec<Vec<f64>> = (1..=dof.min(3) as i32)
+        .map(|p| z.iter().map(|v| v.powi(p)).collect())
+        .collect();
+    for knot in knots {
+        basis.push(z.iter().map(|v| (v - knot).max(0.0).powi(3)).collect());
+    }
+    basis
+}
+
+/// The field as f64, or its dummies when categorical
+pub(crate) fn numeric_columns(df: &DataFrame, field: &str) -> Result<Vec<(String, Vec<f64>)>> {
+    let frame = match df.column(field)?.dtype() {
+        DataType::Utf8 => build_dummies(df.select([field])?, None, None)?,
+        _ => df.select([field])?,
+    };
+    frame
+        .get_columns()
+        .iter()
+        .map(|s| Ok((s.name().to_string(), series_to_f64(s)?)))
+        .collect()
+}
+///
+/// The columns of a field in an interaction.  The dummy of the first level (in sort order) is
+/// the reference and is dropped so that the interaction is not collinear with the main effects
+/// and the bias slot.
+///
+fn interaction_columns(
+    df: &DataFrame,
+    field: &str,
+    categorical: bool,
+) -> Result<Vec<(String, Vec<f64>)>> {
+    let column = df.column(field)?;
+    match categorical || column.dtype() == &DataType::Utf8 {
+        true => {
+            let levels = DataFrame::new(vec![column.cast(&DataType::Utf8)?])?;
+            let mut dummies = numeric_columns(&levels, field)?;
+            dummies.sort_by(|a, b| a.0.cmp(&b.0));
+            Ok(dummies.split_off(1.min(dummies.len())))
+        }
+        false => numeric_columns(df, field),
+    }
+}
+fn standardized(df: &DataFrame, field: &str) -> Result<Vec<f64>> {
+    let values = series_to_f64(df.column(field)?)?;
+    let (m, sd) = (mean(&values), variance(&values).sqrt());
+    let sd = if sd > 0.0 { sd } else { 1.0 };
+    Ok(values.iter().map(|v| (v - m) / sd).collect())
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_crossed() {
+        let terms = parse_terms("a * b * c").unwrap();
+        assert_eq!(7, terms.len());
+        assert_eq!("a:b:c", terms[6].to_string());
+    }
+    #[test]
+    fn test_parse_calls_and_quotes() {
+        let terms = parse_terms("a:b + bs(`x::1`, 5) + poly(y, 3)").unwrap();
+        assert_eq!(
+            vec![
+                Term::Interaction(vec!["a".into(), "b".into()]),
+                Term::Spline {
+                    field: "x::1".into(),
+                    df: 5
+                },
+                Term::Poly {
+                    field: "y".into(),
+                    degree: 3
+                },
+            ],
+            terms
+        );
+        assert_eq!("bs(`x::1`, 5)", terms[1].to_string());
+    }
+    #[test]
+    fn test_parse_rejects_mixed_operators() {
+        assert!(parse_terms("a * b : c").is_err());
+    }
+    #[test]
+    fn test_spline_basis_width() {
+        let z: Vec<f64> = (0..20).map(|v| v as f64).collect();
+        assert_eq!(5, spline_basis(&z, 5).len());
+        assert_eq!(3, spline_basis(&z, 3).len());
+        assert_eq!(2, spline_basis(&z, 2).len());
+    }
+    #[test]
+    fn test_interaction_drops_reference_levels() {
+        let df = df!(
+            "q_state" => ["NY", "CA", "TX", "CA"],
+            "x" => [1.0, 2.0, 3.0, 4.0]
+        )
+        .unwrap();
+        let terms = parse_terms("q_state:x").unwrap();
+        let expanded = expand_terms(&df, &terms, &[]).unwrap();
+        // CA is the reference
+        assert_eq!(
+            vec!["q_state_NY:x", "q_state_TX:x"],
+            expanded.iter().map(|s| s.name()).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(3.0), expanded[1].f64().unwrap().get(2));
+
+        // a numeric field forced to be categorical
+        let df = df!("q_tier" => [1, 2, 3, 1], "x" => [1.0, 2.0, 3.0, 4.0]).unwrap();
+        let terms = parse_terms("q_tier:x").unwrap();
+        assert_eq!(1, expand_terms(&df, &terms, &[]).unwrap().len());
+        let expanded = expand_terms(&df, &terms, &["q_tier".to_string()]).unwrap();
+        assert_eq!(
+            vec!["q_tier_2:x", "q_tier_3:x"],
+            expanded.iter().map(|s| s.name()).collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,5 +1,10 @@
 use serde::{Deserialize, Serialize};
 
+use crate::cross_fit::CrossFitCfg;
+use crate::propensity_model::ModelFamily;
+use crate::terms::{parse_terms, Term};
+use color_eyre::eyre::Result;
+
 /// Wrapper for a wide range of configurations.
 ///
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,6 +39,7 @@ impl<T> std::ops::DerefMut for Config<T> {
 /// ```
 /// use serde::{Deserialize, Serialize};
 /// use tnc_analysis_lib::tnc_analysis_cfg::PropensityScore;
+/// use tnc_analysis_lib::prelude::ModelFamily;
 ///
 /// let json = r#"{
 ///      "binary-target-field-tag": "reach",
@@ -76,6 +82,20 @@ impl<T> std::ops::DerefMut for Config<T> {
 /// let model: PropensityScore = serde_json::from_str(&json).unwrap();
 /// assert!(model.binary_target_field_tag == "reach");
 /// assert!(model.predictors.is_empty());
+/// assert!(model.cross_fit.is_none());
+///
+/// let json = r#"{
+///      "binary-target-field-tag": "reach",
+///      "predictors": [],
+///      "bins": { "count": 5, "ranges": [], "generator": { "type": "EqualRange" } },
+///      "cross-fit": { "folds": 5, "seed": 42 },
+///      "model": { "type": "Probit" },
+///      "terms": ["q_specialty * q_innetwork", "poly(`unitcount.product::A`, 2)"]
+///   }"#;
+/// let model: PropensityScore = serde_json::from_str(&json).unwrap();
+/// assert!(model.parsed_terms().unwrap().len() == 4);
+/// assert!(model.cross_fit.unwrap().folds == 5);
+/// assert!(model.model == ModelFamily::Probit);
 /// ```
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PropensityScore {
@@ -83,6 +103,25 @@ pub struct PropensityScore {
     pub binary_target_field_tag: SearchTerm,
     pub predictors: Vec<SearchTerm>,
     pub bins: Bins,
+    /// out-of-fold scoring; in-sample when absent
+    #[serde(rename = "cross-fit", default)]
+    pub cross_fit: Option<CrossFitCfg>,
+    /// treatment assignment model family; logit when absent
+    #[serde(default)]
+    pub model: ModelFamily,
+    /// design terms in formula syntax, e.g. `"q_specialty * q_innetwork"`, `"poly(x, 2)"`
+    #[serde(default)]
+    pub terms: Vec<String>,
+}
+impl PropensityScore {
+    /// Parse the configured design terms (see [`crate::terms::parse_terms`])
+    pub fn parsed_terms(&self) -> Result<Vec<Term>> {
+        let mut parsed: Vec<Term> = Vec::new();
+        for term in self.terms.iter().map(|t| parse_terms(t)) {
+            parsed.extend(term?);
+        }
+        Ok(parsed)
+    }
 }
 type SearchTerm = String;
 
@@ -0,0 +1,190 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::columns::series_to_f64;
+use crate::field_name::{single_periods, ParsedField, DERIVED_KEY, TIME_KEY};
+
+///
+/// Features of each subject's pre-period trajectory, fitted by least squares over the periods:
+///
+/// * `Slope`        change per period
+/// * `Level`        mean over the window
+/// * `Volatility`   standard deviation of the residuals around the trend line
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrendFeature {
+    Slope,
+    Level,
+    Volatility,
+}
+impl fmt::Display for TrendFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrendFeature::Slope => write!(f, "trendSlope"),
+            TrendFeature::Level => write!(f, "trendLevel"),
+            TrendFeature::Volatility => write!(f, "trendVolatility"),
+        }
+    }
+}
+
+///
+/// Pre-period trend features of a measure over its single-period `time::` columns before the
+/// treatment start (optionally from `window_start`).  The features are appended as
+/// `MeaType::<measure>[.filters].time::<first>_<last>.derivedField::trendSlope` etc. so the
+/// fuzzy predictor search adds them to the propensity design.  [`TrendCfg::fields`] gives the
+/// same columns for [`crate::matching::Distance::TimeSeries`].
+///
+#[derive(Debug, Clone)]
+pub struct TrendCfg {
+    pub measure: String,
+    /// `key::value` qualifiers of the fields, e.g. `product::A`
+    pub filters: Vec<(String, String)>,
+    pub treatment_start: i64,
+    pub window_start: Option<i64>,
+    pub features: Vec<TrendFeature>,
+}
+
+impl TrendCfg {
+    pub fn new(measure: &str, treatment_start: i64) -> Self {
+        TrendCfg {
+            measure: measure.to_string(),
+            filters: Vec::new(),
+            treatment_start,
+            window_start: None,
+            features: vec![
+                TrendFeature::Slope,
+                TrendFeature::Level,
+                TrendFeature::Volatility,
+            ],
+        }
+    }
+    pub fn filter(mut self, key: &str, value: &str) -> Self {
+        self.filters.push((key.to_string(), value.to_string()));
+        self
+    }
+    pub fn window_start(mut self, period: i64) -> Self {
+        self.window_start = Some(period);
+        self
+    }
+    pub fn features(mut self, features: Vec<TrendFeature>) -> Self {
+        self.features = features;
+        self
+    }
+    ///
+    /// The pre-window fields of the measure by period.
+    ///
+    pub fn fields<'a>(&self, names: &[&'a str]) -> Result<BTreeMap<i64, &'a str>> {
+        let fields: BTreeMap<i64, &str> = single_periods(&self.measure, &self.filters, names)?
+            .into_iter()
+            .filter(|(p, _)| match self.window_start {
+                Some(start) => *p >= start && *p < self.treatment_start,
+                None => *p < self.treatment_start,
+            })
+            .collect();
+        match fields.len() < 2 {
+            true => Err(eyre!(
+                "Trend features need two pre-periods of {}, found {}",
+                self.measure,
+                fields.len()
+            )),
+            false => Ok(fields),
+        }
+    }
+    pub fn feature_name(&self, feature: TrendFeature, first: i64, last: i64) -> String {
+        let mut components = self.filters.clone();
+        components.push((TIME_KEY.to_string(), format!("{}_{}", first, last)));
+        components.push((DERIVED_KEY.to_string(), feature.to_string()));
+        ParsedField {
+            measure: self.measure.clone(),
+            components,
+        }
+        .to_string()
+    }
+}
+
+///
+/// One column per configured feature.
+///
+pub fn trend_features(df: &DataFrame, cfg: &TrendCfg) -> Result<Vec<Series>> {
+    let fields = cfg.fields(&df.get_column_names())?;
+    let periods: Vec<f64> = fields.keys().map(|p| *p as f64).collect();
+    let values = fields
+        .values()
+        .map(|f| series_to_f64(df.column(f)?))
+        .collect::<Result<Vec<_>>>()?;
+
+    let k = periods.len() as f64;
+    let centre = periods.iter().sum::<f64>() / k;
+    let spread: f64 = periods.iter().map(|p| (p - centre).powi(2)).sum();
+    let (mut slope, mut level, mut volatility) = (Vec::new(), Vec::new(), Vec::new());
+    for r in 0..df.height() {
+        let y: Vec<f64> = values.iter().map(|v| v[r]).collect();
+        let mean = y.iter().sum::<f64>() / k;
+        let b = periods
+            .iter()
+            .zip(&y)
+            .map(|(p, y)| (p - centre) * (y - mean))
+            .sum::<f64>()
+            / spread;
+        let residual: f64 = periods
+            .iter()
+            .zip(&y)
+            .map(|(p, y)| (y - mean - b * (p - centre)).powi(2))
+            .sum();
+        slope.push(b);
+        level.push(mean);
+        volatility.push(match k > 2.0 {
+            true => (residual / (k - 2.0)).sqrt(),
+            false => 0.0,
+        });
+    }
+
+    let (first, last) = (
+        *fields.keys().next().unwrap_or(&0),
+        *fields.keys().last().unwrap_or(&0),
+    );
+    Ok(cfg
+        .features
+        .iter()
+        .map(|feature| {
+            let values = match feature {
+                TrendFeature::Slope => slope.clone(),
+                TrendFeature::Level => level.clone(),
+                TrendFeature::Volatility => volatility.clone(),
+            };
+            Series::new(&cfg.feature_name(*feature, first, last), values)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trend_features() {
+        let df = df!(
+            "MeaType::m_unitcount.product::A.time::1" => [1.0, 5.0],
+            "MeaType::m_unitcount.product::A.time::2" => [3.0, 4.0],
+            "MeaType::m_unitcount.product::A.time::3" => [5.0, 5.0],
+            "MeaType::m_unitcount.product::A.time::4" => [7.0, 4.0],
+            "MeaType::m_unitcount.product::A.time::5" => [100.0, 100.0]
+        )
+        .unwrap();
+        let cfg = TrendCfg::new("m_unitcount", 5).filter("product", "A");
+        let features = trend_features(&df, &cfg).unwrap();
+
+        assert_eq!(
+            "MeaType::m_unitcount.product::A.time::1_4.derivedField::trendSlope",
+            features[0].name()
+        );
+        let get = |i: usize, r: usize| features[i].f64().unwrap().get(r).unwrap();
+        assert!((get(0, 0) - 2.0).abs() < 1e-12);
+        assert!((get(1, 0) - 4.0).abs() < 1e-12);
+        assert!(get(2, 0).abs() < 1e-12);
+        assert!((get(1, 1) - 4.5).abs() < 1e-12);
+        assert!(get(2, 1) > 0.0);
+    }
+}
@@ -0,0 +1,332 @@
+use color_eyre::eyre::{eyre, Result};
+use polars::prelude::*;
+use std::fmt;
+
+use crate::glm::{dot, ols};
+use crate::propensity::{Predictors, PredictorsOwned};
+use crate::stats::take_rows;
+use crate::terms::Term;
+
+///
+/// * `TwoModel`            T-learner: one outcome regression per arm, uplift = μ1(x) - μ0(x)
+/// * `TransformedOutcome`  regression of Z = Y (T - e) / (e (1 - e)) on x, where E[Z | x] is
+///   the uplift; e is the (weighted) treated share of the sample
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UpliftMethod {
+    #[default]
+    TwoModel,
+    TransformedOutcome,
+}
+impl fmt::Display for UpliftMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpliftMethod::TwoModel => write!(f, "two-model"),
+            UpliftMethod::TransformedOutcome => write!(f, "transformed outcome"),
+        }
+    }
+}
+
+///
+/// Configuration for the uplift score.  Fit on the matched or weighted sample by naming its
+/// weight (`match_weight`, `cem_weight`, `ipw`, ...); rows with a zero weight are left out of
+/// the fit but still scored.  The design matrix is built from the predictors, terms and
+/// categorical fields exactly as for the propensity score.
+///
+#[derive(Debug, Clone)]
+pub struct UpliftCfg {
+    pub treatment: String,
+    pub outcome: String,
+    pub predictors: PredictorsOwned,
+    pub terms: Vec<Term>,
+    pub categorical: Vec<String>,
+    pub method: UpliftMethod,
+    pub weight: Option<String>,
+    /// rows of the Qini and uplift-curve tables
+    pub bins: usize,
+    pub name: String,
+}
+
+impl UpliftCfg {
+    pub fn new(treatment: &str, outcome: &str, predictors: Predictors<'_>) -> Self {
+        UpliftCfg {
+            treatment: treatment.to_string(),
+            outcome: outcome.to_string(),
+            predictors: predictors.into(),
+            terms: Vec::new(),
+            categorical: Vec::new(),
+            method: UpliftMethod::default(),
+            weight: None,
+            bins: 10,
+            name: "uplift".to_string(),
+        }
+    }
+    pub fn method(mut self, method: UpliftMethod) -> Self {
+        self.method = method;
+        self
+    }
+    /// See [`crate::terms::parse_terms`]
+    pub fn terms(mut self, terms: Vec<Term>) -> Self {
+        self.terms = terms;
+        self
+    }
+    pub fn categorical(mut self, categorical: Vec<&str>) -> Self {
+        self.categorical = categorical.iter().map(|c| c.to_string()).collect();
+        self
+    }
+    pub fn weight(mut self, weight: &str) -> Self {
+        self.weight = Some(weight.to_string());
+        self
+    }
+    pub fn bins(mut self, bins: usize) -> Self {
+        self.bins = bins.max(1);
+        self
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+}
+
+///
+/// The top `fraction` of subjects by uplift score.
+///
+/// * `qini`     Y₁ - Y₀ n₁ / n₀, the incremental outcome had the top fraction been treated
+/// * `uplift`   (Y₁ / n₁ - Y₀ / n₀)(n₁ + n₀)
+/// * `random`   the Qini of targeting at random, fraction × Qini(1)
+///
+/// Y and n are the (weighted) outcome sums and subject counts of each arm.
+///
+#[derive(Debug, Clone)]
+pub struct UpliftPoint {
+    pub fraction: f64,
+    pub subjects: usize,
+    pub treated: f64,
+    pub controls: f64,
+    pub qini: f64,
+    pub uplift: f64,
+    pub random: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpliftFindings {
+    pub method: UpliftMethod,
+    pub scores: Vec<f64>,
+    pub curve: Vec<UpliftPoint>,
+    /// area between the Qini curve and the random line
+    pub qini_coefficient: f64,
+}
+
+impl UpliftFindings {
+    ///
+    /// `fraction`, `subjects`, `treated`, `controls`, `qini`, `uplift`, `random`
+    ///
+    pub fn to_dataframe(&self) -> Result<DataFrame> {
+        let column =
+            |f: fn(&UpliftPoint) -> f64| -> Vec<f64> { self.curve.iter().map(f).collect() };
+        Ok(DataFrame::new(vec![
+            Series::new("fraction", column(|p| p.fraction)),
+            Series::new(
+                "subjects",
+                self.curve
+                    .iter()
+                    .map(|p| p.subjects as u32)
+                    .collect::<Vec<_>>(),
+            ),
+            Series::new("treated", column(|p| p.treated)),
+            Series::new("controls", column(|p| p.controls)),
+            Series::new("qini", column(|p| p.qini)),
+            Series::new("uplift", column(|p| p.uplift)),
+            Series::new("random", column(|p| p.random)),
+        ])?)
+    }
+}
+impl fmt::Display for UpliftFindings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Uplift ({}), Qini coefficient {:.4}",
+            self.method, self.qini_coefficient
+        )?;
+        writeln!(
+            f,
+            "  {:>8} {:>10} {:>12} {:>12} {:>12}",
+            "fraction", "subjects", "qini", "uplift", "random"
+        )?;
+        for p in &self.curve {
+            writeln!(
+                f,
+                "  {:>8.2} {:>10} {:>12.4} {:>12.4} {:>12.4}",
+                p.fraction, p.subjects, p.qini, p.uplift, p.random
+            )?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Uplift score of every row of the row-dominant X, and the Qini and uplift curves of the
+/// scores on the same sample.
+///
+pub fn uplift(
+    cfg: &UpliftCfg,
+    x: &[f64],
+    t: &[f64],
+    y: &[f64],
+    weights: Option<&[f64]>,
+) -> Result<UpliftFindings> {
+    let n = t.len();
+    if n == 0 || x.is_empty() {
+        return Err(eyre!("Uplift needs subjects and predictors"));
+    }
+    let cols = x.len() / n;
+    let ones = vec![1.0; n];
+    let w = weights.unwrap_or(&ones);
+    let fitted: Vec<usize> = (0..n).filter(|i| w[*i] > 0.0).collect();
+    let treated: Vec<usize> = fitted.iter().copied().filter(|i| t[*i] > 0.5).collect();
+    let controls: Vec<usize> = fitted.iter().copied().filter(|i| t[*i] <= 0.5).collect();
+    if treated.len() < cols || controls.len() < cols {
+        return Err(eyre!(
+            "Uplift needs at least {} treated and control subjects, found {} and {}",
+            cols,
+            treated.len(),
+            controls.len()
+        ));
+    }
+    let fit = |rows: &[usize], target: &[f64]| -> Result<Vec<f64>> {
+        let xs = take_rows(x, cols, rows);
+        let ys: Vec<f64> = rows.iter().map(|r| target[*r]).collect();
+        let ws: Vec<f64> = rows.iter().map(|r| w[*r]).collect();
+        let beta = ols(&xs, &ys, Some(&ws), rows.len(), 1e-6)?;
+        Ok(x.chunks(cols).map(|row| dot(row, &beta)).collect())
+    };
+
+    let scores = match cfg.method {
+        UpliftMethod::TwoModel => {
+            let mu1 = fit(&treated, y)?;
+            let mu0 = fit(&controls, y)?;
+            mu1.iter().zip(&mu0).map(|(a, b)| a - b).collect()
+        }
+        UpliftMethod::TransformedOutcome => {
+            let total: f64 = fitted.iter().map(|i| w[*i]).sum();
+            let e = treated.iter().map(|i| w[*i]).sum::<f64>() / total;
+            let z: Vec<f64> = (0..n)
+                .map(|i| y[i] * (t[i] - e) / (e * (1.0 - e)))
+                .collect();
+            fit(&fitted, &z)?
+        }
+    };
+    let curve = qini_curve(&scores, t, y, w, cfg.bins);
+    let qini_coefficient = curve
+        .windows(2)
+        .map(|p| {
+            let gap = |p: &UpliftPoint| p.qini - p.random;
+            (gap(&p[0]) + gap(&p[1])) / 2.0 * (p[1].fraction - p[0].fraction)
+        })
+        .sum();
+
+    Ok(UpliftFindings {
+        method: cfg.method,
+        scores,
+        curve,
+        qini_coefficient,
+    })
+}
+
+///
+/// Qini and uplift curves of the scores, one point per bin plus the origin.  Rows with a zero
+/// weight are left out.
+///
+pub fn qini_curve(
+    scores: &[f64],
+    t: &[f64],
+    y: &[f64],
+    w: &[f64],
+    bins: usize,
+) -> Vec<UpliftPoint> {
+    let mut order: Vec<usize> = (0..scores.len()).filter(|i| w[*i] > 0.0).collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    let n = order.len();
+
+    let mut points = Vec::with_capacity(bins + 1);
+    let (mut n1, mut n0, mut y1, mut y0) = (0.0, 0.0, 0.0, 0.0);
+    let mut taken = 0;
+    for k in 0..=bins {
+        let until = n * k / bins;
+        for i in &order[taken..until] {
+            match t[*i] > 0.5 {
+                true => {
+                    n1 += w[*i];
+                    y1 += w[*i] * y[*i];
+                }
+                false => {
+                    n0 += w[*i];
+                    y0 += w[*i] * y[*i];
+                }
+            }
+        }
+        taken = until;
+        let ratio = |a: f64, b: f64| if b > 0.0 { a / b } else { 0.0 };
+        points.push(UpliftPoint {
+            fraction: k as f64 / bins as f64,
+            subjects: until,
+            treated: n1,
+            controls: n0,
+            qini: y1 - ratio(y0 * n1, n0),
+            uplift: (ratio(y1, n1) - ratio(y0, n0)) * (n1 + n0),
+            random: 0.0,
+        });
+    }
+    let overall = points.last().map(|p| p.qini).unwrap_or(0.0);
+    for p in points.iter_mut() {
+        p.random = p.fraction * overall;
+    }
+    points
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// the uplift is 2v, larger for larger v
+    fn sample() -> (Vec<f64>, Vec<f64>, Vec<f64>, Vec<f64>) {
+        let rows = 400;
+        let (mut x, mut t, mut y, mut v) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        for i in 0..rows {
+            let value = (i % 20) as f64 / 10.0;
+            let treated = ((i * 7) % 3 == 0) as u8 as f64;
+            x.extend_from_slice(&[value, 1.0]);
+            t.push(treated);
+            y.push(value + 2.0 * value * treated + ((i * 31) % 7) as f64 / 7.0);
+            v.push(value);
+        }
+        (x, t, y, v)
+    }
+    fn cfg() -> UpliftCfg {
+        UpliftCfg::new("t", "y", Predictors::from(vec!["v"]))
+    }
+    #[test]
+    fn test_two_model_uplift() {
+        let (x, t, y, v) = sample();
+        let found = uplift(&cfg(), &x, &t, &y, None).unwrap();
+        for (score, v) in found.scores.iter().zip(&v) {
+            assert!((score - 2.0 * v).abs() < 0.1);
+        }
+        assert_eq!(11, found.curve.len());
+        assert!(found.qini_coefficient > 0.0);
+        assert!(found.curve[5].qini > found.curve[5].random);
+    }
+    #[test]
+    fn test_transformed_outcome_ranks_like_two_model() {
+        let (x, t, y, _) = sample();
+        let cfg = cfg().method(UpliftMethod::TransformedOutcome);
+        let found = uplift(&cfg, &x, &t, &y, None).unwrap();
+        assert!(found.scores[19] > found.scores[0]);
+        assert!(found.qini_coefficient > 0.0);
+        assert_eq!(7, found.to_dataframe().unwrap().width());
+    }
+    #[test]
+    fn test_uplift_rejects_an_empty_sample() {
+        assert!(uplift(&cfg(), &[], &[], &[], None).is_err());
+    }
+}